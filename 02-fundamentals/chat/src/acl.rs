@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+use std::path::Path;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Permission {
+    Read,
+    Write,
+    // Kick and ban others, as well as reading and writing
+    Moderate,
+}
+
+impl Permission {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "read" => Some(Permission::Read),
+            "write" => Some(Permission::Write),
+            "moderate" => Some(Permission::Moderate),
+            _ => None,
+        }
+    }
+}
+
+/// Who a rule, ban or kick applies to: anyone, everyone at an IP address, or
+/// (only when kicking) whoever has registered a nick
+#[derive(Debug, PartialEq, Clone)]
+pub enum Principal {
+    Anyone,
+    Ip(IpAddr),
+    Nick(String),
+}
+
+impl Principal {
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "*" => Principal::Anyone,
+            s => match s.parse() {
+                Ok(ip) => Principal::Ip(IpAddr::to_canonical(&ip)),
+                Err(_) => Principal::Nick(String::from(s)),
+            },
+        }
+    }
+
+    pub fn matches(&self, ip: IpAddr, nick: Option<&str>) -> bool {
+        match self {
+            Principal::Anyone => true,
+            // Dual-stack sockets see IPv4 peers as IPv4-mapped IPv6 addresses
+            Principal::Ip(allowed) => allowed.to_canonical() == ip.to_canonical(),
+            // Compared like the nick registry does, ignoring case
+            Principal::Nick(allowed) => {
+                nick.is_some_and(|nick| nick.to_lowercase() == allowed.to_lowercase())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Principal::Anyone => write!(f, "*"),
+            Principal::Ip(ip) => write!(f, "{}", ip),
+            Principal::Nick(nick) => write!(f, "{}", nick),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ChannelAcl {
+    read: Vec<Principal>,
+    write: Vec<Principal>,
+    moderate: Vec<Principal>,
+}
+
+/// Who may do what on each channel. Reading and writing are open to anyone
+/// unless a rule says who may do them, while moderating needs a rule.
+#[derive(Default)]
+pub struct Acl {
+    channels: HashMap<String, ChannelAcl>,
+    bans: HashMap<String, Vec<Principal>>,
+}
+
+impl Acl {
+    /// Reads an ACL file, where each line is a channel, a permission (`read`,
+    /// `write` or `moderate`), then whoever gets it. Blank lines and lines
+    /// starting with `#` are ignored.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+        Acl::parse(&fs::read_to_string(path)?)
+            .map_err(|message| io::Error::new(ErrorKind::InvalidData, message))
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut acl = Acl::default();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let (channel, permission) = match (words.next(), words.next()) {
+                (Some(channel), Some(permission)) => (channel, permission),
+                _ => return Err(format!("Expected CHANNEL PERMISSION WHO on line {}", n + 1)),
+            };
+            let permission = Permission::parse(permission)
+                .ok_or_else(|| format!("Unknown permission on line {}: {}", n + 1, permission))?;
+            let principals: Vec<Principal> = words.map(Principal::parse).collect();
+            if principals.is_empty() {
+                return Err(format!("Nobody to give permission to on line {}", n + 1));
+            }
+
+            // Anyone can register a nick while its owner is away, so it's not
+            // enough to grant anything to
+            if let Some(nick) = principals.iter().find(|p| matches!(p, Principal::Nick(_))) {
+                return Err(format!("Not an IP address on line {}: {}", n + 1, nick));
+            }
+
+            let rules = acl.channels.entry(String::from(channel)).or_default();
+            match permission {
+                Permission::Read => rules.read.extend(principals),
+                Permission::Write => rules.write.extend(principals),
+                Permission::Moderate => rules.moderate.extend(principals),
+            }
+        }
+        Ok(acl)
+    }
+
+    pub fn allows(&self, channel: &str, permission: Permission, ip: IpAddr) -> bool {
+        let matches = |principals: &[Principal]| principals.iter().any(|p| p.matches(ip, None));
+        if self.bans.get(channel).is_some_and(|bans| matches(bans)) {
+            return false;
+        }
+        let rules = match self.channels.get(channel) {
+            Some(rules) => rules,
+            None => return permission != Permission::Moderate,
+        };
+        if matches(&rules.moderate) {
+            return true;
+        }
+        match permission {
+            Permission::Read => rules.read.is_empty() || matches(&rules.read),
+            Permission::Write => rules.write.is_empty() || matches(&rules.write),
+            Permission::Moderate => false,
+        }
+    }
+
+    /// Replaces the rules with another ACL's, keeping the bans issued so far
+    pub fn set_rules(&mut self, acl: Acl) {
+        self.channels = acl.channels;
+    }
+
+    /// Returns false for nicks, which can't be banned for the same reason
+    /// rules can't name them
+    pub fn ban(&mut self, channel: &str, principal: Principal) -> bool {
+        if let Principal::Nick(_) = principal {
+            return false;
+        }
+        let bans = self.bans.entry(String::from(channel)).or_default();
+        if !bans.contains(&principal) {
+            bans.push(principal);
+        }
+        true
+    }
+
+    /// Returns false if they weren't banned
+    pub fn unban(&mut self, channel: &str, principal: &Principal) -> bool {
+        match self.bans.get_mut(channel) {
+            Some(bans) => {
+                let before = bans.len();
+                bans.retain(|banned| banned != principal);
+                bans.len() < before
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod acl_tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const HOME: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const AWAY: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+    const MODERATOR: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+    const LAPTOP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 4));
+
+    fn acl() -> Acl {
+        Acl::parse(
+            "# Only members can post, and only from home\n\
+             \n\
+             rust_club write 10.0.0.1 10.0.0.4\n\
+             rust_club moderate 10.0.0.3\n\
+             secret_club read 10.0.0.4\n",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Acl::parse("rust_club").is_err());
+        assert!(Acl::parse("rust_club read").is_err());
+        assert!(Acl::parse("rust_club lurk *").is_err());
+        assert!(Acl::parse("rust_club read *").is_ok());
+        assert!(Acl::parse("rust_club moderate corro").is_err());
+        assert!(Acl::parse("rust_club write 10.0.0.1 ferris").is_err());
+        assert!(Acl::parse("rust_club read ferris").is_err());
+    }
+
+    #[test]
+    fn test_permissions() {
+        let acl = acl();
+        assert!(acl.allows("rust_club", Permission::Read, AWAY));
+        assert!(acl.allows("rust_club", Permission::Write, HOME));
+        assert!(acl.allows("rust_club", Permission::Write, LAPTOP));
+        assert!(!acl.allows("rust_club", Permission::Write, AWAY));
+        assert!(!acl.allows("rust_club", Permission::Moderate, HOME));
+
+        // Moderators can do anything
+        assert!(acl.allows("rust_club", Permission::Write, MODERATOR));
+        assert!(acl.allows("rust_club", Permission::Moderate, MODERATOR));
+
+        assert!(!acl.allows("secret_club", Permission::Read, HOME));
+        assert!(acl.allows("secret_club", Permission::Read, LAPTOP));
+        assert!(acl.allows("secret_club", Permission::Write, HOME));
+
+        // Channels without rules are open, but nobody moderates them
+        assert!(acl.allows("elsewhere", Permission::Write, AWAY));
+        assert!(!acl.allows("elsewhere", Permission::Moderate, MODERATOR));
+    }
+
+    #[test]
+    fn test_mapped_addresses() {
+        let acl = acl();
+        let mapped = |ip: IpAddr| match ip {
+            IpAddr::V4(ip) => IpAddr::V6(ip.to_ipv6_mapped()),
+            ip => ip,
+        };
+        assert!(acl.allows("rust_club", Permission::Write, mapped(HOME)));
+        assert!(acl.allows("rust_club", Permission::Moderate, mapped(MODERATOR)));
+        assert!(!acl.allows("rust_club", Permission::Write, mapped(AWAY)));
+        assert_eq!(
+            Principal::parse("::ffff:10.0.0.3"),
+            Principal::Ip(MODERATOR)
+        );
+    }
+
+    #[test]
+    fn test_bans() {
+        let mut acl = acl();
+        assert!(acl.ban("rust_club", Principal::parse("10.0.0.4")));
+        assert!(acl.ban("elsewhere", Principal::parse("10.0.0.2")));
+        assert!(!acl.ban("elsewhere", Principal::parse("ferris")));
+        assert!(!acl.allows("rust_club", Permission::Read, LAPTOP));
+        assert!(acl.allows("rust_club", Permission::Write, HOME));
+        assert!(!acl.allows("elsewhere", Permission::Read, AWAY));
+        assert!(acl.allows("elsewhere", Permission::Read, HOME));
+
+        assert!(acl.unban("rust_club", &Principal::parse("10.0.0.4")));
+        assert!(!acl.unban("rust_club", &Principal::parse("10.0.0.4")));
+        assert!(acl.allows("rust_club", Permission::Read, LAPTOP));
+    }
+}
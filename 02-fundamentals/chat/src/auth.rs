@@ -0,0 +1,262 @@
+use crate::protocol::{Datagram, Encoding, SignedDatagram};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Signed datagrams are only accepted this long either side of the server's clock
+pub const MAX_CLOCK_SKEW: u64 = 30;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Unsigned,
+    UnknownKey(String),
+    BadSignature,
+    // The timestamp is too far from our clock, so the nonce can't be checked
+    Stale(u64),
+    Replayed(u64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Unsigned => write!(f, "Datagrams must be signed"),
+            Error::UnknownKey(key_id) => write!(f, "Unknown key: {}", key_id),
+            Error::BadSignature => write!(f, "Bad signature"),
+            Error::Stale(timestamp) => write!(f, "Stale timestamp: {}", timestamp),
+            Error::Replayed(nonce) => write!(f, "Replayed nonce: {}", nonce),
+        }
+    }
+}
+
+/// Reads a key file, with a `KEY_ID:SECRET` pair on each line. Blank lines
+/// and lines starting with `#` are ignored.
+pub fn load_keys<P: AsRef<Path>>(path: P) -> Result<Vec<(String, Vec<u8>)>, io::Error> {
+    let mut keys = Vec::new();
+    for (n, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(':') {
+            Some((key_id, secret)) if !key_id.is_empty() && !secret.is_empty() => {
+                keys.push((String::from(key_id), secret.as_bytes().to_vec()))
+            }
+            _ => {
+                let message = format!("Expected KEY_ID:SECRET on line {}", n + 1);
+                return Err(io::Error::new(ErrorKind::InvalidData, message));
+            }
+        }
+    }
+    Ok(keys)
+}
+
+pub fn unix_time(now: SystemTime) -> u64 {
+    now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// Covers everything in the signed datagram but the MAC itself. The binary
+// encoding is used whichever one goes over the wire, since it's unambiguous.
+fn mac(secret: &[u8], key_id: &str, timestamp: u64, nonce: u64, datagram: &Datagram) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&(key_id.len() as u32).to_be_bytes());
+    mac.update(key_id.as_bytes());
+    mac.update(&timestamp.to_be_bytes());
+    mac.update(&nonce.to_be_bytes());
+    mac.update(&datagram.encode(Encoding::Binary));
+    mac
+}
+
+/// Signs datagrams with one key, for a client
+pub struct Signer {
+    key_id: String,
+    secret: Vec<u8>,
+}
+
+impl Signer {
+    pub fn new<K: Into<String>>(key_id: K, secret: Vec<u8>) -> Self {
+        Signer {
+            key_id: key_id.into(),
+            secret,
+        }
+    }
+
+    pub fn sign(&self, datagram: &Datagram, now: SystemTime) -> Datagram {
+        let timestamp = unix_time(now);
+        let nonce = rand::random();
+        let mac = mac(&self.secret, &self.key_id, timestamp, nonce, datagram);
+        Datagram::Signed(SignedDatagram {
+            key_id: self.key_id.clone(),
+            timestamp,
+            nonce,
+            mac: mac.finalize().into_bytes().to_vec(),
+            datagram: Box::new(datagram.clone()),
+        })
+    }
+}
+
+/// Checks signatures against every key the server knows, remembering recent
+/// nonces so nothing can be replayed
+pub struct Verifier {
+    keys: HashMap<String, Vec<u8>>,
+    // The nonces seen for each key, and their timestamps, until they're too
+    // old to get past the clock skew check anyway
+    seen: HashMap<String, HashMap<u64, u64>>,
+}
+
+impl Verifier {
+    pub fn new(keys: Vec<(String, Vec<u8>)>) -> Self {
+        Verifier {
+            keys: keys.into_iter().collect(),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Replaces the keys, but remembers the nonces already seen, so nothing
+    /// can be replayed across the change
+    pub fn set_keys(&mut self, keys: Vec<(String, Vec<u8>)>) {
+        self.keys = keys.into_iter().collect();
+    }
+
+    /// Returns the wrapped datagram if the signature checks out
+    pub fn verify(&mut self, signed: SignedDatagram, now: SystemTime) -> Result<Datagram, Error> {
+        let secret = self
+            .keys
+            .get(&signed.key_id)
+            .ok_or_else(|| Error::UnknownKey(signed.key_id.clone()))?;
+        mac(
+            secret,
+            &signed.key_id,
+            signed.timestamp,
+            signed.nonce,
+            &signed.datagram,
+        )
+        .verify_slice(&signed.mac)
+        .map_err(|_| Error::BadSignature)?;
+
+        let now = unix_time(now);
+        if signed.timestamp.max(now) - signed.timestamp.min(now) > MAX_CLOCK_SKEW {
+            return Err(Error::Stale(signed.timestamp));
+        }
+        let seen = self.seen.entry(signed.key_id).or_default();
+        seen.retain(|_, timestamp| *timestamp + MAX_CLOCK_SKEW >= now);
+        if seen.insert(signed.nonce, signed.timestamp).is_some() {
+            return Err(Error::Replayed(signed.nonce));
+        }
+        Ok(*signed.datagram)
+    }
+}
+
+#[cfg(test)]
+mod auth_tests {
+    use super::*;
+    use crate::storage::storage_tests::test_directory;
+    use std::time::Duration;
+
+    fn unwrap_signed(datagram: Datagram) -> SignedDatagram {
+        match datagram {
+            Datagram::Signed(signed) => signed,
+            datagram => panic!("Not signed: {:?}", datagram),
+        }
+    }
+
+    fn verifier() -> Verifier {
+        Verifier::new(vec![(String::from("ferris"), b"crab".to_vec())])
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let now = SystemTime::now();
+        let datagram = Datagram::subscribe("rust_club");
+        let signed = Signer::new("ferris", b"crab".to_vec()).sign(&datagram, now);
+        let signed = unwrap_signed(signed);
+        assert_eq!(signed.timestamp, unix_time(now));
+
+        let mut verifier = verifier();
+        assert_eq!(verifier.verify(signed.clone(), now), Ok(datagram));
+        assert_eq!(
+            verifier.verify(signed.clone(), now),
+            Err(Error::Replayed(signed.nonce))
+        );
+    }
+
+    #[test]
+    fn test_set_keys() {
+        let now = SystemTime::now();
+        let datagram = Datagram::subscribe("rust_club");
+        let signed = Signer::new("ferris", b"crab".to_vec()).sign(&datagram, now);
+        let signed = unwrap_signed(signed);
+
+        let mut verifier = verifier();
+        assert_eq!(verifier.verify(signed.clone(), now), Ok(datagram));
+        verifier.set_keys(vec![
+            (String::from("ferris"), b"crab".to_vec()),
+            (String::from("gopher"), b"go".to_vec()),
+        ]);
+        assert_eq!(
+            verifier.verify(signed.clone(), now),
+            Err(Error::Replayed(signed.nonce))
+        );
+    }
+
+    #[test]
+    fn test_rejects_forgeries() {
+        let now = SystemTime::now();
+        let datagram = Datagram::subscribe("rust_club");
+        let mut verifier = verifier();
+
+        let wrong_secret = Signer::new("ferris", b"lobster".to_vec()).sign(&datagram, now);
+        assert_eq!(
+            verifier.verify(unwrap_signed(wrong_secret), now),
+            Err(Error::BadSignature)
+        );
+
+        let unknown = Signer::new("gopher", b"crab".to_vec()).sign(&datagram, now);
+        assert_eq!(
+            verifier.verify(unwrap_signed(unknown), now),
+            Err(Error::UnknownKey(String::from("gopher")))
+        );
+
+        let mut tampered =
+            unwrap_signed(Signer::new("ferris", b"crab".to_vec()).sign(&datagram, now));
+        tampered.datagram = Box::new(Datagram::subscribe("secret_club"));
+        assert_eq!(verifier.verify(tampered, now), Err(Error::BadSignature));
+    }
+
+    #[test]
+    fn test_rejects_stale_timestamps() {
+        let now = SystemTime::now();
+        let then = now - Duration::from_secs(MAX_CLOCK_SKEW + 1);
+        let signed = Signer::new("ferris", b"crab".to_vec()).sign(&Datagram::Ping(1), then);
+        assert_eq!(
+            verifier().verify(unwrap_signed(signed), now),
+            Err(Error::Stale(unix_time(then)))
+        );
+    }
+
+    #[test]
+    fn test_load_keys() {
+        let directory = test_directory();
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("keys");
+
+        fs::write(&path, "# comment\n\nferris:crab:cake\n  gopher:go  \n").unwrap();
+        assert_eq!(
+            load_keys(&path).unwrap(),
+            vec![
+                (String::from("ferris"), b"crab:cake".to_vec()),
+                (String::from("gopher"), b"go".to_vec()),
+            ]
+        );
+
+        fs::write(&path, "ferris\n").unwrap();
+        assert_eq!(load_keys(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
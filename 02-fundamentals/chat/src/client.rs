@@ -1,58 +1,313 @@
-use crate::protocol::{self, Datagram};
-use std::io::Error;
-use std::net::{UdpSocket, SocketAddrV4, Ipv4Addr};
-use std::str::from_utf8;
-use std::time::Duration;
+use crate::auth::Signer;
+use crate::encryption::ChannelKey;
+use crate::fragment::{self, Reassembler, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::net;
+use crate::protocol::{
+    Datagram, Encoding, ErrorCode, ErrorDatagram, ReliableDatagram, MAX_DATAGRAM_SIZE,
+};
+use crate::reliability::{RetransmitQueue, SequenceWindow};
+use crate::transport::{write_frame, FrameReader};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant, SystemTime};
+
+// How often to wake up and retransmit while waiting for replies
+const RETRANSMIT_TICK: Duration = Duration::from_millis(50);
+
+// How we reach the server
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream, FrameReader),
+}
 
 pub struct Client {
-    socket: UdpSocket,
-    server_address: SocketAddrV4,
+    connection: Connection,
+    server_address: SocketAddr,
+    next_sequence: u64,
+    retransmits: RetransmitQueue<SocketAddr, Datagram>,
+    received: SequenceWindow,
+    inbox: VecDeque<Datagram>,
+    encoding: Encoding,
+    fragments: Reassembler<SocketAddr>,
+    next_fragment_id: u64,
+    // Signs everything we send, for servers that only accept signed datagrams
+    signer: Option<Signer>,
+    // Channels whose messages are encrypted end to end
+    channel_keys: HashMap<String, ChannelKey>,
 }
 
 impl Client {
-    pub fn new(port: u16, server_address: SocketAddrV4) -> Result<Self, Error> {
-        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port))?;
-        Ok(Client { socket, server_address })
+    /// Binds to `address`, which needs to be in the same address family as
+    /// the server (see `net::unspecified_for`)
+    pub fn new(address: SocketAddr, server_address: SocketAddr) -> Result<Self, Error> {
+        let socket = net::bind(address)?;
+        Ok(Client::with_connection(
+            Connection::Udp(socket),
+            server_address,
+        ))
+    }
+
+    /// Talks to the server over TCP instead, for networks that drop UDP
+    pub fn connect_tcp(server_address: SocketAddr) -> Result<Self, Error> {
+        let stream = TcpStream::connect(server_address)?;
+        let frames = FrameReader::new(DEFAULT_MAX_MESSAGE_SIZE);
+        Ok(Client::with_connection(
+            Connection::Tcp(stream, frames),
+            server_address,
+        ))
+    }
+
+    fn with_connection(connection: Connection, server_address: SocketAddr) -> Self {
+        Client {
+            connection,
+            server_address,
+            next_sequence: 0,
+            retransmits: RetransmitQueue::new(),
+            received: SequenceWindow::new(),
+            inbox: VecDeque::new(),
+            encoding: Encoding::Text,
+            fragments: Reassembler::new(DEFAULT_MAX_MESSAGE_SIZE),
+            next_fragment_id: 0,
+            signer: None,
+            channel_keys: HashMap::new(),
+        }
     }
 
-    pub fn send(&self, datagram: &Datagram) -> Result<(), Error> {
-        self.socket.send_to(datagram.serialize().as_bytes(), self.server_address)?;
+    /// The server replies in whichever encoding we last used
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    pub fn set_signer(&mut self, signer: Signer) {
+        self.signer = Some(signer);
+    }
+
+    /// Encrypts everything we publish to `channel`, and decrypts everything
+    /// published there, with a key derived from `passphrase`
+    pub fn encrypt_channel(&mut self, channel: &str, passphrase: &[u8]) {
+        let key = ChannelKey::derive(channel, passphrase);
+        self.channel_keys.insert(String::from(channel), key);
+    }
+
+    fn encrypt(&self, datagram: &Datagram) -> Option<Datagram> {
+        match datagram {
+            Datagram::Publish(publish) => {
+                let key = self.channel_keys.get(&publish.channel)?;
+                Some(Datagram::Publish(key.encrypt(publish)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Sends a datagram, in fragments if it's too big for a single one
+    pub fn send(&mut self, datagram: &Datagram) -> Result<(), Error> {
+        let encrypted = self.encrypt(datagram);
+        let datagram = encrypted.as_ref().unwrap_or(datagram);
+        // Signed afresh every time, so retransmits don't look like replays
+        let signed;
+        let datagram = match &self.signer {
+            Some(signer) => {
+                signed = signer.sign(datagram, SystemTime::now());
+                &signed
+            }
+            None => datagram,
+        };
+        match &mut self.connection {
+            Connection::Udp(socket) => {
+                self.next_fragment_id += 1;
+                for buf in fragment::encode(datagram, self.encoding, self.next_fragment_id) {
+                    socket.send_to(&buf, self.server_address)?;
+                }
+            }
+            Connection::Tcp(stream, _) => write_frame(stream, &datagram.encode(self.encoding))?,
+        }
         Ok(())
     }
 
+    #[cfg(test)]
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        match &self.connection {
+            Connection::Udp(socket) => socket.local_addr(),
+            Connection::Tcp(stream, _) => stream.local_addr(),
+        }
+    }
+
+    /// Sends a datagram that will be retransmitted until the server ACKs it.
+    /// Retransmits happen while calling `listen` or `flush`.
+    pub fn send_reliable(&mut self, datagram: &Datagram) -> Result<u64, Error> {
+        self.next_sequence += 1;
+        // Encrypted before it's wrapped, so retransmits send the same ciphertext
+        let datagram = self.encrypt(datagram).unwrap_or_else(|| datagram.clone());
+        let reliable = Datagram::reliable(self.next_sequence, datagram);
+        self.retransmits.push(
+            self.server_address,
+            self.next_sequence,
+            reliable.clone(),
+            Instant::now(),
+        );
+        self.send(&reliable)?;
+        Ok(self.next_sequence)
+    }
+
+    /// Waits until every reliable datagram has been ACKed (or given up on).
+    /// Returns false if the timeout expired first.
+    pub fn flush(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !self.retransmits.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            if let Some(datagram) = self.receive(Some(deadline - now)) {
+                // Keep anything else for the next call to listen
+                self.inbox.push_back(datagram);
+            }
+        }
+        true
+    }
+
     fn parse_datagram(&self, buf: &[u8]) -> Option<Datagram> {
-        let message = match from_utf8(&buf) {
-            Ok(message) => message,
+        match Datagram::decode(buf) {
+            Ok((datagram, _)) => Some(datagram),
             Err(error) => {
-                error!("Failed to parse datagram as UTF8: {}", error);
-                return None;
+                error!("Failed to parse datagram: {}", error);
+                None
             }
-        };
+        }
+    }
 
-        match Datagram::parse(message) {
-            Ok(datagram) => Some(datagram),
-            Err(protocol::Error::BadDatagram(message)) => {
-                error!("Failed to parse datagram: {}", message);
+    fn retransmit(&mut self) {
+        for (address, sequence, datagram) in self.retransmits.due(Instant::now()) {
+            debug!("Retransmitting datagram {} to {}", sequence, address);
+            if let Err(error) = self.send(&datagram) {
+                error!("Failed to retransmit datagram: {}", error);
+            }
+        }
+    }
+
+    // Handles ACKs, pings and reliable datagrams, only returning datagrams that
+    // should be passed on to the caller
+    fn handle_datagram(&mut self, datagram: Datagram) -> Option<Datagram> {
+        match datagram {
+            Datagram::Ack(sequence) => {
+                self.retransmits.acknowledge(&self.server_address, sequence);
+                None
+            }
+            Datagram::Reliable(ReliableDatagram { sequence, datagram }) => {
+                if let Err(error) = self.send(&Datagram::Ack(sequence)) {
+                    error!("Failed to send ACK: {}", error);
+                }
+                if self.received.insert(sequence) {
+                    Some(*datagram)
+                } else {
+                    debug!("Ignoring duplicate datagram {}", sequence);
+                    None
+                }
+            }
+            Datagram::Fragment(fragment) => {
+                let result = self
+                    .fragments
+                    .push(self.server_address, fragment, Instant::now());
+                match result {
+                    Ok(Some(buf)) => self
+                        .parse_datagram(&buf)
+                        .and_then(|datagram| self.handle_datagram(datagram)),
+                    Ok(None) => None,
+                    Err(error) => {
+                        error!("Failed to reassemble datagram: {}", error);
+                        None
+                    }
+                }
+            }
+            Datagram::Ping(nonce) => {
+                // Let the server know we're still here
+                if let Err(error) = self.send(&Datagram::Pong(nonce)) {
+                    error!("Failed to send pong: {}", error);
+                }
                 None
             }
+            datagram => Some(datagram),
         }
     }
 
-    pub fn listen(&self, timeout: Option<Duration>) -> Option<Datagram> {
-        self.socket
-            .set_read_timeout(timeout)
-            .unwrap_or_else(|error| {
-                error!("Failed to set read timeout: {}", error);
-            });
+    fn recv(&mut self, timeout: Option<Duration>) -> Result<Vec<u8>, Error> {
+        match &mut self.connection {
+            Connection::Udp(socket) => {
+                socket.set_read_timeout(timeout)?;
+                let mut buf = [0; MAX_DATAGRAM_SIZE];
+                let n = socket.recv(&mut buf)?;
+                Ok(buf[..n].to_vec())
+            }
+            Connection::Tcp(stream, frames) => {
+                stream.set_read_timeout(timeout)?;
+                frames.read(stream)
+            }
+        }
+    }
 
-        let mut buf = [0; 1024];
-        match self.socket.recv(&mut buf) {
-            Ok(n) => self.parse_datagram(&buf[..n]),
-            Err(error) => {
-                let datagram = from_utf8(&buf).unwrap_or("[unable to parse UTF8]");
-                error!("Failed to receive datagram: '{}'. {}", datagram, error);
-                None
+    // Waits for a single datagram from the server, retransmitting as needed
+    fn receive(&mut self, timeout: Option<Duration>) -> Option<Datagram> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            self.fragments.expire(Instant::now());
+            self.retransmit();
+
+            let now = Instant::now();
+            let mut read_timeout = match deadline {
+                Some(deadline) if deadline <= now => return None,
+                Some(deadline) => Some(deadline - now),
+                None => None,
+            };
+            if !self.retransmits.is_empty() {
+                read_timeout =
+                    Some(read_timeout.map_or(RETRANSMIT_TICK, |t| t.min(RETRANSMIT_TICK)));
+            }
+
+            match self.recv(read_timeout) {
+                Ok(buf) => {
+                    let datagram = self
+                        .parse_datagram(&buf)
+                        .and_then(|datagram| self.handle_datagram(datagram));
+                    if datagram.is_some() {
+                        return datagram;
+                    }
+                }
+                Err(ref error)
+                    if error.kind() == ErrorKind::WouldBlock
+                        || error.kind() == ErrorKind::TimedOut => {}
+                Err(error) => {
+                    error!("Failed to receive datagram: {}", error);
+                    return None;
+                }
             }
         }
     }
+
+    /// Waits for the next datagram from the server. Errors the server sent
+    /// back to us, usually because it rejected something, come back as `Err`,
+    /// as do messages on encrypted channels that can't be decrypted.
+    pub fn listen(&mut self, timeout: Option<Duration>) -> Option<Result<Datagram, ErrorDatagram>> {
+        let datagram = match self.inbox.pop_front() {
+            Some(datagram) => datagram,
+            None => self.receive(timeout)?,
+        };
+        match datagram {
+            Datagram::Error(error) => Some(Err(error)),
+            Datagram::Publish(publish) => match self.channel_keys.get(&publish.channel) {
+                Some(key) => match key.decrypt(&publish) {
+                    Ok(publish) => Some(Ok(Datagram::Publish(publish))),
+                    Err(error) => Some(Err(ErrorDatagram {
+                        code: ErrorCode::Undecryptable,
+                        message: format!(
+                            "Message from {} on {}: {}",
+                            publish.display_name, publish.channel, error
+                        ),
+                    })),
+                },
+                None => Some(Ok(Datagram::Publish(publish))),
+            },
+            datagram => Some(Ok(datagram)),
+        }
+    }
 }
@@ -0,0 +1,337 @@
+use crate::acl::Acl;
+use crate::auth;
+use crate::net;
+use crate::protocol::MAX_DATAGRAM_SIZE;
+use crate::rate_limit::Rate;
+use crate::server::Options;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_UDP_PORT: u16 = 31337;
+
+/// Everything the server can be configured with, as read from a TOML file
+/// or the command line. Keys are named after the command line flags, with
+/// underscores instead of dashes, and anything left out is defaulted.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub tcp_port: Option<u16>,
+    pub websocket_port: Option<u16>,
+    pub peers: Option<Vec<String>>,
+    // In seconds
+    pub heartbeat_interval: Option<u64>,
+    pub max_missed_heartbeats: Option<u32>,
+    // In seconds, and can be fractional
+    pub write_timeout: Option<f64>,
+    pub max_datagram_size: Option<usize>,
+    pub max_message_size: Option<usize>,
+    pub queue_size: Option<usize>,
+    pub send_threads: Option<usize>,
+    pub history_size: Option<usize>,
+    pub history_channels: Option<usize>,
+    pub rate_limit: Option<u32>,
+    pub rate_burst: Option<u32>,
+    pub channel_rate_limit: Option<u32>,
+    pub channel_rate_burst: Option<u32>,
+    pub key_file: Option<PathBuf>,
+    pub acl_file: Option<PathBuf>,
+    pub data_dir: Option<PathBuf>,
+    pub compact_after: Option<usize>,
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+fn at_least<T: PartialOrd + Copy + fmt::Display>(
+    value: Option<T>,
+    minimum: T,
+    name: &str,
+) -> Result<Option<T>, Error> {
+    match value {
+        Some(value) if value < minimum => {
+            Err(invalid(format!("{} must be at least {}", name, minimum)))
+        }
+        value => Ok(value),
+    }
+}
+
+// Bursts default to a second's worth of messages
+fn rate(per_second: Option<u32>, burst: Option<u32>) -> Option<Rate> {
+    per_second.map(|per_second| Rate {
+        per_second,
+        burst: burst.unwrap_or(per_second),
+    })
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        toml::from_str(&fs::read_to_string(path)?).map_err(|error| {
+            let message = format!("Error in {}: {}", path.display(), error);
+            Error::new(ErrorKind::InvalidData, message)
+        })
+    }
+
+    /// Takes each setting from `self`, or from `other` where it's missing,
+    /// so command line flags can override a file
+    pub fn or(self, other: Config) -> Config {
+        Config {
+            bind: self.bind.or(other.bind),
+            port: self.port.or(other.port),
+            tcp_port: self.tcp_port.or(other.tcp_port),
+            websocket_port: self.websocket_port.or(other.websocket_port),
+            peers: self.peers.or(other.peers),
+            heartbeat_interval: self.heartbeat_interval.or(other.heartbeat_interval),
+            max_missed_heartbeats: self.max_missed_heartbeats.or(other.max_missed_heartbeats),
+            write_timeout: self.write_timeout.or(other.write_timeout),
+            max_datagram_size: self.max_datagram_size.or(other.max_datagram_size),
+            max_message_size: self.max_message_size.or(other.max_message_size),
+            queue_size: self.queue_size.or(other.queue_size),
+            send_threads: self.send_threads.or(other.send_threads),
+            history_size: self.history_size.or(other.history_size),
+            history_channels: self.history_channels.or(other.history_channels),
+            // A rate and its burst go together
+            rate_limit: self.rate_limit.or(other.rate_limit),
+            rate_burst: match self.rate_limit {
+                Some(_) => self.rate_burst,
+                None => other.rate_burst,
+            },
+            channel_rate_limit: self.channel_rate_limit.or(other.channel_rate_limit),
+            channel_rate_burst: match self.channel_rate_limit {
+                Some(_) => self.channel_rate_burst,
+                None => other.channel_rate_burst,
+            },
+            key_file: self.key_file.or(other.key_file),
+            acl_file: self.acl_file.or(other.acl_file),
+            data_dir: self.data_dir.or(other.data_dir),
+            compact_after: self.compact_after.or(other.compact_after),
+        }
+    }
+
+    /// Where to receive UDP datagrams
+    pub fn address(&self) -> SocketAddr {
+        let ip = self.bind.unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        SocketAddr::new(ip, self.port.unwrap_or(DEFAULT_UDP_PORT))
+    }
+
+    pub fn tcp_address(&self) -> Option<SocketAddr> {
+        let ip = self.address().ip();
+        self.tcp_port.map(|port| SocketAddr::new(ip, port))
+    }
+
+    pub fn websocket_address(&self) -> Option<SocketAddr> {
+        let ip = self.address().ip();
+        self.websocket_port.map(|port| SocketAddr::new(ip, port))
+    }
+
+    /// The server's options, with defaults for anything not set, or an error
+    /// for the first setting that doesn't make sense
+    pub fn options(&self) -> Result<Options, Error> {
+        let mut options = Options::default();
+        if let Some(seconds) = at_least(self.heartbeat_interval, 1, "heartbeat_interval")? {
+            options.heartbeat_interval = Duration::from_secs(seconds);
+        }
+        if let Some(count) = at_least(self.max_missed_heartbeats, 1, "max_missed_heartbeats")? {
+            options.max_missed_heartbeats = count;
+        }
+        if let Some(seconds) = self.write_timeout {
+            options.write_timeout = Duration::try_from_secs_f64(seconds)
+                .ok()
+                .filter(|timeout| !timeout.is_zero())
+                .ok_or_else(|| invalid(String::from("write_timeout must be positive")))?;
+        }
+        let minimum = MAX_DATAGRAM_SIZE;
+        if let Some(size) = at_least(self.max_datagram_size, minimum, "max_datagram_size")? {
+            options.max_datagram_size = size;
+        }
+        if let Some(size) = at_least(self.max_message_size, 1, "max_message_size")? {
+            options.max_message_size = size;
+        }
+        if let Some(size) = at_least(self.queue_size, 1, "queue_size")? {
+            options.outbound_queue = size;
+        }
+        if let Some(threads) = at_least(self.send_threads, 1, "send_threads")? {
+            options.sender_threads = threads;
+        }
+        if let Some(size) = at_least(self.history_size, 1, "history_size")? {
+            options.history_size = size;
+        }
+        if let Some(count) = at_least(self.history_channels, 1, "history_channels")? {
+            options.history_channels = count;
+        }
+        options.sender_rate = rate(
+            at_least(self.rate_limit, 1, "rate_limit")?,
+            at_least(self.rate_burst, 1, "rate_burst")?,
+        );
+        options.channel_rate = rate(
+            at_least(self.channel_rate_limit, 1, "channel_rate_limit")?,
+            at_least(self.channel_rate_burst, 1, "channel_rate_burst")?,
+        );
+        options.key_file = self.key_file.clone();
+        options.acl_file = self.acl_file.clone();
+        options.data_dir = self.data_dir.clone();
+        if let Some(count) = at_least(self.compact_after, 1, "compact_after")? {
+            options.compact_after = count;
+        }
+        for peer in self.peers.iter().flatten() {
+            let peer = net::resolve(peer)
+                .map_err(|error| invalid(format!("Bad peer {}: {}", peer, error)))?;
+            options.peers.push(peer);
+        }
+        // Peers are only recognised by their address, so they'd get around both
+        if !options.peers.is_empty() && (options.key_file.is_some() || options.acl_file.is_some()) {
+            let message = "peers can't be used with key_file or acl_file, since they aren't signed";
+            return Err(invalid(String::from(message)));
+        }
+        Ok(options)
+    }
+
+    /// Makes sure the server could start with this configuration, including
+    /// the key and ACL files it refers to
+    pub fn check(&self) -> Result<(), Error> {
+        self.options()?;
+        if let Some(key_file) = &self.key_file {
+            auth::load_keys(key_file).map_err(|error| {
+                let message = format!("Error in {}: {}", key_file.display(), error);
+                Error::new(error.kind(), message)
+            })?;
+        }
+        if let Some(acl_file) = &self.acl_file {
+            Acl::load(acl_file).map_err(|error| {
+                let message = format!("Error in {}: {}", acl_file.display(), error);
+                Error::new(error.kind(), message)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+    use crate::storage::storage_tests::test_directory;
+
+    fn parse(s: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(s)
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = parse(
+            r#"
+            bind = "127.0.0.1"
+            port = 4000
+            peers = ["127.0.0.1:4001"]
+            write_timeout = 0.5
+            rate_limit = 10
+            data_dir = "/var/lib/chat"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.address(), "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(config.tcp_address(), None);
+
+        let options = config.options().unwrap();
+        assert_eq!(options.peers, vec!["127.0.0.1:4001".parse().unwrap()]);
+        assert_eq!(options.write_timeout, Duration::from_millis(500));
+        assert_eq!(
+            options.sender_rate,
+            Some(Rate {
+                per_second: 10,
+                burst: 10
+            })
+        );
+        assert_eq!(options.data_dir, Some(PathBuf::from("/var/lib/chat")));
+
+        assert_eq!(parse("").unwrap(), Config::default());
+        assert!(parse("prot = 4000").is_err());
+        assert!(parse("port = 70000").is_err());
+        assert!(parse("bind = \"localhost\"").is_err());
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::default();
+        assert_eq!(
+            config.address(),
+            SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), DEFAULT_UDP_PORT)
+        );
+        let options = config.options().unwrap();
+        assert_eq!(options.max_datagram_size, MAX_DATAGRAM_SIZE);
+        assert_eq!(options.sender_rate, None);
+    }
+
+    #[test]
+    fn test_flags_override_file() {
+        let file = parse(
+            r#"
+            port = 4000
+            tcp_port = 4001
+            rate_limit = 10
+            rate_burst = 50
+            "#,
+        )
+        .unwrap();
+        let flags = Config {
+            port: Some(5000),
+            rate_limit: Some(20),
+            ..Config::default()
+        };
+        let config = flags.or(file);
+        assert_eq!(config.port, Some(5000));
+        assert_eq!(config.tcp_port, Some(4001));
+        // The file's burst went with its rate
+        assert_eq!(config.rate_burst, None);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let invalid = [
+            "heartbeat_interval = 0",
+            "write_timeout = 0",
+            "write_timeout = -1.5",
+            "max_datagram_size = 512",
+            "max_message_size = 0",
+            "history_size = 0",
+            "history_channels = 0",
+            "queue_size = 0",
+            "send_threads = 0",
+            "channel_rate_limit = 0",
+            "peers = [\"nowhere\"]",
+            "peers = [\"127.0.0.1:4001\"]\nkey_file = \"keys\"",
+            "peers = [\"127.0.0.1:4001\"]\nacl_file = \"acl\"",
+        ];
+        for s in invalid.iter() {
+            assert!(parse(s).unwrap().options().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let directory = test_directory();
+        fs::create_dir_all(&directory).unwrap();
+        let acl_file = directory.join("acl");
+        fs::write(&acl_file, "secret read 10.9.9.9\n").unwrap();
+        let config = Config {
+            acl_file: Some(acl_file.clone()),
+            ..Config::default()
+        };
+        assert!(config.check().is_ok());
+
+        fs::write(&acl_file, "secret dance 10.9.9.9\n").unwrap();
+        assert!(config.check().is_err());
+
+        let config = Config {
+            key_file: Some(directory.join("missing")),
+            ..Config::default()
+        };
+        assert!(config.check().is_err());
+    }
+}
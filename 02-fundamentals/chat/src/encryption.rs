@@ -0,0 +1,158 @@
+use crate::protocol::PublishDatagram;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use sha2::Sha256;
+use std::fmt;
+
+// Marks a message body as encrypted, and which scheme it uses
+const PREFIX: &str = "e2e1:";
+const NONCE_SIZE: usize = 24;
+// Slows down guessing passphrases from a captured message
+const KDF_ROUNDS: u32 = 100_000;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    NotEncrypted,
+    Malformed,
+    // Either the wrong passphrase, or someone changed the message on the way
+    Tampered,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotEncrypted => write!(f, "Message wasn't encrypted"),
+            Error::Malformed => write!(f, "Malformed ciphertext"),
+            Error::Tampered => write!(f, "Message failed authentication"),
+        }
+    }
+}
+
+/// Encrypts and decrypts message bodies on one channel. Everyone who knows
+/// the channel's passphrase derives the same key, and the server only ever
+/// sees ciphertext.
+pub struct ChannelKey {
+    cipher: XChaCha20Poly1305,
+}
+
+// The channel and display name aren't secret, since the server needs them,
+// but they're authenticated so a message can't be replayed elsewhere or
+// passed off as someone else's
+fn associated_data(datagram: &PublishDatagram) -> Vec<u8> {
+    let mut aad = Vec::new();
+    for field in &[&datagram.channel, &datagram.display_name] {
+        aad.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        aad.extend_from_slice(field.as_bytes());
+    }
+    aad
+}
+
+impl ChannelKey {
+    pub fn derive(channel: &str, passphrase: &[u8]) -> Self {
+        // Salting with the channel means each channel gets its own key
+        let salt = format!("chat:{}", channel);
+        let mut key = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt.as_bytes(), KDF_ROUNDS, &mut key);
+        ChannelKey {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+        }
+    }
+
+    pub fn encrypt(&self, datagram: &PublishDatagram) -> PublishDatagram {
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let payload = Payload {
+            msg: datagram.message.as_bytes(),
+            aad: &associated_data(datagram),
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .expect("Messages are far smaller than the cipher's limit");
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        PublishDatagram {
+            message: format!("{}{}", PREFIX, hex::encode(sealed)),
+            ..datagram.clone()
+        }
+    }
+
+    pub fn decrypt(&self, datagram: &PublishDatagram) -> Result<PublishDatagram, Error> {
+        let sealed = datagram
+            .message
+            .strip_prefix(PREFIX)
+            .ok_or(Error::NotEncrypted)?;
+        let sealed = hex::decode(sealed).map_err(|_| Error::Malformed)?;
+        if sealed.len() < NONCE_SIZE {
+            return Err(Error::Malformed);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &associated_data(datagram),
+        };
+        let message = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| Error::Tampered)?;
+        Ok(PublishDatagram {
+            message: String::from_utf8(message).map_err(|_| Error::Malformed)?,
+            ..datagram.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod encryption_tests {
+    use super::*;
+
+    fn publish(channel: &str, display_name: &str, message: &str) -> PublishDatagram {
+        PublishDatagram {
+            channel: String::from(channel),
+            display_name: String::from(display_name),
+            message: String::from(message),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let key = ChannelKey::derive("rust_club", b"hunter2");
+        let datagram = publish("rust_club", "ferris", "meet at the usual place");
+        let encrypted = key.encrypt(&datagram);
+        assert!(encrypted.message.starts_with(PREFIX));
+        assert!(!encrypted.message.contains("usual"));
+        assert_ne!(key.encrypt(&datagram), encrypted);
+
+        let other = ChannelKey::derive("rust_club", b"hunter2");
+        assert_eq!(other.decrypt(&encrypted), Ok(datagram));
+    }
+
+    #[test]
+    fn test_detects_tampering() {
+        let key = ChannelKey::derive("rust_club", b"hunter2");
+        let encrypted = key.encrypt(&publish("rust_club", "ferris", "hi"));
+
+        let wrong = ChannelKey::derive("rust_club", b"hunter3");
+        assert_eq!(wrong.decrypt(&encrypted), Err(Error::Tampered));
+
+        let mut flipped = encrypted.clone();
+        let last = if flipped.message.ends_with('0') {
+            "1"
+        } else {
+            "0"
+        };
+        flipped.message.pop();
+        flipped.message.push_str(last);
+        assert_eq!(key.decrypt(&flipped), Err(Error::Tampered));
+
+        let renamed = PublishDatagram {
+            display_name: String::from("gopher"),
+            ..encrypted
+        };
+        assert_eq!(key.decrypt(&renamed), Err(Error::Tampered));
+
+        let plain = publish("rust_club", "ferris", "hi");
+        assert_eq!(key.decrypt(&plain), Err(Error::NotEncrypted));
+        let short = publish("rust_club", "ferris", "e2e1:00ff");
+        assert_eq!(key.decrypt(&short), Err(Error::Malformed));
+    }
+}
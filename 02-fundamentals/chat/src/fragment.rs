@@ -0,0 +1,238 @@
+use crate::protocol::{Datagram, Encoding, Error, FragmentDatagram, MAX_DATAGRAM_SIZE};
+use std::collections::hash_map::{Entry, HashMap};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+// Leaves room for the fragment's own header in either encoding
+pub const FRAGMENT_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - 64;
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+// Give up on a message if the rest of it hasn't turned up by now
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Encodes a datagram, splitting it into fragments if it's too big to send
+/// in one go. Each fragment carries a slice of the encoded datagram, and
+/// text fragments are only ever split between characters.
+pub fn encode(datagram: &Datagram, encoding: Encoding, id: u64) -> Vec<Vec<u8>> {
+    let encoded = datagram.encode(encoding);
+    if encoded.len() <= MAX_DATAGRAM_SIZE {
+        return vec![encoded];
+    }
+
+    let mut payloads = Vec::new();
+    let mut rest = &encoded[..];
+    while !rest.is_empty() {
+        let mut end = rest.len().min(FRAGMENT_PAYLOAD_SIZE);
+        if encoding == Encoding::Text {
+            while end < rest.len() && rest[end] & 0xC0 == 0x80 {
+                end -= 1;
+            }
+        }
+        let (payload, remaining) = rest.split_at(end);
+        payloads.push(payload);
+        rest = remaining;
+    }
+
+    let count = payloads.len() as u32;
+    payloads
+        .into_iter()
+        .enumerate()
+        .map(|(index, payload)| {
+            Datagram::Fragment(FragmentDatagram {
+                id,
+                index: index as u32,
+                count,
+                payload: payload.to_vec(),
+            })
+            .encode(encoding)
+        })
+        .collect()
+}
+
+struct Partial {
+    started: Instant,
+    size: usize,
+    fragments: Vec<Option<Vec<u8>>>,
+    // Rejected messages are remembered until they expire, so the rest of
+    // their fragments are dropped quietly
+    rejected: bool,
+}
+
+impl Partial {
+    fn reject(&mut self) {
+        self.rejected = true;
+        self.fragments = Vec::new();
+    }
+}
+
+/// Collects fragments from each sender until a whole datagram has arrived.
+pub struct Reassembler<K> {
+    max_message_size: usize,
+    partial: HashMap<(K, u64), Partial>,
+}
+
+impl<K: Eq + Hash + Clone> Reassembler<K> {
+    pub fn new(max_message_size: usize) -> Self {
+        Reassembler {
+            max_message_size,
+            partial: HashMap::new(),
+        }
+    }
+
+    /// Returns the encoded datagram once its last fragment arrives
+    pub fn push(
+        &mut self,
+        sender: K,
+        fragment: FragmentDatagram,
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let max_fragments = self.max_message_size / FRAGMENT_PAYLOAD_SIZE + 1;
+        let key = (sender, fragment.id);
+        let partial = match self.partial.entry(key.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let partial = entry.insert(Partial {
+                    started: now,
+                    size: 0,
+                    fragments: Vec::new(),
+                    rejected: false,
+                });
+                if fragment.count as usize > max_fragments {
+                    partial.reject();
+                    return Err(Error::TooLarge(self.max_message_size));
+                }
+                partial.fragments = vec![None; fragment.count as usize];
+                partial
+            }
+        };
+        if partial.rejected {
+            return Ok(None);
+        }
+        if partial.fragments.len() != fragment.count as usize {
+            partial.reject();
+            return Err(Error::Malformed(format!(
+                "Fragment count changed for {}",
+                fragment.id
+            )));
+        }
+
+        let slot = &mut partial.fragments[fragment.index as usize];
+        if slot.is_some() {
+            // A retransmit of something we already have
+            return Ok(None);
+        }
+        partial.size += fragment.payload.len();
+        *slot = Some(fragment.payload);
+        if partial.size > self.max_message_size {
+            partial.reject();
+            return Err(Error::TooLarge(self.max_message_size));
+        }
+        if partial.fragments.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+
+        let partial = self.partial.remove(&key).unwrap();
+        Ok(Some(
+            partial.fragments.into_iter().flatten().flatten().collect(),
+        ))
+    }
+
+    /// Drops any message that has taken too long to arrive in full
+    pub fn expire(&mut self, now: Instant) {
+        self.partial.retain(|(_, id), partial| {
+            let expired = now - partial.started >= REASSEMBLY_TIMEOUT;
+            if expired {
+                warn!("Gave up reassembling fragmented datagram {}", id);
+            }
+            !expired
+        });
+    }
+
+    pub fn forget(&mut self, sender: &K) {
+        self.partial.retain(|(s, _), _| s != sender);
+    }
+}
+
+#[cfg(test)]
+mod fragment_tests {
+    use super::*;
+
+    fn fragments(encoded: Vec<Vec<u8>>) -> Vec<FragmentDatagram> {
+        encoded
+            .iter()
+            .map(|buf| match Datagram::decode(buf).unwrap().0 {
+                Datagram::Fragment(fragment) => fragment,
+                datagram => panic!("Expected a fragment, got {:?}", datagram),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_small_datagrams_are_not_fragmented() {
+        let datagram = Datagram::publish("rust_club", "me", "hello");
+        let encoded = encode(&datagram, Encoding::Text, 1);
+        assert_eq!(encoded, vec![datagram.encode(Encoding::Text)]);
+    }
+
+    #[test]
+    fn test_fragment_round_trip() {
+        // Multi-byte characters, so some of them straddle a fragment boundary
+        let datagram = Datagram::publish("rust_club", "me", "héllo|\n".repeat(500));
+        for &encoding in &[Encoding::Text, Encoding::Binary] {
+            let encoded = encode(&datagram, encoding, 7);
+            assert!(encoded.len() > 1);
+            assert!(encoded.iter().all(|buf| buf.len() <= MAX_DATAGRAM_SIZE));
+
+            let mut reassembler = Reassembler::new(DEFAULT_MAX_MESSAGE_SIZE);
+            let now = Instant::now();
+            let mut reassembled = None;
+            // Out of order, with a duplicate
+            let mut fragments = fragments(encoded);
+            fragments.reverse();
+            fragments.insert(1, fragments[0].clone());
+            for fragment in fragments {
+                assert!(reassembled.is_none());
+                reassembled = reassembler.push("peer", fragment, now).unwrap();
+            }
+            let (decoded, _) = Datagram::decode(&reassembled.unwrap()).unwrap();
+            assert_eq!(decoded, datagram);
+        }
+    }
+
+    #[test]
+    fn test_reassembly_is_limited() {
+        let datagram = Datagram::publish("rust_club", "me", "a".repeat(5000));
+        let fragments = fragments(encode(&datagram, Encoding::Text, 1));
+        let now = Instant::now();
+        let push_all = |reassembler: &mut Reassembler<&str>| -> Vec<_> {
+            fragments
+                .iter()
+                .map(|fragment| reassembler.push("peer", fragment.clone(), now))
+                .collect()
+        };
+
+        // Too many fragments to possibly fit, so only the first is rejected
+        let results = push_all(&mut Reassembler::new(2000));
+        assert_eq!(results[0], Err(Error::TooLarge(2000)));
+        assert!(results[1..].iter().all(|result| *result == Ok(None)));
+
+        // Few enough fragments, but too many bytes
+        let limit = 5000;
+        let results = push_all(&mut Reassembler::new(limit));
+        assert_eq!(results.last(), Some(&Err(Error::TooLarge(limit))));
+    }
+
+    #[test]
+    fn test_reassembly_times_out() {
+        let datagram = Datagram::publish("rust_club", "me", "a".repeat(2000));
+        let mut fragments = fragments(encode(&datagram, Encoding::Binary, 1));
+        let last = fragments.pop().unwrap();
+
+        let mut reassembler = Reassembler::new(DEFAULT_MAX_MESSAGE_SIZE);
+        let now = Instant::now();
+        for fragment in fragments {
+            assert_eq!(reassembler.push("peer", fragment, now), Ok(None));
+        }
+        reassembler.expire(now + REASSEMBLY_TIMEOUT);
+        assert_eq!(reassembler.push("peer", last, now), Ok(None));
+    }
+}
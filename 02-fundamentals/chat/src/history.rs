@@ -0,0 +1,117 @@
+use crate::protocol::PublishDatagram;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// The most recent messages published on a channel, numbered from 1 in the
+/// order the server received them.
+pub struct ChannelHistory {
+    capacity: usize,
+    last_sequence: u64,
+    messages: VecDeque<(u64, PublishDatagram)>,
+    // When a message was last added
+    updated: Instant,
+}
+
+impl ChannelHistory {
+    pub fn new(capacity: usize) -> Self {
+        ChannelHistory {
+            capacity,
+            last_sequence: 0,
+            messages: VecDeque::with_capacity(capacity),
+            updated: Instant::now(),
+        }
+    }
+
+    pub fn push(&mut self, datagram: PublishDatagram) -> u64 {
+        self.last_sequence += 1;
+        self.updated = Instant::now();
+        if self.capacity == 0 {
+            return self.last_sequence;
+        }
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((self.last_sequence, datagram));
+        self.last_sequence
+    }
+
+    // Puts back a message that was recorded before a restart
+    pub fn restore(&mut self, sequence: u64, datagram: PublishDatagram) {
+        self.last_sequence = self.last_sequence.max(sequence);
+        self.updated = Instant::now();
+        if self.capacity == 0 {
+            return;
+        }
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((sequence, datagram));
+    }
+
+    pub fn updated(&self) -> Instant {
+        self.updated
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &(u64, PublishDatagram)> {
+        self.messages.iter()
+    }
+
+    /// The latest `count` messages, oldest first
+    pub fn last(&self, count: u64) -> Vec<&PublishDatagram> {
+        let skip = self.messages.len().saturating_sub(count as usize);
+        self.messages.iter().skip(skip).map(|(_, d)| d).collect()
+    }
+}
+
+#[cfg(test)]
+mod history_tests {
+    use super::*;
+
+    fn message(n: u32) -> PublishDatagram {
+        PublishDatagram {
+            channel: String::from("rust_club"),
+            display_name: String::from("me"),
+            message: format!("message {}", n),
+        }
+    }
+
+    fn messages(history: &ChannelHistory, count: u64) -> Vec<String> {
+        history
+            .last(count)
+            .into_iter()
+            .map(|d| d.message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut history = ChannelHistory::new(3);
+        for n in 1..=5 {
+            assert_eq!(history.push(message(n)), u64::from(n));
+        }
+        assert_eq!(
+            messages(&history, 10),
+            vec!["message 3", "message 4", "message 5"]
+        );
+    }
+
+    #[test]
+    fn test_history_last() {
+        let mut history = ChannelHistory::new(10);
+        for n in 1..=5 {
+            history.push(message(n));
+        }
+        assert_eq!(messages(&history, 2), vec!["message 4", "message 5"]);
+        assert!(messages(&history, 0).is_empty());
+    }
+
+    #[test]
+    fn test_history_restore() {
+        let mut history = ChannelHistory::new(2);
+        history.restore(7, message(7));
+        history.restore(8, message(8));
+        history.restore(9, message(9));
+        assert_eq!(history.push(message(10)), 10);
+        assert_eq!(messages(&history, 10), vec!["message 9", "message 10"]);
+    }
+}
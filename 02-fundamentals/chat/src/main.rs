@@ -4,17 +4,43 @@ extern crate clap;
 extern crate log;
 extern crate env_logger;
 
+mod acl;
+mod auth;
 mod client;
+mod config;
+mod encryption;
+mod fragment;
+mod history;
+mod net;
+mod nicks;
 mod protocol;
+mod rate_limit;
+mod reliability;
+mod repl;
 mod server;
+mod storage;
+mod transport;
+mod tui;
 
+use auth::Signer;
 use clap::{App, Arg, ArgMatches, SubCommand};
 use client::Client;
-use protocol::{Datagram, PublishDatagram};
+use config::Config;
+use protocol::{validate_display_name, Datagram, Encoding, PublishDatagram};
+use repl::{Command, Session};
 use server::Server;
-use std::net::SocketAddrV4;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
-const DEFAULT_UDP_PORT: u16 = 31337;
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn main() {
     env_logger::init();
@@ -34,6 +60,163 @@ fn main() {
                         .help("Select a port to listen on")
                         .takes_value(true)
                         .validator(validate_u16_arg),
+                )
+                .arg(
+                    Arg::with_name("bind")
+                        .long("bind")
+                        .value_name("IP")
+                        .help("Address to listen on (defaults to every IPv4 and IPv6 address)")
+                        .takes_value(true)
+                        .validator(validate_ip_address),
+                )
+                .arg(
+                    Arg::with_name("tcp_port")
+                        .long("tcp-port")
+                        .value_name("PORT")
+                        .help("Also accept TCP connections on this port")
+                        .takes_value(true)
+                        .validator(validate_u16_arg),
+                )
+                .arg(
+                    Arg::with_name("websocket_port")
+                        .long("websocket-port")
+                        .value_name("PORT")
+                        .help("Also accept WebSocket connections on this port")
+                        .takes_value(true)
+                        .validator(validate_u16_arg),
+                )
+                .arg(
+                    Arg::with_name("heartbeat_interval")
+                        .long("heartbeat-interval")
+                        .value_name("SECONDS")
+                        .help("How often to ping subscribers")
+                        .takes_value(true)
+                        .validator(validate_u16_arg),
+                )
+                .arg(
+                    Arg::with_name("max_missed_heartbeats")
+                        .long("max-missed-heartbeats")
+                        .value_name("COUNT")
+                        .help("Unsubscribe clients that miss this many pings")
+                        .takes_value(true)
+                        .validator(validate_u16_arg),
+                )
+                .arg(
+                    Arg::with_name("history_size")
+                        .long("history-size")
+                        .value_name("COUNT")
+                        .help("How many recent messages to keep for each channel")
+                        .takes_value(true)
+                        .validator(validate_u16_arg),
+                )
+                .arg(
+                    Arg::with_name("history_channels")
+                        .long("history-channels")
+                        .value_name("COUNT")
+                        .help("How many channels to keep recent messages for")
+                        .takes_value(true)
+                        .validator(validate_positive_arg),
+                )
+                .arg(
+                    Arg::with_name("max_message_size")
+                        .long("max-message-size")
+                        .value_name("BYTES")
+                        .help("Largest datagram to reassemble from fragments")
+                        .takes_value(true)
+                        .validator(validate_usize_arg),
+                )
+                .arg(
+                    Arg::with_name("key_file")
+                        .long("key-file")
+                        .value_name("FILE")
+                        .help("Only accept datagrams signed with one of these KEY_ID:SECRET keys")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("rate_limit")
+                        .long("rate-limit")
+                        .value_name("MESSAGES")
+                        .help("Most messages each IP address may publish per second")
+                        .takes_value(true)
+                        .validator(validate_rate_arg),
+                )
+                .arg(
+                    Arg::with_name("rate_burst")
+                        .long("rate-burst")
+                        .value_name("MESSAGES")
+                        .help("Most messages each IP address may publish at once (defaults to the rate)")
+                        .takes_value(true)
+                        .requires("rate_limit")
+                        .validator(validate_rate_arg),
+                )
+                .arg(
+                    Arg::with_name("channel_rate_limit")
+                        .long("channel-rate-limit")
+                        .value_name("MESSAGES")
+                        .help("Most messages that may be published to each channel per second")
+                        .takes_value(true)
+                        .validator(validate_rate_arg),
+                )
+                .arg(
+                    Arg::with_name("channel_rate_burst")
+                        .long("channel-rate-burst")
+                        .value_name("MESSAGES")
+                        .help("Most messages that may be published to each channel at once (defaults to the rate)")
+                        .takes_value(true)
+                        .requires("channel_rate_limit")
+                        .validator(validate_rate_arg),
+                )
+                .arg(
+                    Arg::with_name("acl_file")
+                        .long("acl-file")
+                        .value_name("FILE")
+                        .help("Who may read, write and moderate each channel")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("data_dir")
+                        .long("data-dir")
+                        .value_name("DIRECTORY")
+                        .help("Keep a log here so subscriptions and history survive restarts")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("peer")
+                        .long("peer")
+                        .value_name("SERVER_ADDRESS")
+                        .help("Link to another server, which must list this one as a peer too")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .validator(validate_server_address),
+                )
+                .arg(
+                    Arg::with_name("queue_size")
+                        .long("queue-size")
+                        .value_name("DATAGRAMS")
+                        .help("Most datagrams that may wait to be sent to each subscriber before more are dropped")
+                        .takes_value(true)
+                        .validator(validate_positive_arg),
+                )
+                .arg(
+                    Arg::with_name("send_threads")
+                        .long("send-threads")
+                        .value_name("THREADS")
+                        .help("How many threads send datagrams to subscribers")
+                        .takes_value(true)
+                        .validator(validate_positive_arg),
+                )
+                .arg(
+                    Arg::with_name("config")
+                        .long("config")
+                        .value_name("FILE")
+                        .help("Read settings from this TOML file, which flags override. Reloaded on SIGHUP.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("check_config")
+                        .long("check-config")
+                        .help("Check the configuration, including the files it refers to, and exit"),
                 ),
         )
         .subcommand(
@@ -44,8 +227,8 @@ fn main() {
                         .short("s")
                         .long("server")
                         .value_name("SERVER_ADDRESS")
-                        .help("IPv4 address of the server (e.g. 127.0.0.1:31337)")
-                        .validator(validate_ipv4_address)
+                        .help("Address of the server (e.g. localhost:31337 or [::1]:31337)")
+                        .validator(validate_server_address)
                         .takes_value(true)
                         .required(true),
                 )
@@ -58,6 +241,14 @@ fn main() {
                         .takes_value(true)
                         .validator(validate_u16_arg),
                 )
+                .arg(
+                    Arg::with_name("bind")
+                        .long("bind")
+                        .value_name("IP")
+                        .help("Address to listen on for replies")
+                        .takes_value(true)
+                        .validator(validate_ip_address),
+                )
                 .arg(
                     Arg::with_name("channel")
                         .short("c")
@@ -72,8 +263,78 @@ fn main() {
                         .short("m")
                         .long("message")
                         .value_name("MESSAGE")
-                        .help("Message to send on connect")
+                        .help("Message to send on connect, as CHANNEL|NAME|MESSAGE (or CHANNEL|MESSAGE with --nick)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("history")
+                        .long("history")
+                        .value_name("COUNT")
+                        .help("Replay this many recent messages from each channel")
+                        .takes_value(true)
+                        .validator(validate_u16_arg),
+                )
+                .arg(
+                    Arg::with_name("binary")
+                        .short("b")
+                        .long("binary")
+                        .help("Use the binary encoding instead of the text one"),
+                )
+                .arg(
+                    Arg::with_name("tcp")
+                        .short("t")
+                        .long("tcp")
+                        .help("Connect to the server over TCP instead of UDP")
+                        .conflicts_with_all(&["port", "bind"]),
+                )
+                .arg(
+                    Arg::with_name("interactive")
+                        .short("i")
+                        .long("interactive")
+                        .help("Read messages and commands from stdin (type /help once running)"),
+                )
+                .arg(
+                    Arg::with_name("tui")
+                        .long("tui")
+                        .help("Run a full-screen terminal UI, with a pane for each channel")
+                        .conflicts_with("interactive"),
+                )
+                .arg(
+                    Arg::with_name("nick")
+                        .short("n")
+                        .long("nick")
+                        .value_name("NAME")
+                        .help("Display name to claim on the server (defaults to $USER when interactive)")
+                        .takes_value(true)
+                        .validator(|s| validate_display_name(&s).map_err(|e| e.to_string())),
+                )
+                .arg(
+                    Arg::with_name("key_file")
+                        .long("key-file")
+                        .value_name("FILE")
+                        .help("Sign everything with a KEY_ID:SECRET key from this file")
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("key_id")
+                        .long("key-id")
+                        .value_name("KEY_ID")
+                        .help("Which key to sign with (defaults to the first in the file)")
+                        .takes_value(true)
+                        .requires("key_file"),
+                )
+                .arg(
+                    Arg::with_name("channel_keys")
+                        .long("channel-keys")
+                        .value_name("FILE")
+                        .help("Encrypt channels end to end with CHANNEL:PASSPHRASE pairs from this file")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("reliable")
+                        .short("r")
+                        .long("reliable")
+                        .help("Retransmit until the server acknowledges each datagram"),
                 ),
         )
         .get_matches();
@@ -90,11 +351,38 @@ fn validate_u16_arg(s: String) -> Result<(), String> {
     result.map(|_| ()).map_err(|error| format!("{}", error))
 }
 
-fn validate_ipv4_address(s: String) -> Result<(), String> {
-    let result: Result<SocketAddrV4, std::net::AddrParseError> = s.parse();
+fn validate_usize_arg(s: String) -> Result<(), String> {
+    let result: Result<usize, std::num::ParseIntError> = s.parse();
+    result.map(|_| ()).map_err(|error| format!("{}", error))
+}
+
+fn validate_positive_arg(s: String) -> Result<(), String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err(String::from("must be at least 1")),
+        Ok(_) => Ok(()),
+        Err(error) => Err(format!("{}", error)),
+    }
+}
+
+fn validate_rate_arg(s: String) -> Result<(), String> {
+    match s.parse::<u32>() {
+        Ok(0) => Err(String::from("must be at least 1")),
+        Ok(_) => Ok(()),
+        Err(error) => Err(format!("{}", error)),
+    }
+}
+
+fn validate_ip_address(s: String) -> Result<(), String> {
+    let result: Result<IpAddr, std::net::AddrParseError> = s.parse();
     result.map(|_| ()).map_err(|error| format!("{}", error))
 }
 
+fn validate_server_address(s: String) -> Result<(), String> {
+    net::resolve(&s)
+        .map(|_| ())
+        .map_err(|error| format!("{}", error))
+}
+
 pub fn run_client(app: &ArgMatches) {
     let server_address_arg = app.value_of("server_address").unwrap();
     let port_arg = match app.value_of("port") {
@@ -102,42 +390,218 @@ pub fn run_client(app: &ArgMatches) {
         None => 0,
     };
     let channels_arg = app.values_of("channel");
-    let message_arg = app
-        .value_of("message")
-        .map(|s| Datagram::Publish(PublishDatagram::parse(s).unwrap()));
+    let nick_arg = app.value_of("nick");
+    let message_arg = app.value_of("message").map(|s| {
+        let publish = match nick_arg {
+            Some(nick) => PublishDatagram::parse_as(s, nick),
+            None => PublishDatagram::parse(s),
+        };
+        Datagram::Publish(publish.unwrap_or_else(|error| exit_with(error)))
+    });
+    let interactive = app.is_present("interactive") || app.is_present("tui");
+
+    let history_arg: Option<u64> = app.value_of("history").map(|s| s.parse().unwrap());
+    let reliable = app.is_present("reliable");
 
-    let client = Client::new(port_arg, server_address_arg.parse().unwrap()).unwrap();
+    let server_address = net::resolve(server_address_arg).unwrap();
+    let address = match app.value_of("bind") {
+        Some(ip) => SocketAddr::new(ip.parse().unwrap(), port_arg),
+        None => net::unspecified_for(&server_address, port_arg),
+    };
+    let mut client = if app.is_present("tcp") {
+        Client::connect_tcp(server_address).unwrap()
+    } else {
+        Client::new(address, server_address).unwrap()
+    };
+    if app.is_present("binary") {
+        client.set_encoding(Encoding::Binary);
+    }
+    if let Some(path) = app.value_of("key_file") {
+        let keys = auth::load_keys(path).unwrap();
+        let (key_id, secret) = match app.value_of("key_id") {
+            Some(key_id) => keys.into_iter().find(|(id, _)| id == key_id),
+            None => keys.into_iter().next(),
+        }
+        .expect("No such key in the key file");
+        client.set_signer(Signer::new(key_id, secret));
+    }
+    // The key file format works just as well for passphrases
+    if let Some(path) = app.value_of("channel_keys") {
+        for (channel, passphrase) in auth::load_keys(path).unwrap() {
+            client.encrypt_channel(&channel, &passphrase);
+        }
+    }
+    let nick = match nick_arg {
+        Some(nick) => Some(String::from(nick)),
+        None if interactive => {
+            Some(std::env::var("USER").unwrap_or_else(|_| String::from("anonymous")))
+        }
+        None => None,
+    };
+    // Only claim the name if we're sticking around, so one-off messages don't
+    // hold on to it until the server notices we've gone
+    if let Some(nick) = &nick {
+        if interactive || channels_arg.is_some() {
+            repl::send(&mut client, &Datagram::nick(nick.as_str()), reliable).unwrap();
+        }
+    }
 
     // Send a message
     if let Some(message) = &message_arg {
-        client.send(message).unwrap();
+        repl::send(&mut client, message, reliable).unwrap();
+    }
+
+    if interactive {
+        let mut session = Session::new(nick.unwrap());
+        for channel in channels_arg.into_iter().flatten() {
+            if let Some(count) = history_arg {
+                repl::send(&mut client, &Datagram::history(channel, count), reliable).unwrap();
+            }
+            match session.handle(Command::Join(String::from(channel))) {
+                Ok(datagrams) => {
+                    for datagram in datagrams {
+                        repl::send(&mut client, &datagram, reliable).unwrap();
+                    }
+                }
+                Err(error) => eprintln!("{}", error),
+            }
+        }
+        if app.is_present("tui") {
+            tui::run(client, session, reliable).unwrap();
+        } else {
+            repl::run(client, session, reliable);
+        }
+        return;
     }
 
     if let Some(channels) = channels_arg {
         for channel in channels {
-            client.send(&Datagram::subscribe(channel)).unwrap();
+            // Ask for history first so it arrives before anything live
+            if let Some(count) = history_arg {
+                repl::send(&mut client, &Datagram::history(channel, count), reliable).unwrap();
+            }
+            repl::send(&mut client, &Datagram::subscribe(channel), reliable).unwrap();
         }
     } else {
-        // Nothing else to do if we're not subscribing
+        // Nothing else to do if we're not subscribing, once the server has everything
+        if !client.flush(FLUSH_TIMEOUT) {
+            warn!("Server didn't acknowledge everything we sent");
+        }
         return;
     }
 
     loop {
-        if let Some(datagram) = client.listen(None) {
-            println!("{}", datagram.serialize());
-        } else {
-            debug!("No messages recieved...");
+        match client.listen(None) {
+            Some(Ok(datagram)) => println!("{}", datagram.serialize()),
+            Some(Err(error)) => eprintln!("Server error: {}", error),
+            None => debug!("No messages recieved..."),
         }
     }
 }
 
-pub fn run_server(server_app: &ArgMatches) -> ! {
-    let port = match server_app.value_of("port") {
-        Some(s) => s.parse().unwrap(),
-        None => DEFAULT_UDP_PORT,
-    };
+fn value<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T>
+where
+    T::Err: fmt::Debug,
+{
+    // Everything has been through a validator already
+    matches.value_of(name).map(|s| s.parse().unwrap())
+}
+
+// Whatever was given on the command line, which overrides the config file
+fn flags(server_app: &ArgMatches) -> Config {
+    Config {
+        bind: value(server_app, "bind"),
+        port: value(server_app, "port"),
+        tcp_port: value(server_app, "tcp_port"),
+        websocket_port: value(server_app, "websocket_port"),
+        peers: server_app
+            .values_of("peer")
+            .map(|peers| peers.map(String::from).collect()),
+        heartbeat_interval: value(server_app, "heartbeat_interval"),
+        max_missed_heartbeats: value(server_app, "max_missed_heartbeats"),
+        history_size: value(server_app, "history_size"),
+        history_channels: value(server_app, "history_channels"),
+        max_message_size: value(server_app, "max_message_size"),
+        queue_size: value(server_app, "queue_size"),
+        send_threads: value(server_app, "send_threads"),
+        rate_limit: value(server_app, "rate_limit"),
+        rate_burst: value(server_app, "rate_burst"),
+        channel_rate_limit: value(server_app, "channel_rate_limit"),
+        channel_rate_burst: value(server_app, "channel_rate_burst"),
+        key_file: value(server_app, "key_file"),
+        acl_file: value(server_app, "acl_file"),
+        data_dir: value(server_app, "data_dir"),
+        ..Config::default()
+    }
+}
 
-    debug!("Running server on port: {}", port);
-    Server::new(port).unwrap().run()
+fn exit_with<E: fmt::Display>(error: E) -> ! {
+    eprintln!("{}", error);
+    process::exit(1)
 }
 
+// The config file, if there is one, with the flags on top
+fn load_config(flags: &Config, config_file: Option<&Path>) -> Result<Config, io::Error> {
+    match config_file {
+        Some(path) => Ok(flags.clone().or(Config::load(path)?)),
+        None => Ok(flags.clone()),
+    }
+}
+
+pub fn run_server(server_app: &ArgMatches) {
+    let flags = flags(server_app);
+    let config_file: Option<PathBuf> = value(server_app, "config");
+    let loaded = load_config(&flags, config_file.as_deref());
+    if server_app.is_present("check_config") {
+        if let Err(error) = loaded.and_then(|config| config.check()) {
+            exit_with(error);
+        }
+        println!("Configuration is valid");
+        return;
+    }
+    let config = loaded.unwrap_or_else(|error| exit_with(error));
+    let options = config.options().unwrap_or_else(|error| exit_with(error));
+
+    let mut server = Server::new(config.address(), options).unwrap();
+    debug!("Running server on: {}", server.local_addr());
+    if let Some(address) = config.tcp_address() {
+        let address = server.listen_tcp(address).unwrap();
+        debug!("Accepting TCP connections on: {}", address);
+    }
+    if let Some(address) = config.websocket_address() {
+        let address = server.listen_websocket(address).unwrap();
+        debug!("Accepting WebSocket connections on: {}", address);
+    }
+
+    // Say goodbye to everyone when asked to stop, rather than just vanishing,
+    // and pick up changes to the config file when asked to reload it
+    let handle = server.handle();
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP]).unwrap();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal != SIGHUP {
+                info!("Received signal {}", signal);
+                handle.shutdown();
+                continue;
+            }
+            info!("Reloading configuration");
+            let options = load_config(&flags, config_file.as_deref()).and_then(|reloaded| {
+                if reloaded.address() != config.address()
+                    || reloaded.tcp_address() != config.tcp_address()
+                    || reloaded.websocket_address() != config.websocket_address()
+                {
+                    warn!("Restart the server to change the addresses it listens on");
+                }
+                reloaded.options()
+            });
+            match options {
+                Ok(options) => handle.reload(options),
+                Err(error) => error!("Not reloading: {}", error),
+            }
+        }
+    });
+    if let Err(error) = server.run() {
+        error!("Error shutting down: {}", error);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,114 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
+
+/// Binds a UDP socket. Binding to the unspecified IPv6 address (`::`) also
+/// accepts IPv4 traffic where the platform allows it, and falls back to
+/// `0.0.0.0` on hosts without IPv6 at all.
+pub fn bind(address: SocketAddr) -> Result<UdpSocket, Error> {
+    Ok(socket(address, Type::DGRAM, Protocol::UDP)?.into())
+}
+
+/// Listens for TCP connections, binding the same way as `bind`
+pub fn listen(address: SocketAddr) -> Result<TcpListener, Error> {
+    let socket = socket(address, Type::STREAM, Protocol::TCP)?;
+    socket.set_reuse_address(true)?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+fn socket(address: SocketAddr, kind: Type, protocol: Protocol) -> Result<Socket, Error> {
+    let dual_stack = address.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+    let socket = match Socket::new(Domain::for_address(address), kind, Some(protocol)) {
+        Ok(socket) => socket,
+        Err(error) if dual_stack => {
+            warn!("IPv6 is unavailable ({}), only binding to IPv4", error);
+            let address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), address.port());
+            return socket(address, kind, protocol);
+        }
+        Err(error) => return Err(error),
+    };
+    if dual_stack {
+        socket.set_only_v6(false)?;
+    }
+    socket.bind(&address.into())?;
+    Ok(socket)
+}
+
+/// The address to bind to when talking to `peer`, which has to be in the
+/// same address family
+pub fn unspecified_for(peer: &SocketAddr, port: u16) -> SocketAddr {
+    let ip: IpAddr = match peer {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    SocketAddr::new(ip, port)
+}
+
+/// The address to send to `peer` at from a socket bound to `local`. IPv4
+/// addresses are mapped into IPv6 for sockets bound to both, which is also
+/// how datagrams from them show up on those sockets.
+pub fn reachable_from(local: &SocketAddr, peer: SocketAddr) -> SocketAddr {
+    match (local, peer.ip()) {
+        (SocketAddr::V6(_), IpAddr::V4(ip)) => {
+            SocketAddr::new(ip.to_ipv6_mapped().into(), peer.port())
+        }
+        _ => peer,
+    }
+}
+
+/// Resolves a `host:port` pair, where the host can be a name or either kind
+/// of IP address (IPv6 ones in brackets, e.g. `[::1]:31337`)
+pub fn resolve(s: &str) -> Result<SocketAddr, Error> {
+    s.to_socket_addrs()?.next().ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("No addresses found for: {}", s),
+        )
+    })
+}
+
+#[cfg(test)]
+mod net_tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            resolve("127.0.0.1:31337").unwrap(),
+            "127.0.0.1:31337".parse().unwrap()
+        );
+        assert_eq!(
+            resolve("[::1]:31337").unwrap(),
+            "[::1]:31337".parse().unwrap()
+        );
+        assert!(resolve("localhost:31337").unwrap().ip().is_loopback());
+        assert!(resolve("localhost").is_err());
+    }
+
+    #[test]
+    fn test_reachable_from() {
+        let v4: SocketAddr = "127.0.0.1:31337".parse().unwrap();
+        let v6: SocketAddr = "[::1]:31337".parse().unwrap();
+        let dual_stack: SocketAddr = "[::]:0".parse().unwrap();
+        let only_v4: SocketAddr = "0.0.0.0:0".parse().unwrap();
+        assert_eq!(
+            reachable_from(&dual_stack, v4),
+            "[::ffff:127.0.0.1]:31337".parse().unwrap()
+        );
+        assert_eq!(reachable_from(&dual_stack, v6), v6);
+        assert_eq!(reachable_from(&only_v4, v4), v4);
+    }
+
+    #[test]
+    fn test_dual_stack_bind() {
+        let socket = bind("[::]:0".parse().unwrap()).unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        let v4 = UdpSocket::bind("127.0.0.1:0").unwrap();
+        v4.send_to(b"I|1", ("127.0.0.1", port)).unwrap();
+        let mut buf = [0; 3];
+        let (n, _) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"I|1");
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    // Someone else has already registered the name
+    InUse(String),
+    // The sender has registered a different name
+    NotYours { registered: String },
+    // The name belongs to someone else
+    Impersonation(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InUse(nick) => write!(f, "Nick already in use: {}", nick),
+            Error::NotYours { registered } => write!(f, "You're registered as: {}", registered),
+            Error::Impersonation(nick) => write!(f, "Nick belongs to someone else: {}", nick),
+        }
+    }
+}
+
+/// Which peer owns which display name. Names are compared ignoring case, so
+/// "Ferris" can't pass for "ferris".
+pub struct NickRegistry<K: Eq + Hash + Clone> {
+    nicks: HashMap<K, String>,
+    owners: HashMap<String, K>,
+}
+
+fn fold(nick: &str) -> String {
+    nick.to_lowercase()
+}
+
+impl<K: Eq + Hash + Clone> NickRegistry<K> {
+    pub fn new() -> Self {
+        NickRegistry {
+            nicks: HashMap::new(),
+            owners: HashMap::new(),
+        }
+    }
+
+    /// Claims `nick` for `peer`, giving up whatever it had before
+    pub fn register(&mut self, peer: K, nick: &str) -> Result<(), Error> {
+        match self.owners.get(&fold(nick)) {
+            Some(owner) if *owner != peer => return Err(Error::InUse(String::from(nick))),
+            _ => {}
+        }
+        self.forget(&peer);
+        self.owners.insert(fold(nick), peer.clone());
+        self.nicks.insert(peer, String::from(nick));
+        Ok(())
+    }
+
+    /// Who has registered `nick`, if anyone
+    pub fn owner(&self, nick: &str) -> Option<&K> {
+        self.owners.get(&fold(nick))
+    }
+
+    pub fn nick(&self, peer: &K) -> Option<&str> {
+        self.nicks.get(peer).map(String::as_str)
+    }
+
+    /// Checks that `peer` is allowed to publish as `display_name`. Peers that
+    /// haven't registered can use any name nobody else has claimed.
+    pub fn check(&self, peer: &K, display_name: &str) -> Result<(), Error> {
+        if let Some(registered) = self.nicks.get(peer) {
+            if fold(registered) != fold(display_name) {
+                return Err(Error::NotYours {
+                    registered: registered.clone(),
+                });
+            }
+            return Ok(());
+        }
+        if self.owners.contains_key(&fold(display_name)) {
+            return Err(Error::Impersonation(String::from(display_name)));
+        }
+        Ok(())
+    }
+
+    pub fn forget(&mut self, peer: &K) {
+        if let Some(nick) = self.nicks.remove(peer) {
+            self.owners.remove(&fold(&nick));
+        }
+    }
+}
+
+#[cfg(test)]
+mod nicks_tests {
+    use super::*;
+
+    #[test]
+    fn test_register() {
+        let mut nicks = NickRegistry::new();
+        assert_eq!(nicks.register(1, "ferris"), Ok(()));
+        assert_eq!(nicks.register(1, "ferris"), Ok(()));
+        assert_eq!(
+            nicks.register(2, "Ferris"),
+            Err(Error::InUse(String::from("Ferris")))
+        );
+
+        // Changing names frees up the old one
+        assert_eq!(nicks.register(1, "crab"), Ok(()));
+        assert_eq!(nicks.nick(&1), Some("crab"));
+        assert_eq!(nicks.register(2, "Ferris"), Ok(()));
+        assert_eq!(nicks.nick(&2), Some("Ferris"));
+    }
+
+    #[test]
+    fn test_check() {
+        let mut nicks = NickRegistry::new();
+        nicks.register(1, "ferris").unwrap();
+
+        assert_eq!(nicks.check(&1, "ferris"), Ok(()));
+        assert_eq!(nicks.check(&1, "FERRIS"), Ok(()));
+        assert_eq!(
+            nicks.check(&1, "crab"),
+            Err(Error::NotYours {
+                registered: String::from("ferris")
+            })
+        );
+        assert_eq!(
+            nicks.check(&2, "Ferris"),
+            Err(Error::Impersonation(String::from("Ferris")))
+        );
+        assert_eq!(nicks.check(&2, "crab"), Ok(()));
+
+        nicks.forget(&1);
+        assert_eq!(nicks.check(&2, "ferris"), Ok(()));
+        assert_eq!(nicks.nick(&1), None);
+    }
+}
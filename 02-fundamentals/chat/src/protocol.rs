@@ -1,28 +1,161 @@
-#[derive(Debug)]
+mod binary;
+mod text;
+
+use std::fmt;
+use std::str;
+use text::{escape, escape_last, fields, unescape};
+
+// Anything bigger than this is truncated by the receiver
+pub const MAX_DATAGRAM_SIZE: usize = 1024;
+pub const MAX_CHANNEL_LENGTH: usize = 64;
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 32;
+// Subscriptions can use these in place of a level of a channel name
+pub const SINGLE_LEVEL_WILDCARD: &str = "*";
+pub const MULTI_LEVEL_WILDCARD: &str = "#";
+
+#[derive(Debug, PartialEq)]
 pub enum Error {
-    BadDatagram(String),
+    UnknownOpcode(String),
+    MissingField(&'static str),
+    InvalidChannel(String),
+    InvalidPattern(String),
+    InvalidDisplayName(String),
+    InvalidTarget(String),
+    InvalidEvent(String),
+    InvalidAction(String),
+    InvalidNumber(String),
+    InvalidEscape(String),
+    InvalidUtf8,
+    // Problems with the framing of the binary encoding
+    Malformed(String),
+    // A fragmented datagram that would be bigger than the limit once reassembled
+    TooLarge(usize),
+    // A reliable datagram inside another, or a signed one inside another
+    Nested(&'static str),
 }
 
-#[derive(Debug, PartialEq)]
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnknownOpcode(opcode) => write!(f, "Unknown opcode: {}", opcode),
+            Error::MissingField(field) => write!(f, "Missing field: {}", field),
+            Error::InvalidChannel(channel) => write!(f, "Invalid channel: {}", channel),
+            Error::InvalidPattern(pattern) => write!(f, "Invalid pattern: {}", pattern),
+            Error::InvalidDisplayName(name) => write!(f, "Invalid display name: {}", name),
+            Error::InvalidTarget(target) => write!(f, "Invalid target: {}", target),
+            Error::InvalidEvent(event) => write!(f, "Invalid presence event: {}", event),
+            Error::InvalidAction(action) => write!(f, "Invalid moderation action: {}", action),
+            Error::InvalidNumber(number) => write!(f, "Invalid number: {}", number),
+            Error::InvalidEscape(escape) => write!(f, "Invalid escape: {}", escape),
+            Error::InvalidUtf8 => write!(f, "Datagram is not UTF8"),
+            Error::Malformed(message) => write!(f, "Malformed datagram: {}", message),
+            Error::TooLarge(limit) => write!(f, "Messages are limited to {} bytes", limit),
+            Error::Nested(kind) => write!(f, "Nested {} datagram", kind),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Encoding {
+    // Pipe-delimited, human readable text
+    Text,
+    // Versioned, with explicit field lengths
+    Binary,
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum Datagram {
     Subscribe(SubscribeDatagram),
     Unsubscribe(UnsubscribeDatagram),
     Publish(PublishDatagram),
-    Error(String),
+    Error(ErrorDatagram),
+    Reliable(ReliableDatagram),
+    Ack(u64),
+    Ping(u64),
+    Pong(u64),
+    History(HistoryDatagram),
+    Fragment(FragmentDatagram),
+    Nick(NickDatagram),
+    Signed(SignedDatagram),
+    Moderate(ModerateDatagram),
+    Direct(DirectDatagram),
+    List(ListDatagram),
+    ChannelList(ChannelListDatagram),
+    Who(WhoDatagram),
+    MemberList(MemberListDatagram),
+    Presence(PresenceDatagram),
+    Interest(InterestDatagram),
+    Forward(ForwardDatagram),
+    Goodbye(String),
+}
+
+// The wrappers around a datagram being parsed. Reliable and signed datagrams
+// may each wrap the other, but not themselves, so nothing can nest deeply
+// enough to overflow the stack.
+#[derive(Debug, Default, Clone, Copy)]
+struct Wrappers {
+    reliable: bool,
+    signed: bool,
+}
+
+impl Wrappers {
+    fn reliable(self) -> Result<Self, Error> {
+        if self.reliable {
+            return Err(Error::Nested("reliable"));
+        }
+        Ok(Wrappers {
+            reliable: true,
+            ..self
+        })
+    }
+
+    fn signed(self) -> Result<Self, Error> {
+        if self.signed {
+            return Err(Error::Nested("signed"));
+        }
+        Ok(Wrappers {
+            signed: true,
+            ..self
+        })
+    }
 }
 
 impl Datagram {
     pub fn parse(s: &str) -> Result<Self, Error> {
+        Datagram::parse_within(s, Wrappers::default())
+    }
+
+    fn parse_within(s: &str, wrappers: Wrappers) -> Result<Self, Error> {
         let mut iter = s.splitn(2, '|');
-        match (iter.next(), iter.next()) {
-            (Some("S"), Some(rest)) => Ok(Datagram::Subscribe(SubscribeDatagram::parse(rest))),
-            (Some("U"), Some(rest)) => Ok(Datagram::Unsubscribe(UnsubscribeDatagram::parse(rest))),
-            (Some("P"), Some(rest)) => Ok(Datagram::Publish(PublishDatagram::parse(rest)?)),
-            (Some("E"), Some(rest)) => Ok(Datagram::Error(String::from(rest))),
-            _ => Err(Error::BadDatagram(format!(
-                "Could not parse Datagram: {}",
-                s
-            ))),
+        let opcode = iter.next().unwrap_or("");
+        // Everything after the opcode, or which field is missing without it
+        let mut rest = |field| iter.next().ok_or(Error::MissingField(field));
+        match opcode {
+            "S" => SubscribeDatagram::parse(rest("channel")?).map(Datagram::Subscribe),
+            "U" => UnsubscribeDatagram::parse(rest("channel")?).map(Datagram::Unsubscribe),
+            "P" => PublishDatagram::parse(rest("channel")?).map(Datagram::Publish),
+            "E" => ErrorDatagram::parse(rest("message")?).map(Datagram::Error),
+            "R" => ReliableDatagram::parse_within(rest("sequence")?, wrappers.reliable()?)
+                .map(Datagram::Reliable),
+            "A" => parse_sequence(rest("sequence")?).map(Datagram::Ack),
+            "I" => parse_sequence(rest("nonce")?).map(Datagram::Ping),
+            "O" => parse_sequence(rest("nonce")?).map(Datagram::Pong),
+            "H" => HistoryDatagram::parse(rest("channel")?).map(Datagram::History),
+            "F" => FragmentDatagram::parse(rest("fragment id")?).map(Datagram::Fragment),
+            "N" => NickDatagram::parse(rest("display name")?).map(Datagram::Nick),
+            "Z" => SignedDatagram::parse_within(rest("key id")?, wrappers.signed()?)
+                .map(Datagram::Signed),
+            "M" => ModerateDatagram::parse(rest("channel")?).map(Datagram::Moderate),
+            "D" => DirectDatagram::parse(rest("recipient")?).map(Datagram::Direct),
+            "L" => ListDatagram::parse(rest("pattern")?).map(Datagram::List),
+            "C" => ChannelListDatagram::parse(rest("pattern")?).map(Datagram::ChannelList),
+            "W" => WhoDatagram::parse(rest("channel")?).map(Datagram::Who),
+            "G" => MemberListDatagram::parse(rest("channel")?).map(Datagram::MemberList),
+            "J" => PresenceDatagram::parse(rest("channel")?).map(Datagram::Presence),
+            "Y" => InterestDatagram::parse(rest("node")?).map(Datagram::Interest),
+            "B" => ForwardDatagram::parse(rest("origin")?).map(Datagram::Forward),
+            "Q" => unescape(rest("message")?).map(Datagram::Goodbye),
+            opcode => Err(Error::UnknownOpcode(String::from(opcode))),
         }
     }
 
@@ -31,112 +164,960 @@ impl Datagram {
             Datagram::Subscribe(c) => format!("S|{}", c.serialize()),
             Datagram::Unsubscribe(c) => format!("U|{}", c.serialize()),
             Datagram::Publish(d) => format!("P|{}", d.serialize()),
-            Datagram::Error(e) => format!("E|{}", e),
+            Datagram::Error(e) => format!("E|{}", e.serialize()),
+            Datagram::Reliable(r) => format!("R|{}", r.serialize()),
+            Datagram::Ack(sequence) => format!("A|{}", sequence),
+            Datagram::Ping(nonce) => format!("I|{}", nonce),
+            Datagram::Pong(nonce) => format!("O|{}", nonce),
+            Datagram::History(h) => format!("H|{}", h.serialize()),
+            Datagram::Fragment(f) => format!("F|{}", f.serialize()),
+            Datagram::Nick(n) => format!("N|{}", n.serialize()),
+            Datagram::Signed(s) => format!("Z|{}", s.serialize()),
+            Datagram::Moderate(m) => format!("M|{}", m.serialize()),
+            Datagram::Direct(d) => format!("D|{}", d.serialize()),
+            Datagram::List(l) => format!("L|{}", l.serialize()),
+            Datagram::ChannelList(c) => format!("C|{}", c.serialize()),
+            Datagram::Who(w) => format!("W|{}", w.serialize()),
+            Datagram::MemberList(m) => format!("G|{}", m.serialize()),
+            Datagram::Presence(p) => format!("J|{}", p.serialize()),
+            Datagram::Interest(i) => format!("Y|{}", i.serialize()),
+            Datagram::Forward(f) => format!("B|{}", f.serialize()),
+            Datagram::Goodbye(message) => format!("Q|{}", escape_last(message)),
+        }
+    }
+
+    /// Checks the rules the text parser enforces, for datagrams that came
+    /// from somewhere else
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            Datagram::Subscribe(d) => validate_pattern(&d.channel),
+            Datagram::Unsubscribe(d) => validate_pattern(&d.channel),
+            Datagram::Publish(d) => {
+                validate_channel(&d.channel)?;
+                validate_display_name(&d.display_name)
+            }
+            Datagram::Reliable(d) => d.datagram.validate(),
+            Datagram::History(d) => validate_channel(&d.channel),
+            Datagram::Nick(d) => validate_display_name(&d.display_name),
+            Datagram::Signed(d) => d.datagram.validate(),
+            Datagram::Moderate(d) => {
+                validate_channel(&d.channel)?;
+                validate_target(&d.target)
+            }
+            Datagram::Direct(d) => {
+                validate_display_name(&d.recipient)?;
+                validate_display_name(&d.display_name)
+            }
+            Datagram::List(d) => validate_pattern(&d.pattern),
+            Datagram::ChannelList(d) => {
+                validate_pattern(&d.pattern)?;
+                d.channels
+                    .iter()
+                    .try_for_each(|(channel, _)| validate_channel(channel))
+            }
+            Datagram::Who(d) => validate_channel(&d.channel),
+            Datagram::MemberList(d) => {
+                validate_channel(&d.channel)?;
+                d.members.iter().try_for_each(|who| validate_target(who))
+            }
+            Datagram::Presence(d) => {
+                validate_channel(&d.channel)?;
+                validate_target(&d.who)
+            }
+            Datagram::Interest(d) => d.patterns.iter().try_for_each(|p| validate_pattern(p)),
+            Datagram::Forward(d) => {
+                validate_channel(&d.publish.channel)?;
+                validate_display_name(&d.publish.display_name)
+            }
+            // The reassembled datagram gets validated once it's complete
+            Datagram::Fragment(_) => Ok(()),
+            Datagram::Error(_)
+            | Datagram::Ack(_)
+            | Datagram::Ping(_)
+            | Datagram::Pong(_)
+            | Datagram::Goodbye(_) => Ok(()),
+        }
+    }
+
+    pub fn encode(&self, encoding: Encoding) -> Vec<u8> {
+        match encoding {
+            Encoding::Text => self.serialize().into_bytes(),
+            Encoding::Binary => binary::encode(self),
+        }
+    }
+
+    /// Decodes a datagram in either encoding, telling us which one was used
+    pub fn decode(buf: &[u8]) -> Result<(Self, Encoding), Error> {
+        if buf.first() == Some(&binary::MAGIC) {
+            let datagram = binary::decode(buf)?;
+            datagram.validate()?;
+            return Ok((datagram, Encoding::Binary));
+        }
+        let s = str::from_utf8(buf).map_err(|_| Error::InvalidUtf8)?;
+        Ok((Datagram::parse(s)?, Encoding::Text))
+    }
+
+    pub fn history<C: Into<String>>(channel: C, count: u64) -> Self {
+        Datagram::History(HistoryDatagram {
+            channel: channel.into(),
+            count,
+        })
+    }
+
+    pub fn reliable(sequence: u64, datagram: Datagram) -> Self {
+        Datagram::Reliable(ReliableDatagram {
+            sequence,
+            datagram: Box::new(datagram),
+        })
+    }
+
+    pub fn subscribe<C: Into<String>>(channel: C) -> Self {
+        Datagram::Subscribe(SubscribeDatagram {
+            channel: channel.into(),
+        })
+    }
+
+    pub fn unsubscribe<C: Into<String>>(channel: C) -> Self {
+        Datagram::Unsubscribe(UnsubscribeDatagram {
+            channel: channel.into(),
+        })
+    }
+
+    pub fn nick<N: Into<String>>(display_name: N) -> Self {
+        Datagram::Nick(NickDatagram {
+            display_name: display_name.into(),
+        })
+    }
+
+    pub fn moderate<C, T>(channel: C, action: ModerateAction, target: T) -> Self
+    where
+        C: Into<String>,
+        T: Into<String>,
+    {
+        Datagram::Moderate(ModerateDatagram {
+            channel: channel.into(),
+            action,
+            target: target.into(),
+        })
+    }
+
+    pub fn direct<R, N, M>(recipient: R, display_name: N, message: M) -> Self
+    where
+        R: Into<String>,
+        N: Into<String>,
+        M: Into<String>,
+    {
+        Datagram::Direct(DirectDatagram {
+            recipient: recipient.into(),
+            display_name: display_name.into(),
+            message: message.into(),
+        })
+    }
+
+    pub fn list<P: Into<String>>(pattern: P) -> Self {
+        Datagram::List(ListDatagram {
+            pattern: pattern.into(),
+        })
+    }
+
+    pub fn who<C: Into<String>>(channel: C) -> Self {
+        Datagram::Who(WhoDatagram {
+            channel: channel.into(),
+        })
+    }
+
+    pub fn presence<C, W>(channel: C, event: PresenceEvent, who: W) -> Self
+    where
+        C: Into<String>,
+        W: Into<String>,
+    {
+        Datagram::Presence(PresenceDatagram {
+            channel: channel.into(),
+            event,
+            who: who.into(),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn publish<C, N, M>(channel: C, display_name: N, message: M) -> Self
+    where
+        C: Into<String>,
+        N: Into<String>,
+        M: Into<String>,
+    {
+        Datagram::Publish(PublishDatagram {
+            channel: channel.into(),
+            display_name: display_name.into(),
+            message: message.into(),
+        })
+    }
+
+    pub fn rejection<M: Into<String>>(code: ErrorCode, message: M) -> Self {
+        Datagram::Error(ErrorDatagram {
+            code,
+            message: message.into(),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn error<M: Into<String>>(message: M) -> Self {
+        Datagram::rejection(ErrorCode::Other, message)
+    }
+
+    pub fn goodbye<M: Into<String>>(message: M) -> Self {
+        Datagram::Goodbye(message.into())
+    }
+}
+
+/// Channels are 1 to 64 ASCII letters, digits, `_`, `-` or `.`
+pub fn validate_channel(channel: &str) -> Result<(), Error> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.';
+    if channel.is_empty() || channel.len() > MAX_CHANNEL_LENGTH || !channel.chars().all(valid_char)
+    {
+        return Err(Error::InvalidChannel(String::from(channel)));
+    }
+    Ok(())
+}
+
+/// Patterns are channels whose levels (separated by `.`) can also be `*`,
+/// to match any one level, or end with `#`, to match any number of levels
+pub fn validate_pattern(pattern: &str) -> Result<(), Error> {
+    let levels: Vec<&str> = pattern.split('.').collect();
+    let mut literal = Vec::with_capacity(levels.len());
+    for (index, level) in levels.iter().enumerate() {
+        match *level {
+            SINGLE_LEVEL_WILDCARD => literal.push("_"),
+            MULTI_LEVEL_WILDCARD if index == levels.len() - 1 => literal.push("_"),
+            level if level.contains(&['*', '#'][..]) => {
+                return Err(Error::InvalidPattern(String::from(pattern)))
+            }
+            level => literal.push(level),
+        }
+    }
+    // Otherwise the same rules as channels apply
+    validate_channel(&literal.join(".")).map_err(|_| Error::InvalidChannel(String::from(pattern)))
+}
+
+pub fn is_pattern(pattern: &str) -> bool {
+    pattern
+        .split('.')
+        .any(|level| level == SINGLE_LEVEL_WILDCARD || level == MULTI_LEVEL_WILDCARD)
+}
+
+/// Whether a message on `channel` goes to subscribers of `pattern`
+pub fn matches_pattern(pattern: &str, channel: &str) -> bool {
+    let mut channel = channel.split('.');
+    for level in pattern.split('.') {
+        match (level, channel.next()) {
+            (MULTI_LEVEL_WILDCARD, _) => return true,
+            (SINGLE_LEVEL_WILDCARD, Some(_)) => {}
+            (level, Some(actual)) if level == actual => {}
+            _ => return false,
+        }
+    }
+    channel.next().is_none()
+}
+
+/// Display names are 1 to 32 characters, none of which are control characters
+pub fn validate_display_name(display_name: &str) -> Result<(), Error> {
+    if display_name.is_empty()
+        || display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH
+        || display_name.chars().any(char::is_control)
+    {
+        return Err(Error::InvalidDisplayName(String::from(display_name)));
+    }
+    Ok(())
+}
+
+/// Moderation targets are a nick, an IP address or `*`, so just can't be
+/// empty or contain control characters
+pub fn validate_target(target: &str) -> Result<(), Error> {
+    if target.is_empty() || target.chars().any(char::is_control) {
+        return Err(Error::InvalidTarget(String::from(target)));
+    }
+    Ok(())
+}
+
+fn parse_channel(s: Option<&str>) -> Result<String, Error> {
+    let channel = unescape(s.ok_or(Error::MissingField("channel"))?)?;
+    validate_channel(&channel)?;
+    Ok(channel)
+}
+
+fn parse_pattern(s: &str) -> Result<String, Error> {
+    let pattern = unescape(s)?;
+    validate_pattern(&pattern)?;
+    Ok(pattern)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SubscribeDatagram {
+    pub channel: String,
+}
+
+impl SubscribeDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        Ok(SubscribeDatagram {
+            channel: parse_pattern(s)?,
+        })
+    }
+
+    pub fn serialize(&self) -> String {
+        escape_last(&self.channel)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct UnsubscribeDatagram {
+    pub channel: String,
+}
+
+impl UnsubscribeDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        Ok(UnsubscribeDatagram {
+            channel: parse_pattern(s)?,
+        })
+    }
+
+    pub fn serialize(&self) -> String {
+        escape_last(&self.channel)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PublishDatagram {
+    pub channel: String,
+    pub message: String,
+    pub display_name: String,
+}
+
+impl PublishDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let fields = fields(s, 3);
+        let mut iter = fields.into_iter();
+        let channel = parse_channel(iter.next())?;
+        let display_name = unescape(iter.next().ok_or(Error::MissingField("display name"))?)?;
+        validate_display_name(&display_name)?;
+        let message = unescape(iter.next().ok_or(Error::MissingField("message"))?)?;
+        Ok(PublishDatagram {
+            channel,
+            message,
+            display_name,
+        })
+    }
+
+    /// Parses `CHANNEL|MESSAGE`, for senders whose display name is already known
+    pub fn parse_as<N: Into<String>>(s: &str, display_name: N) -> Result<Self, Error> {
+        let fields = fields(s, 2);
+        let mut iter = fields.into_iter();
+        let channel = parse_channel(iter.next())?;
+        let message = unescape(iter.next().ok_or(Error::MissingField("message"))?)?;
+        let display_name = display_name.into();
+        validate_display_name(&display_name)?;
+        Ok(PublishDatagram {
+            channel,
+            message,
+            display_name,
+        })
+    }
+
+    pub fn serialize(&self) -> String {
+        format!(
+            "{}|{}|{}",
+            escape(&self.channel),
+            escape(&self.display_name),
+            escape_last(&self.message)
+        )
+    }
+}
+
+/// A private message to whoever has registered the recipient's display name
+#[derive(Debug, PartialEq, Clone)]
+pub struct DirectDatagram {
+    pub recipient: String,
+    pub display_name: String,
+    pub message: String,
+}
+
+impl DirectDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let fields = fields(s, 3);
+        let mut iter = fields.into_iter();
+        let recipient = unescape(iter.next().unwrap_or(""))?;
+        validate_display_name(&recipient)?;
+        let display_name = unescape(iter.next().ok_or(Error::MissingField("display name"))?)?;
+        validate_display_name(&display_name)?;
+        let message = unescape(iter.next().ok_or(Error::MissingField("message"))?)?;
+        Ok(DirectDatagram {
+            recipient,
+            display_name,
+            message,
+        })
+    }
+
+    pub fn serialize(&self) -> String {
+        format!(
+            "{}|{}|{}",
+            escape(&self.recipient),
+            escape(&self.display_name),
+            escape_last(&self.message)
+        )
+    }
+}
+
+/// Claims a display name for the sender, so nobody else can publish as it
+#[derive(Debug, PartialEq, Clone)]
+pub struct NickDatagram {
+    pub display_name: String,
+}
+
+impl NickDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let display_name = unescape(s)?;
+        validate_display_name(&display_name)?;
+        Ok(NickDatagram { display_name })
+    }
+
+    pub fn serialize(&self) -> String {
+        escape_last(&self.display_name)
+    }
+}
+
+/// Asks which channels matching a pattern have anyone in them
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListDatagram {
+    pub pattern: String,
+}
+
+impl ListDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        Ok(ListDatagram {
+            pattern: parse_pattern(s)?,
+        })
+    }
+
+    pub fn serialize(&self) -> String {
+        escape_last(&self.pattern)
+    }
+}
+
+/// The answer to a LIST: each channel, and how many subscribers it has
+#[derive(Debug, PartialEq, Clone)]
+pub struct ChannelListDatagram {
+    pub pattern: String,
+    pub channels: Vec<(String, u64)>,
+}
+
+impl ChannelListDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let fields = fields(s, usize::MAX);
+        let mut iter = fields.into_iter();
+        let pattern = parse_pattern(iter.next().unwrap_or(""))?;
+        let mut channels = Vec::new();
+        while let Some(channel) = iter.next() {
+            let channel = parse_channel(Some(channel))?;
+            let count = iter.next().ok_or(Error::MissingField("subscriber count"))?;
+            channels.push((channel, parse_sequence(count)?));
+        }
+        Ok(ChannelListDatagram { pattern, channels })
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut s = escape(&self.pattern);
+        for (channel, count) in &self.channels {
+            s.push_str(&format!("|{}|{}", escape(channel), count));
+        }
+        s
+    }
+}
+
+/// Asks who is subscribed to a channel
+#[derive(Debug, PartialEq, Clone)]
+pub struct WhoDatagram {
+    pub channel: String,
+}
+
+impl WhoDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        Ok(WhoDatagram {
+            channel: parse_channel(Some(s))?,
+        })
+    }
+
+    pub fn serialize(&self) -> String {
+        escape_last(&self.channel)
+    }
+}
+
+/// The answer to a WHO: the nick of everyone subscribed to the channel, or
+/// their address if they haven't claimed one
+#[derive(Debug, PartialEq, Clone)]
+pub struct MemberListDatagram {
+    pub channel: String,
+    pub members: Vec<String>,
+}
+
+impl MemberListDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let fields = fields(s, usize::MAX);
+        let mut iter = fields.into_iter();
+        let channel = parse_channel(iter.next())?;
+        let members = iter
+            .map(|who| {
+                let who = unescape(who)?;
+                validate_target(&who)?;
+                Ok(who)
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(MemberListDatagram { channel, members })
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut s = escape(&self.channel);
+        for who in &self.members {
+            s.push('|');
+            s.push_str(&escape(who));
+        }
+        s
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PresenceEvent {
+    Joined,
+    Left,
+}
+
+impl PresenceEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PresenceEvent::Joined => "joined",
+            PresenceEvent::Left => "left",
+        }
+    }
+}
+
+/// Tells a channel's subscribers that someone joined or left it
+#[derive(Debug, PartialEq, Clone)]
+pub struct PresenceDatagram {
+    pub channel: String,
+    pub event: PresenceEvent,
+    // A nick, or an address if they haven't claimed one
+    pub who: String,
+}
+
+impl PresenceDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let fields = fields(s, 3);
+        let mut iter = fields.into_iter();
+        let channel = parse_channel(iter.next())?;
+        let event = match iter.next().ok_or(Error::MissingField("presence event"))? {
+            "joined" => PresenceEvent::Joined,
+            "left" => PresenceEvent::Left,
+            event => return Err(Error::InvalidEvent(String::from(event))),
+        };
+        let who = unescape(iter.next().ok_or(Error::MissingField("who"))?)?;
+        validate_target(&who)?;
+        Ok(PresenceDatagram {
+            channel,
+            event,
+            who,
+        })
+    }
+
+    pub fn serialize(&self) -> String {
+        format!(
+            "{}|{}|{}",
+            escape(&self.channel),
+            self.event.as_str(),
+            escape_last(&self.who)
+        )
+    }
+}
+
+/// Sent between linked servers to say which patterns a server's own
+/// subscribers have, and passed on to the rest of the federation. Each
+/// server numbers its adverts, so stale ones can be ignored.
+#[derive(Debug, PartialEq, Clone)]
+pub struct InterestDatagram {
+    pub node: u64,
+    pub version: u64,
+    pub patterns: Vec<String>,
+}
+
+impl InterestDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let fields = fields(s, usize::MAX);
+        let mut iter = fields.into_iter();
+        let node = parse_sequence(iter.next().unwrap_or(""))?;
+        let version = parse_sequence(iter.next().ok_or(Error::MissingField("version"))?)?;
+        let patterns = iter.map(parse_pattern).collect::<Result<_, Error>>()?;
+        Ok(InterestDatagram {
+            node,
+            version,
+            patterns,
+        })
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut s = format!("{}|{}", self.node, self.version);
+        for pattern in &self.patterns {
+            s.push('|');
+            s.push_str(&escape(pattern));
+        }
+        s
+    }
+}
+
+/// A publish passed from one server to another, identified by the server it
+/// was first published on and that server's count of messages, so it's only
+/// handled once however many ways it arrives
+#[derive(Debug, PartialEq, Clone)]
+pub struct ForwardDatagram {
+    pub origin: u64,
+    pub id: u64,
+    pub publish: PublishDatagram,
+}
+
+impl ForwardDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let fields = fields(s, 3);
+        let mut iter = fields.into_iter();
+        let origin = parse_sequence(iter.next().unwrap_or(""))?;
+        let id = parse_sequence(iter.next().ok_or(Error::MissingField("message id"))?)?;
+        let publish = PublishDatagram::parse(iter.next().ok_or(Error::MissingField("publish"))?)?;
+        Ok(ForwardDatagram {
+            origin,
+            id,
+            publish,
+        })
+    }
+
+    pub fn serialize(&self) -> String {
+        format!("{}|{}|{}", self.origin, self.id, self.publish.serialize())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ModerateAction {
+    // Unsubscribes them from the channel
+    Kick,
+    // Kicks them, and stops them subscribing or publishing again
+    Ban,
+    Unban,
+}
+
+impl ModerateAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ModerateAction::Kick => "kick",
+            ModerateAction::Ban => "ban",
+            ModerateAction::Unban => "unban",
+        }
+    }
+}
+
+/// Asks the server to kick or ban whoever matches the target on a channel,
+/// which only the channel's moderators may do
+#[derive(Debug, PartialEq, Clone)]
+pub struct ModerateDatagram {
+    pub channel: String,
+    pub action: ModerateAction,
+    pub target: String,
+}
+
+impl ModerateDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let fields = fields(s, 3);
+        let mut iter = fields.into_iter();
+        let channel = parse_channel(iter.next())?;
+        let action = match iter
+            .next()
+            .ok_or(Error::MissingField("moderation action"))?
+        {
+            "kick" => ModerateAction::Kick,
+            "ban" => ModerateAction::Ban,
+            "unban" => ModerateAction::Unban,
+            action => return Err(Error::InvalidAction(String::from(action))),
+        };
+        let target = unescape(iter.next().ok_or(Error::MissingField("target"))?)?;
+        validate_target(&target)?;
+        Ok(ModerateDatagram {
+            channel,
+            action,
+            target,
+        })
+    }
+
+    pub fn serialize(&self) -> String {
+        format!(
+            "{}|{}|{}",
+            escape(&self.channel),
+            self.action.as_str(),
+            escape_last(&self.target)
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ErrorCode {
+    Other,
+    // The datagram couldn't be parsed, or broke the protocol's rules
+    Parse,
+    Utf8,
+    Oversize,
+    UnknownChannel,
+    // Someone else has registered the nick
+    NickInUse,
+    // Nobody has registered the nick a direct message was sent to
+    UnknownNick,
+    // The sender isn't allowed to do that
+    Forbidden,
+    // The datagram wasn't signed with a key the server knows, or was replayed
+    Unauthorized,
+    // The sender, or the channel, is over its rate limit
+    RateLimited,
+    // Only ever reported by clients, for messages on an encrypted channel
+    // that were tampered with or use a different passphrase
+    Undecryptable,
+}
+
+impl ErrorCode {
+    pub const ALL: &'static [ErrorCode] = &[
+        ErrorCode::Other,
+        ErrorCode::Parse,
+        ErrorCode::Utf8,
+        ErrorCode::Oversize,
+        ErrorCode::UnknownChannel,
+        ErrorCode::NickInUse,
+        ErrorCode::UnknownNick,
+        ErrorCode::Forbidden,
+        ErrorCode::Unauthorized,
+        ErrorCode::RateLimited,
+        ErrorCode::Undecryptable,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::Other => "other",
+            ErrorCode::Parse => "parse",
+            ErrorCode::Utf8 => "utf8",
+            ErrorCode::Oversize => "oversize",
+            ErrorCode::UnknownChannel => "unknown_channel",
+            ErrorCode::NickInUse => "nick_in_use",
+            ErrorCode::UnknownNick => "unknown_nick",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::Undecryptable => "undecryptable",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        ErrorCode::ALL
+            .iter()
+            .cloned()
+            .find(|code| code.as_str() == s)
+    }
+}
+
+impl<'a> From<&'a Error> for ErrorCode {
+    fn from(error: &'a Error) -> Self {
+        match error {
+            Error::InvalidUtf8 => ErrorCode::Utf8,
+            Error::TooLarge(_) => ErrorCode::Oversize,
+            _ => ErrorCode::Parse,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ErrorDatagram {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        // Errors without a code are from before codes existed
+        match fields(s, 2).as_slice() {
+            [code, message] if ErrorCode::parse(code).is_some() => Ok(ErrorDatagram {
+                code: ErrorCode::parse(code).unwrap(),
+                message: unescape(message)?,
+            }),
+            _ => Ok(ErrorDatagram {
+                code: ErrorCode::Other,
+                message: unescape(s)?,
+            }),
+        }
+    }
+
+    pub fn serialize(&self) -> String {
+        match self.code {
+            // Escape any pipes so the message can't be mistaken for a code
+            ErrorCode::Other => escape(&self.message),
+            code => format!("{}|{}", code.as_str(), escape_last(&self.message)),
         }
     }
+}
 
-    pub fn subscribe<C: Into<String>>(channel: C) -> Self {
-        Datagram::Subscribe(SubscribeDatagram {
-            channel: channel.into(),
-        })
+impl fmt::Display for ErrorDatagram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code.as_str())
     }
+}
 
-    #[cfg(test)]
-    pub fn unsubscribe<C: Into<String>>(channel: C) -> Self {
-        Datagram::Unsubscribe(UnsubscribeDatagram {
-            channel: channel.into(),
-        })
-    }
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReliableDatagram {
+    pub sequence: u64,
+    pub datagram: Box<Datagram>,
+}
 
-    #[cfg(test)]
-    pub fn publish<C, N, M>(channel: C, display_name: N, message: M) -> Self
-    where
-        C: Into<String>,
-        N: Into<String>,
-        M: Into<String>,
-    {
-        Datagram::Publish(PublishDatagram {
-            channel: channel.into(),
-            display_name: display_name.into(),
-            message: message.into(),
+impl ReliableDatagram {
+    // `wrappers` includes this datagram
+    fn parse_within(s: &str, wrappers: Wrappers) -> Result<Self, Error> {
+        // The wrapped datagram does its own unescaping
+        let mut iter = s.splitn(2, '|');
+        let sequence = parse_sequence(iter.next().unwrap_or(""))?;
+        let datagram = iter.next().ok_or(Error::MissingField("datagram"))?;
+        Ok(ReliableDatagram {
+            sequence,
+            datagram: Box::new(Datagram::parse_within(datagram, wrappers)?),
         })
     }
 
-    #[cfg(test)]
-    pub fn error<M: Into<String>>(message: M) -> Self {
-        Datagram::Error(message.into())
+    pub fn serialize(&self) -> String {
+        format!("{}|{}", self.sequence, self.datagram.serialize())
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct SubscribeDatagram {
-    pub channel: String,
+/// Wraps a datagram with an HMAC that proves which key signed it, and a
+/// timestamp and nonce so it can't be replayed (see `auth`)
+#[derive(Debug, PartialEq, Clone)]
+pub struct SignedDatagram {
+    pub key_id: String,
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub nonce: u64,
+    pub mac: Vec<u8>,
+    pub datagram: Box<Datagram>,
 }
 
-impl SubscribeDatagram {
-    pub fn parse(s: &str) -> Self {
-        SubscribeDatagram {
-            channel: String::from(s),
-        }
+impl SignedDatagram {
+    // `wrappers` includes this datagram
+    fn parse_within(s: &str, wrappers: Wrappers) -> Result<Self, Error> {
+        // As with reliable datagrams, the wrapped datagram does its own unescaping
+        let fields = fields(s, 5);
+        let mut iter = fields.into_iter();
+        let key_id = unescape(iter.next().unwrap_or(""))?;
+        let timestamp = parse_sequence(iter.next().ok_or(Error::MissingField("timestamp"))?)?;
+        let nonce = parse_sequence(iter.next().ok_or(Error::MissingField("nonce"))?)?;
+        let mac = iter.next().ok_or(Error::MissingField("mac"))?;
+        let mac =
+            hex::decode(mac).map_err(|_| Error::Malformed(format!("Invalid MAC: {}", mac)))?;
+        let datagram = iter.next().ok_or(Error::MissingField("datagram"))?;
+        Ok(SignedDatagram {
+            key_id,
+            timestamp,
+            nonce,
+            mac,
+            datagram: Box::new(Datagram::parse_within(datagram, wrappers)?),
+        })
     }
 
     pub fn serialize(&self) -> String {
-        self.channel.to_owned()
+        format!(
+            "{}|{}|{}|{}|{}",
+            escape(&self.key_id),
+            self.timestamp,
+            self.nonce,
+            hex::encode(&self.mac),
+            self.datagram.serialize()
+        )
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct UnsubscribeDatagram {
+#[derive(Debug, PartialEq, Clone)]
+pub struct HistoryDatagram {
     pub channel: String,
+    // How many of the channel's latest messages to replay
+    pub count: u64,
 }
 
-impl UnsubscribeDatagram {
-    pub fn parse(s: &str) -> Self {
-        UnsubscribeDatagram {
-            channel: String::from(s),
-        }
+impl HistoryDatagram {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let fields = fields(s, 2);
+        let mut iter = fields.into_iter();
+        let channel = parse_channel(iter.next())?;
+        let count = parse_sequence(iter.next().ok_or(Error::MissingField("history count"))?)?;
+        Ok(HistoryDatagram { channel, count })
     }
 
     pub fn serialize(&self) -> String {
-        self.channel.to_owned()
+        format!("{}|{}", escape(&self.channel), self.count)
     }
 }
 
+/// One piece of an encoded datagram that was too big to send in one go
 #[derive(Debug, PartialEq, Clone)]
-pub struct PublishDatagram {
-    pub channel: String,
-    pub message: String,
-    pub display_name: String,
+pub struct FragmentDatagram {
+    pub id: u64,
+    pub index: u32,
+    pub count: u32,
+    pub payload: Vec<u8>,
 }
 
-impl PublishDatagram {
+impl FragmentDatagram {
     pub fn parse(s: &str) -> Result<Self, Error> {
-        let mut iter = s.splitn(3, '|');
-        match (iter.next(), iter.next(), iter.next()) {
-            (Some(channel), Some(display_name), Some(message)) => Ok(PublishDatagram {
-                channel: String::from(channel),
-                message: String::from(message),
-                display_name: String::from(display_name),
-            }),
-            _ => Err(Error::BadDatagram(format!(
-                "Could not parse PublishDatagram: {}",
-                s
-            ))),
+        // The payload is a slice of a text datagram, so it's left as it is
+        let mut iter = s.splitn(4, '|');
+        let id = parse_sequence(iter.next().unwrap_or(""))?;
+        let index = parse_index(iter.next().ok_or(Error::MissingField("fragment index"))?)?;
+        let count = parse_index(iter.next().ok_or(Error::MissingField("fragment count"))?)?;
+        let payload = iter.next().ok_or(Error::MissingField("fragment payload"))?;
+        let fragment = FragmentDatagram {
+            id,
+            index,
+            count,
+            payload: payload.as_bytes().to_vec(),
+        };
+        fragment.validate()?;
+        Ok(fragment)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.index >= self.count {
+            return Err(Error::Malformed(format!(
+                "Fragment {} of {}",
+                self.index, self.count
+            )));
         }
+        Ok(())
     }
 
     pub fn serialize(&self) -> String {
-        format!("{}|{}|{}", self.channel, self.display_name, self.message)
+        format!(
+            "{}|{}|{}|{}",
+            self.id,
+            self.index,
+            self.count,
+            String::from_utf8_lossy(&self.payload)
+        )
     }
+}
 
-    pub fn copy(&self) -> Self {
-        Self::parse(&self.serialize()).expect("Parsing error in copy...")
-    }
+fn parse_index(s: &str) -> Result<u32, Error> {
+    s.parse().map_err(|_| Error::InvalidNumber(String::from(s)))
+}
+
+fn parse_sequence(s: &str) -> Result<u64, Error> {
+    s.parse().map_err(|_| Error::InvalidNumber(String::from(s)))
 }
 
 #[cfg(test)]
 mod protocol_tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_subscribe_parse() {
@@ -205,7 +1186,7 @@ mod protocol_tests {
     #[test]
     fn test_subscribe_datagram_parsing() {
         let message = "some_fake_channel";
-        let req = SubscribeDatagram::parse(message);
+        let req = SubscribeDatagram::parse(message).unwrap();
         assert_eq!(
             req,
             SubscribeDatagram {
@@ -217,7 +1198,7 @@ mod protocol_tests {
     #[test]
     fn test_unsubscribe_datagram_parsing() {
         let message = "some_fake_channel";
-        let req = UnsubscribeDatagram::parse(message);
+        let req = UnsubscribeDatagram::parse(message).unwrap();
         assert_eq!(
             req,
             UnsubscribeDatagram {
@@ -239,4 +1220,515 @@ mod protocol_tests {
             }
         );
     }
+
+    #[test]
+    fn test_publish_datagram_parsing_as() {
+        let req = PublishDatagram::parse_as("rust_club|hello | world", "m|e").unwrap();
+        assert_eq!(
+            Datagram::Publish(req),
+            Datagram::publish("rust_club", "m|e", "hello | world")
+        );
+        assert!(PublishDatagram::parse_as("rust_club", "me").is_err());
+        assert!(PublishDatagram::parse_as("rust_club|hi", "").is_err());
+    }
+
+    #[test]
+    fn test_reliable_parse() {
+        let message = "R|42|P|rust_club|me|hello world! ||||| yo";
+        let req = Datagram::parse(message).unwrap();
+        assert_eq!(
+            req,
+            Datagram::reliable(
+                42,
+                Datagram::publish("rust_club", "me", "hello world! ||||| yo")
+            )
+        );
+    }
+
+    #[test]
+    fn test_reliable_serialize() {
+        let req = Datagram::reliable(7, Datagram::subscribe("rust_club"));
+        assert_eq!(req.serialize(), "R|7|S|rust_club");
+    }
+
+    #[test]
+    fn test_deeply_nested() {
+        let nested = format!("{}S|rust_club", "R|1|Z|a|1|2|00|".repeat(100_000));
+        assert_eq!(Datagram::parse(&nested), Err(Error::Nested("reliable")));
+        assert!(Datagram::parse("R|1|Z|a|1|2|00|S|rust_club").is_ok());
+        assert!(Datagram::parse("Z|a|1|2|00|R|1|S|rust_club").is_ok());
+    }
+
+    #[test]
+    fn test_reliable_bad_sequence() {
+        assert!(Datagram::parse("R|nope|S|rust_club").is_err());
+        assert!(Datagram::parse("R|7").is_err());
+    }
+
+    #[test]
+    fn test_ack_parse() {
+        let req = Datagram::parse("A|42").unwrap();
+        assert_eq!(req, Datagram::Ack(42));
+        assert!(Datagram::parse("A|-1").is_err());
+    }
+
+    #[test]
+    fn test_ack_serialize() {
+        assert_eq!(Datagram::Ack(42).serialize(), "A|42");
+    }
+
+    #[test]
+    fn test_ping_pong_parse() {
+        assert_eq!(Datagram::parse("I|3").unwrap(), Datagram::Ping(3));
+        assert_eq!(Datagram::parse("O|3").unwrap(), Datagram::Pong(3));
+        assert!(Datagram::parse("I|").is_err());
+    }
+
+    #[test]
+    fn test_ping_pong_serialize() {
+        assert_eq!(Datagram::Ping(3).serialize(), "I|3");
+        assert_eq!(Datagram::Pong(3).serialize(), "O|3");
+    }
+
+    #[test]
+    fn test_goodbye() {
+        assert_eq!(
+            Datagram::parse("Q|Restarting|soon").unwrap(),
+            Datagram::goodbye("Restarting|soon")
+        );
+        assert_eq!(Datagram::parse("Q|").unwrap(), Datagram::goodbye(""));
+        assert!(Datagram::parse("Q").is_err());
+        assert_eq!(Datagram::goodbye("Bye").serialize(), "Q|Bye");
+    }
+
+    #[test]
+    fn test_history_parse() {
+        assert_eq!(
+            Datagram::parse("H|rust_club|10").unwrap(),
+            Datagram::history("rust_club", 10)
+        );
+        assert!(Datagram::parse("H|rust_club|last|10").is_err());
+        assert!(Datagram::parse("H|rust_club|ten").is_err());
+        assert!(Datagram::parse("H|rust_club").is_err());
+    }
+
+    #[test]
+    fn test_history_serialize() {
+        let req = Datagram::history("rust_club", 10);
+        assert_eq!(req.serialize(), "H|rust_club|10");
+    }
+
+    #[test]
+    fn test_nick_parse() {
+        assert_eq!(
+            Datagram::parse("N|Ferris").unwrap(),
+            Datagram::nick("Ferris")
+        );
+        assert_eq!(Datagram::parse("N|a|b").unwrap(), Datagram::nick("a|b"));
+        assert!(Datagram::parse("N|").is_err());
+        assert!(Datagram::parse("N|a\\nb").is_err());
+    }
+
+    #[test]
+    fn test_nick_serialize() {
+        assert_eq!(Datagram::nick("Ferris").serialize(), "N|Ferris");
+    }
+
+    #[test]
+    fn test_direct_parse() {
+        assert_eq!(
+            Datagram::parse("D|ferris|gopher|psst | hi").unwrap(),
+            Datagram::direct("ferris", "gopher", "psst | hi")
+        );
+        assert_eq!(
+            Datagram::parse("D|a\\|b|me|hi").unwrap(),
+            Datagram::direct("a|b", "me", "hi")
+        );
+        assert!(Datagram::parse("D||me|hi").is_err());
+        assert_eq!(
+            Datagram::parse("D|ferris|me"),
+            Err(Error::MissingField("message"))
+        );
+    }
+
+    #[test]
+    fn test_direct_serialize() {
+        let req = Datagram::direct("a|b", "me", "hi|there");
+        assert_eq!(req.serialize(), "D|a\\|b|me|hi|there");
+    }
+
+    #[test]
+    fn test_list_and_who() {
+        assert_eq!(
+            Datagram::parse("L|rust.#").unwrap(),
+            Datagram::list("rust.#")
+        );
+        assert_eq!(
+            Datagram::parse("W|rust_club").unwrap(),
+            Datagram::who("rust_club")
+        );
+        assert!(Datagram::parse("W|rust.*").is_err());
+
+        let channels = Datagram::ChannelList(ChannelListDatagram {
+            pattern: String::from("rust.#"),
+            channels: vec![(String::from("rust.club"), 3), (String::from("rust"), 1)],
+        });
+        assert_eq!(channels.serialize(), "C|rust.#|rust.club|3|rust|1");
+        assert_eq!(
+            Datagram::parse("C|rust.#|rust.club|3|rust|1").unwrap(),
+            channels
+        );
+        assert_eq!(
+            Datagram::parse("C|#").unwrap(),
+            Datagram::ChannelList(ChannelListDatagram {
+                pattern: String::from("#"),
+                channels: vec![],
+            })
+        );
+        assert_eq!(
+            Datagram::parse("C|#|rust.club"),
+            Err(Error::MissingField("subscriber count"))
+        );
+
+        let members = Datagram::MemberList(MemberListDatagram {
+            channel: String::from("rust_club"),
+            members: vec![String::from("a|b"), String::from("127.0.0.1:1234")],
+        });
+        assert_eq!(members.serialize(), "G|rust_club|a\\|b|127.0.0.1:1234");
+        assert_eq!(Datagram::parse(&members.serialize()).unwrap(), members);
+        assert!(Datagram::parse("G|rust_club||b").is_err());
+    }
+
+    #[test]
+    fn test_federation_parse() {
+        let interest = Datagram::Interest(InterestDatagram {
+            node: 42,
+            version: 7,
+            patterns: vec![String::from("rust.#"), String::from("go_club")],
+        });
+        assert_eq!(interest.serialize(), "Y|42|7|rust.#|go_club");
+        assert_eq!(Datagram::parse("Y|42|7|rust.#|go_club").unwrap(), interest);
+        assert_eq!(
+            Datagram::parse("Y|42|8").unwrap(),
+            Datagram::Interest(InterestDatagram {
+                node: 42,
+                version: 8,
+                patterns: vec![],
+            })
+        );
+        assert!(Datagram::parse("Y|42").is_err());
+        assert!(Datagram::parse("Y|42|8|not a pattern").is_err());
+
+        let forward = Datagram::parse("B|42|3|rust_club|me|a|b").unwrap();
+        assert_eq!(
+            forward,
+            Datagram::Forward(ForwardDatagram {
+                origin: 42,
+                id: 3,
+                publish: PublishDatagram {
+                    channel: String::from("rust_club"),
+                    display_name: String::from("me"),
+                    message: String::from("a|b"),
+                },
+            })
+        );
+        assert_eq!(forward.serialize(), "B|42|3|rust_club|me|a|b");
+        assert!(Datagram::parse("B|42|3|rust_club|me").is_err());
+    }
+
+    #[test]
+    fn test_presence_parse() {
+        let req = Datagram::parse("J|rust_club|joined|ferris").unwrap();
+        assert_eq!(
+            req,
+            Datagram::presence("rust_club", PresenceEvent::Joined, "ferris")
+        );
+        assert_eq!(req.serialize(), "J|rust_club|joined|ferris");
+        assert_eq!(
+            Datagram::parse("J|rust_club|left|a|b").unwrap(),
+            Datagram::presence("rust_club", PresenceEvent::Left, "a|b")
+        );
+        assert!(Datagram::parse("J|rust_club|waved|ferris").is_err());
+    }
+
+    #[test]
+    fn test_moderate_parse() {
+        assert_eq!(
+            Datagram::parse("M|rust_club|ban|10.0.0.1").unwrap(),
+            Datagram::moderate("rust_club", ModerateAction::Ban, "10.0.0.1")
+        );
+        assert_eq!(
+            Datagram::parse("M|rust_club|kick|a|b").unwrap(),
+            Datagram::moderate("rust_club", ModerateAction::Kick, "a|b")
+        );
+        assert!(Datagram::parse("M|rust_club|mute|ferris").is_err());
+        assert!(Datagram::parse("M|rust_club|kick|").is_err());
+        assert!(Datagram::parse("M|rust_club|kick").is_err());
+    }
+
+    #[test]
+    fn test_moderate_serialize() {
+        let req = Datagram::moderate("rust_club", ModerateAction::Unban, "ferris");
+        assert_eq!(req.serialize(), "M|rust_club|unban|ferris");
+    }
+
+    #[test]
+    fn test_signed_parse() {
+        let req = Datagram::parse("Z|a\\|b|1560000000|7|00ff|P|rust_club|me|hi").unwrap();
+        assert_eq!(
+            req,
+            Datagram::Signed(SignedDatagram {
+                key_id: String::from("a|b"),
+                timestamp: 1_560_000_000,
+                nonce: 7,
+                mac: vec![0x00, 0xFF],
+                datagram: Box::new(Datagram::publish("rust_club", "me", "hi")),
+            })
+        );
+        assert_eq!(
+            req.serialize(),
+            "Z|a\\|b|1560000000|7|00ff|P|rust_club|me|hi"
+        );
+        assert!(Datagram::parse("Z|a|1560000000|7|nothex|S|rust_club").is_err());
+        assert!(Datagram::parse("Z|a|1560000000|7|00ff").is_err());
+    }
+
+    #[test]
+    fn test_coded_error_parse() {
+        let req = Datagram::parse("E|unknown_channel|No subscribers on rust_club").unwrap();
+        assert_eq!(
+            req,
+            Datagram::rejection(ErrorCode::UnknownChannel, "No subscribers on rust_club")
+        );
+        let req = Datagram::parse("E|not_a_code|some|error").unwrap();
+        assert_eq!(req, Datagram::error("not_a_code|some|error"));
+    }
+
+    #[test]
+    fn test_coded_error_serialize() {
+        let req = Datagram::rejection(ErrorCode::Parse, "Invalid channel: a|b");
+        assert_eq!(req.serialize(), "E|parse|Invalid channel: a|b");
+        let req = Datagram::error("parse|not really");
+        assert_eq!(req.serialize(), "E|parse\\|not really");
+        assert_eq!(Datagram::parse(&req.serialize()).unwrap(), req);
+    }
+
+    #[test]
+    fn test_fragment_parse() {
+        let req = Datagram::parse("F|9|1|3|P|rust|club\\").unwrap();
+        assert_eq!(
+            req,
+            Datagram::Fragment(FragmentDatagram {
+                id: 9,
+                index: 1,
+                count: 3,
+                payload: b"P|rust|club\\".to_vec(),
+            })
+        );
+        assert_eq!(req.serialize(), "F|9|1|3|P|rust|club\\");
+        assert!(Datagram::parse("F|9|3|3|P").is_err());
+        assert!(Datagram::parse("F|9|1|3").is_err());
+    }
+
+    #[test]
+    fn test_decode_detects_encoding() {
+        let datagram = Datagram::publish("rust_club", "m|e", "hello");
+        let text = datagram.encode(Encoding::Text);
+        let binary = datagram.encode(Encoding::Binary);
+        assert_eq!(
+            Datagram::decode(&binary).unwrap(),
+            (datagram.clone(), Encoding::Binary)
+        );
+        assert_eq!(Datagram::decode(&text).unwrap(), (datagram, Encoding::Text));
+        assert_eq!(Datagram::decode(&[0xC3, 0x28]), Err(Error::InvalidUtf8));
+    }
+
+    #[test]
+    fn test_decode_validates_binary() {
+        let binary = Datagram::subscribe("rust club").encode(Encoding::Binary);
+        assert_eq!(
+            Datagram::decode(&binary),
+            Err(Error::InvalidChannel(String::from("rust club")))
+        );
+    }
+
+    #[test]
+    fn test_publish_escaping() {
+        let req = Datagram::publish("rust_club", "m|e\\", "hello\nworld|");
+        assert_eq!(req.serialize(), "P|rust_club|m\\|e\\\\|hello\\nworld|");
+        assert_eq!(Datagram::parse(&req.serialize()).unwrap(), req);
+    }
+
+    #[test]
+    fn test_escaped_pipes_in_message() {
+        let req = Datagram::parse("P|rust_club|me|a\\|b|c").unwrap();
+        assert_eq!(req, Datagram::publish("rust_club", "me", "a|b|c"));
+    }
+
+    #[test]
+    fn test_invalid_escapes() {
+        assert_eq!(
+            Datagram::parse("P|rust_club|me|C:\\path"),
+            Err(Error::InvalidEscape(String::from("\\p")))
+        );
+        assert!(Datagram::parse("P|rust_club|me|hello\nworld").is_err());
+        assert!(Datagram::parse("E|trailing\\").is_err());
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        assert_eq!(
+            Datagram::parse("X|rust_club"),
+            Err(Error::UnknownOpcode(String::from("X")))
+        );
+        assert_eq!(
+            Datagram::parse(""),
+            Err(Error::UnknownOpcode(String::new()))
+        );
+    }
+
+    #[test]
+    fn test_invalid_fields() {
+        assert_eq!(
+            Datagram::parse("J|rust_club|arrived|ferris"),
+            Err(Error::InvalidEvent(String::from("arrived")))
+        );
+        assert_eq!(
+            Datagram::parse("M|rust_club|mute|ferris"),
+            Err(Error::InvalidAction(String::from("mute")))
+        );
+    }
+
+    #[test]
+    fn test_missing_fields() {
+        assert_eq!(Datagram::parse("S"), Err(Error::MissingField("channel")));
+        assert_eq!(Datagram::parse("A"), Err(Error::MissingField("sequence")));
+        assert_eq!(Datagram::parse("Z"), Err(Error::MissingField("key id")));
+        assert_eq!(
+            Datagram::parse("P|rust_club"),
+            Err(Error::MissingField("display name"))
+        );
+        assert_eq!(
+            Datagram::parse("P|rust_club|me"),
+            Err(Error::MissingField("message"))
+        );
+        assert_eq!(Datagram::parse("R|1"), Err(Error::MissingField("datagram")));
+        assert_eq!(
+            Datagram::parse("R|1|R|2|S|rust_club"),
+            Err(Error::Nested("reliable"))
+        );
+        assert_eq!(
+            Datagram::parse("Z|a|1|2|00|R|1|Z|a|1|2|00|S|rust_club"),
+            Err(Error::Nested("signed"))
+        );
+    }
+
+    #[test]
+    fn test_invalid_channels() {
+        let too_long = "a".repeat(MAX_CHANNEL_LENGTH + 1);
+        for channel in &[
+            "",
+            "rust club",
+            "rust\\|club",
+            "rust\\nclub",
+            "ŕust",
+            &too_long,
+        ] {
+            assert!(Datagram::parse(&format!("S|{}", channel)).is_err());
+            assert!(Datagram::parse(&format!("U|{}", channel)).is_err());
+            assert!(Datagram::parse(&format!("P|{}|me|hi", channel)).is_err());
+            assert!(Datagram::parse(&format!("H|{}|1", channel)).is_err());
+        }
+        assert!(Datagram::parse("S|rust-club.2019_v2").is_ok());
+        assert!(Datagram::parse(&format!("S|{}", "a".repeat(MAX_CHANNEL_LENGTH))).is_ok());
+    }
+
+    #[test]
+    fn test_patterns() {
+        for pattern in &["rust.*", "rust.#", "*.club", "#", "*", "rust.*.#"] {
+            assert!(Datagram::parse(&format!("S|{}", pattern)).is_ok());
+            assert!(Datagram::parse(&format!("U|{}", pattern)).is_ok());
+            assert!(Datagram::parse(&format!("P|{}|me|hi", pattern)).is_err());
+            assert!(is_pattern(pattern));
+        }
+        assert!(!is_pattern("rust.club"));
+        assert_eq!(
+            Datagram::parse("S|rust.#.club"),
+            Err(Error::InvalidPattern(String::from("rust.#.club")))
+        );
+        assert!(Datagram::parse("S|rust*").is_err());
+        assert!(Datagram::parse("S|rust club.*").is_err());
+
+        assert!(matches_pattern("rust.*", "rust.club"));
+        assert!(!matches_pattern("rust.*", "rust"));
+        assert!(!matches_pattern("rust.*", "rust.club.meetup"));
+        assert!(matches_pattern("rust.#", "rust"));
+        assert!(matches_pattern("rust.#", "rust.club.meetup"));
+        assert!(!matches_pattern("rust.#", "rusty"));
+        assert!(matches_pattern("rust.club", "rust.club"));
+        assert!(!matches_pattern("rust.club", "rust.club.meetup"));
+    }
+
+    #[test]
+    fn test_invalid_display_names() {
+        assert_eq!(
+            Datagram::parse("P|rust_club||hi"),
+            Err(Error::InvalidDisplayName(String::new()))
+        );
+        assert!(Datagram::parse("P|rust_club|a\\nb|hi").is_err());
+        let too_long = "a".repeat(MAX_DISPLAY_NAME_LENGTH + 1);
+        assert!(Datagram::parse(&format!("P|rust_club|{}|hi", too_long)).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn test_text_round_trip(
+            channel in "[A-Za-z0-9_.-]{1,64}",
+            display_name in "\\PC{1,32}",
+            message in any::<String>(),
+        ) {
+            let datagrams = vec![
+                Datagram::subscribe(channel.as_str()),
+                Datagram::unsubscribe(channel.as_str()),
+                Datagram::publish(channel.as_str(), display_name.as_str(), message.as_str()),
+                Datagram::error(message.as_str()),
+                Datagram::goodbye(message.as_str()),
+                Datagram::direct(display_name.as_str(), display_name.as_str(), message.as_str()),
+                Datagram::rejection(ErrorCode::Oversize, message.as_str()),
+                Datagram::history(channel.as_str(), 7),
+                Datagram::nick(display_name.as_str()),
+                Datagram::list(channel.as_str()),
+                Datagram::who(channel.as_str()),
+                Datagram::presence(channel.as_str(), PresenceEvent::Left, display_name.as_str()),
+                Datagram::Interest(InterestDatagram {
+                    node: 1,
+                    version: 2,
+                    patterns: vec![channel.clone()],
+                }),
+                Datagram::Forward(ForwardDatagram {
+                    origin: 3,
+                    id: 4,
+                    publish: PublishDatagram {
+                        channel: channel.clone(),
+                        display_name: display_name.clone(),
+                        message: message.clone(),
+                    },
+                }),
+                Datagram::ChannelList(ChannelListDatagram {
+                    pattern: channel.clone(),
+                    channels: vec![(channel.clone(), 2)],
+                }),
+                Datagram::MemberList(MemberListDatagram {
+                    channel: channel.clone(),
+                    members: vec![display_name.clone(), String::from("127.0.0.1:1234")],
+                }),
+                Datagram::moderate(channel, ModerateAction::Kick, display_name),
+            ];
+            for datagram in datagrams {
+                let reliable = Datagram::reliable(1, datagram.clone());
+                prop_assert_eq!(Datagram::parse(&datagram.serialize()).unwrap(), datagram);
+                prop_assert_eq!(Datagram::parse(&reliable.serialize()).unwrap(), reliable);
+            }
+        }
+    }
 }
@@ -0,0 +1,537 @@
+use super::{
+    ChannelListDatagram, Datagram, DirectDatagram, Error, ErrorCode, ErrorDatagram,
+    ForwardDatagram, FragmentDatagram, HistoryDatagram, InterestDatagram, ListDatagram,
+    MemberListDatagram, ModerateAction, ModerateDatagram, NickDatagram, PresenceDatagram,
+    PresenceEvent, PublishDatagram, ReliableDatagram, SignedDatagram, SubscribeDatagram,
+    UnsubscribeDatagram, WhoDatagram, Wrappers,
+};
+use std::convert::TryInto;
+
+// 0xFE can never appear in UTF-8, so it can't be mistaken for the text format
+pub const MAGIC: u8 = 0xFE;
+pub const VERSION: u8 = 1;
+
+// Datagrams are encoded as MAGIC, VERSION, then the same opcode letter the
+// text format uses, followed by the fields for that opcode. Strings are a
+// u32 length followed by UTF-8 bytes, numbers are u64s, and lists are a u32
+// count followed by their items. Everything is big endian.
+pub fn encode(datagram: &Datagram) -> Vec<u8> {
+    let mut buf = vec![MAGIC, VERSION];
+    encode_datagram(datagram, &mut buf);
+    buf
+}
+
+pub fn decode(buf: &[u8]) -> Result<Datagram, Error> {
+    let mut reader = Reader { buf };
+    match (reader.u8()?, reader.u8()?) {
+        (MAGIC, VERSION) => {}
+        (MAGIC, version) => return Err(bad(format!("Unsupported version: {}", version))),
+        _ => return Err(bad("Missing magic byte")),
+    };
+    let datagram = reader.datagram(Wrappers::default())?;
+    if !reader.buf.is_empty() {
+        return Err(bad("Trailing bytes after datagram"));
+    }
+    Ok(datagram)
+}
+
+fn bad<S: Into<String>>(message: S) -> Error {
+    Error::Malformed(message.into())
+}
+
+fn put_u64(n: u64, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&n.to_be_bytes());
+}
+
+fn put_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn put_string(s: &str, buf: &mut Vec<u8>) {
+    put_bytes(s.as_bytes(), buf);
+}
+
+fn put_count(n: usize, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(n as u32).to_be_bytes());
+}
+
+fn encode_datagram(datagram: &Datagram, buf: &mut Vec<u8>) {
+    match datagram {
+        Datagram::Subscribe(d) => {
+            buf.push(b'S');
+            put_string(&d.channel, buf);
+        }
+        Datagram::Unsubscribe(d) => {
+            buf.push(b'U');
+            put_string(&d.channel, buf);
+        }
+        Datagram::Publish(d) => {
+            buf.push(b'P');
+            put_string(&d.channel, buf);
+            put_string(&d.display_name, buf);
+            put_string(&d.message, buf);
+        }
+        Datagram::Error(e) => {
+            buf.push(b'E');
+            put_string(e.code.as_str(), buf);
+            put_string(&e.message, buf);
+        }
+        Datagram::Reliable(d) => {
+            buf.push(b'R');
+            put_u64(d.sequence, buf);
+            encode_datagram(&d.datagram, buf);
+        }
+        Datagram::Ack(sequence) => {
+            buf.push(b'A');
+            put_u64(*sequence, buf);
+        }
+        Datagram::Ping(nonce) => {
+            buf.push(b'I');
+            put_u64(*nonce, buf);
+        }
+        Datagram::Pong(nonce) => {
+            buf.push(b'O');
+            put_u64(*nonce, buf);
+        }
+        Datagram::History(d) => {
+            buf.push(b'H');
+            put_string(&d.channel, buf);
+            put_u64(d.count, buf);
+        }
+        Datagram::Fragment(d) => {
+            buf.push(b'F');
+            put_u64(d.id, buf);
+            buf.extend_from_slice(&d.index.to_be_bytes());
+            buf.extend_from_slice(&d.count.to_be_bytes());
+            put_bytes(&d.payload, buf);
+        }
+        Datagram::Nick(d) => {
+            buf.push(b'N');
+            put_string(&d.display_name, buf);
+        }
+        Datagram::Signed(d) => {
+            buf.push(b'Z');
+            put_string(&d.key_id, buf);
+            put_u64(d.timestamp, buf);
+            put_u64(d.nonce, buf);
+            put_bytes(&d.mac, buf);
+            encode_datagram(&d.datagram, buf);
+        }
+        Datagram::Moderate(d) => {
+            buf.push(b'M');
+            put_string(&d.channel, buf);
+            buf.push(match d.action {
+                ModerateAction::Kick => b'K',
+                ModerateAction::Ban => b'B',
+                ModerateAction::Unban => b'U',
+            });
+            put_string(&d.target, buf);
+        }
+        Datagram::Direct(d) => {
+            buf.push(b'D');
+            put_string(&d.recipient, buf);
+            put_string(&d.display_name, buf);
+            put_string(&d.message, buf);
+        }
+        Datagram::List(d) => {
+            buf.push(b'L');
+            put_string(&d.pattern, buf);
+        }
+        Datagram::ChannelList(d) => {
+            buf.push(b'C');
+            put_string(&d.pattern, buf);
+            put_count(d.channels.len(), buf);
+            for (channel, count) in &d.channels {
+                put_string(channel, buf);
+                put_u64(*count, buf);
+            }
+        }
+        Datagram::Who(d) => {
+            buf.push(b'W');
+            put_string(&d.channel, buf);
+        }
+        Datagram::MemberList(d) => {
+            buf.push(b'G');
+            put_string(&d.channel, buf);
+            put_count(d.members.len(), buf);
+            for who in &d.members {
+                put_string(who, buf);
+            }
+        }
+        Datagram::Presence(d) => {
+            buf.push(b'J');
+            put_string(&d.channel, buf);
+            buf.push(match d.event {
+                PresenceEvent::Joined => b'J',
+                PresenceEvent::Left => b'L',
+            });
+            put_string(&d.who, buf);
+        }
+        Datagram::Interest(d) => {
+            buf.push(b'Y');
+            put_u64(d.node, buf);
+            put_u64(d.version, buf);
+            put_count(d.patterns.len(), buf);
+            for pattern in &d.patterns {
+                put_string(pattern, buf);
+            }
+        }
+        Datagram::Forward(d) => {
+            buf.push(b'B');
+            put_u64(d.origin, buf);
+            put_u64(d.id, buf);
+            put_string(&d.publish.channel, buf);
+            put_string(&d.publish.display_name, buf);
+            put_string(&d.publish.message, buf);
+        }
+        Datagram::Goodbye(message) => {
+            buf.push(b'Q');
+            put_string(message, buf);
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() < n {
+            return Err(bad("Datagram is too short"));
+        }
+        let (taken, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let length = self.u32()?;
+        Ok(self.take(length as usize)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.bytes()?).map_err(|_| Error::InvalidUtf8)
+    }
+
+    fn datagram(&mut self, wrappers: Wrappers) -> Result<Datagram, Error> {
+        let datagram = match self.u8()? {
+            b'S' => Datagram::Subscribe(SubscribeDatagram {
+                channel: self.string()?,
+            }),
+            b'U' => Datagram::Unsubscribe(UnsubscribeDatagram {
+                channel: self.string()?,
+            }),
+            b'P' => Datagram::Publish(PublishDatagram {
+                channel: self.string()?,
+                display_name: self.string()?,
+                message: self.string()?,
+            }),
+            b'E' => {
+                let code = self.string()?;
+                Datagram::Error(ErrorDatagram {
+                    code: ErrorCode::parse(&code).unwrap_or(ErrorCode::Other),
+                    message: self.string()?,
+                })
+            }
+            b'R' => {
+                let wrappers = wrappers.reliable()?;
+                Datagram::Reliable(ReliableDatagram {
+                    sequence: self.u64()?,
+                    datagram: Box::new(self.datagram(wrappers)?),
+                })
+            }
+            b'A' => Datagram::Ack(self.u64()?),
+            b'I' => Datagram::Ping(self.u64()?),
+            b'O' => Datagram::Pong(self.u64()?),
+            b'H' => Datagram::History(HistoryDatagram {
+                channel: self.string()?,
+                count: self.u64()?,
+            }),
+            b'F' => {
+                let fragment = FragmentDatagram {
+                    id: self.u64()?,
+                    index: self.u32()?,
+                    count: self.u32()?,
+                    payload: self.bytes()?,
+                };
+                fragment.validate()?;
+                Datagram::Fragment(fragment)
+            }
+            b'N' => Datagram::Nick(NickDatagram {
+                display_name: self.string()?,
+            }),
+            b'Z' => {
+                let wrappers = wrappers.signed()?;
+                Datagram::Signed(SignedDatagram {
+                    key_id: self.string()?,
+                    timestamp: self.u64()?,
+                    nonce: self.u64()?,
+                    mac: self.bytes()?,
+                    datagram: Box::new(self.datagram(wrappers)?),
+                })
+            }
+            b'M' => {
+                let channel = self.string()?;
+                let action = match self.u8()? {
+                    b'K' => ModerateAction::Kick,
+                    b'B' => ModerateAction::Ban,
+                    b'U' => ModerateAction::Unban,
+                    action => return Err(Error::InvalidAction(format!("{:#04x}", action))),
+                };
+                Datagram::Moderate(ModerateDatagram {
+                    channel,
+                    action,
+                    target: self.string()?,
+                })
+            }
+            b'D' => Datagram::Direct(DirectDatagram {
+                recipient: self.string()?,
+                display_name: self.string()?,
+                message: self.string()?,
+            }),
+            b'L' => Datagram::List(ListDatagram {
+                pattern: self.string()?,
+            }),
+            b'C' => {
+                let pattern = self.string()?;
+                // Not preallocated, since the count hasn't been checked yet
+                let mut channels = Vec::new();
+                for _ in 0..self.u32()? {
+                    channels.push((self.string()?, self.u64()?));
+                }
+                Datagram::ChannelList(ChannelListDatagram { pattern, channels })
+            }
+            b'W' => Datagram::Who(WhoDatagram {
+                channel: self.string()?,
+            }),
+            b'G' => {
+                let channel = self.string()?;
+                let mut members = Vec::new();
+                for _ in 0..self.u32()? {
+                    members.push(self.string()?);
+                }
+                Datagram::MemberList(MemberListDatagram { channel, members })
+            }
+            b'J' => {
+                let channel = self.string()?;
+                let event = match self.u8()? {
+                    b'J' => PresenceEvent::Joined,
+                    b'L' => PresenceEvent::Left,
+                    event => return Err(Error::InvalidEvent(format!("{:#04x}", event))),
+                };
+                Datagram::Presence(PresenceDatagram {
+                    channel,
+                    event,
+                    who: self.string()?,
+                })
+            }
+            b'Y' => {
+                let node = self.u64()?;
+                let version = self.u64()?;
+                let mut patterns = Vec::new();
+                for _ in 0..self.u32()? {
+                    patterns.push(self.string()?);
+                }
+                Datagram::Interest(InterestDatagram {
+                    node,
+                    version,
+                    patterns,
+                })
+            }
+            b'B' => Datagram::Forward(ForwardDatagram {
+                origin: self.u64()?,
+                id: self.u64()?,
+                publish: PublishDatagram {
+                    channel: self.string()?,
+                    display_name: self.string()?,
+                    message: self.string()?,
+                },
+            }),
+            b'Q' => Datagram::Goodbye(self.string()?),
+            opcode => return Err(Error::UnknownOpcode(format!("{:#04x}", opcode))),
+        };
+        Ok(datagram)
+    }
+}
+
+#[cfg(test)]
+mod binary_tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn moderate_action() -> impl Strategy<Value = ModerateAction> {
+        prop_oneof![
+            Just(ModerateAction::Kick),
+            Just(ModerateAction::Ban),
+            Just(ModerateAction::Unban),
+        ]
+    }
+
+    fn presence_event() -> impl Strategy<Value = PresenceEvent> {
+        prop_oneof![Just(PresenceEvent::Joined), Just(PresenceEvent::Left)]
+    }
+
+    fn datagram() -> impl Strategy<Value = Datagram> {
+        let leaf = prop_oneof![
+            any::<String>().prop_map(Datagram::subscribe),
+            any::<String>().prop_map(Datagram::unsubscribe),
+            (any::<String>(), any::<String>(), any::<String>())
+                .prop_map(|(c, n, m)| Datagram::publish(c, n, m)),
+            any::<String>().prop_map(Datagram::error),
+            any::<String>().prop_map(Datagram::goodbye),
+            any::<String>().prop_map(|m| Datagram::rejection(ErrorCode::Oversize, m)),
+            any::<u64>().prop_map(Datagram::Ack),
+            any::<u64>().prop_map(Datagram::Ping),
+            any::<u64>().prop_map(Datagram::Pong),
+            (any::<String>(), any::<u64>()).prop_map(|(c, n)| Datagram::history(c, n)),
+            any::<String>().prop_map(Datagram::nick),
+            (any::<String>(), any::<String>(), any::<String>())
+                .prop_map(|(r, n, m)| Datagram::direct(r, n, m)),
+            (any::<String>(), moderate_action(), any::<String>())
+                .prop_map(|(c, a, t)| Datagram::moderate(c, a, t)),
+            any::<String>().prop_map(Datagram::list),
+            any::<String>().prop_map(Datagram::who),
+            (any::<String>(), any::<Vec<(String, u64)>>()).prop_map(|(pattern, channels)| {
+                Datagram::ChannelList(ChannelListDatagram { pattern, channels })
+            }),
+            (any::<String>(), any::<Vec<String>>()).prop_map(|(channel, members)| {
+                Datagram::MemberList(MemberListDatagram { channel, members })
+            }),
+            (any::<String>(), presence_event(), any::<String>())
+                .prop_map(|(c, e, w)| Datagram::presence(c, e, w)),
+            (any::<u64>(), any::<u64>(), any::<Vec<String>>()).prop_map(
+                |(node, version, patterns)| {
+                    Datagram::Interest(InterestDatagram {
+                        node,
+                        version,
+                        patterns,
+                    })
+                }
+            ),
+            (
+                any::<u64>(),
+                any::<u64>(),
+                any::<String>(),
+                any::<String>(),
+                any::<String>()
+            )
+                .prop_map(|(origin, id, channel, display_name, message)| {
+                    Datagram::Forward(ForwardDatagram {
+                        origin,
+                        id,
+                        publish: PublishDatagram {
+                            channel,
+                            display_name,
+                            message,
+                        },
+                    })
+                }),
+            (any::<u64>(), 1..u32::MAX, any::<Vec<u8>>()).prop_map(|(id, count, payload)| {
+                Datagram::Fragment(FragmentDatagram {
+                    id,
+                    index: count - 1,
+                    count,
+                    payload,
+                })
+            }),
+        ]
+        .boxed();
+        // Only the wrappings the decoder accepts
+        prop_oneof![
+            leaf.clone(),
+            reliable(leaf.clone()),
+            signed(leaf.clone()),
+            signed(reliable(leaf.clone())),
+            reliable(signed(leaf)),
+        ]
+    }
+
+    fn reliable(inner: BoxedStrategy<Datagram>) -> BoxedStrategy<Datagram> {
+        (any::<u64>(), inner)
+            .prop_map(|(s, d)| Datagram::reliable(s, d))
+            .boxed()
+    }
+
+    fn signed(inner: BoxedStrategy<Datagram>) -> BoxedStrategy<Datagram> {
+        (any::<String>(), any::<u64>(), any::<Vec<u8>>(), inner)
+            .prop_map(|(key_id, nonce, mac, d)| {
+                Datagram::Signed(SignedDatagram {
+                    key_id,
+                    timestamp: nonce / 2,
+                    nonce,
+                    mac,
+                    datagram: Box::new(d),
+                })
+            })
+            .boxed()
+    }
+
+    proptest! {
+        #[test]
+        fn test_binary_round_trip(datagram in datagram()) {
+            let encoded = encode(&datagram);
+            prop_assert_eq!(decode(&encoded).unwrap(), datagram);
+        }
+
+        #[test]
+        fn test_binary_truncation_is_an_error(datagram in datagram()) {
+            let encoded = encode(&datagram);
+            for n in 0..encoded.len() {
+                prop_assert!(decode(&encoded[..n]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_binary_encoding() {
+        let encoded = encode(&Datagram::publish("a|b", "c", "d"));
+        assert_eq!(
+            encoded,
+            vec![
+                MAGIC, VERSION, b'P', 0, 0, 0, 3, b'a', b'|', b'b', 0, 0, 0, 1, b'c', 0, 0, 0, 1,
+                b'd'
+            ]
+        );
+    }
+
+    #[test]
+    fn test_binary_rejects_deep_nesting() {
+        let mut datagram = Datagram::subscribe("rust_club");
+        for sequence in 0..2 {
+            datagram = Datagram::reliable(sequence, datagram);
+        }
+        assert_eq!(decode(&encode(&datagram)), Err(Error::Nested("reliable")));
+
+        // Built by hand, since encoding this would itself recurse too deeply
+        let mut encoded = vec![MAGIC, VERSION];
+        for _ in 0..100_000 {
+            encoded.push(b'R');
+            encoded.extend_from_slice(&1u64.to_be_bytes());
+            encoded.extend_from_slice(&[b'Z', 0, 0, 0, 0]);
+            encoded.extend_from_slice(&[0; 16]);
+            encoded.extend_from_slice(&[0, 0, 0, 0]);
+        }
+        encoded.extend_from_slice(&[b'S', 0, 0, 0, 0]);
+        assert_eq!(decode(&encoded), Err(Error::Nested("reliable")));
+    }
+
+    #[test]
+    fn test_binary_rejects_unknown_versions() {
+        assert!(decode(&[MAGIC, VERSION + 1, b'A', 0, 0, 0, 0, 0, 0, 0, 1]).is_err());
+        assert!(decode(b"A|1").is_err());
+    }
+}
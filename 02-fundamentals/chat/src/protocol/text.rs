@@ -0,0 +1,100 @@
+use super::Error;
+
+// Splits a text datagram into at most `count` fields on any `|` that isn't
+// escaped. The last field gets everything that's left, so it can contain
+// unescaped pipes. Fields are returned still escaped.
+pub fn fields(s: &str, count: usize) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+
+    for (i, c) in s.char_indices() {
+        if fields.len() + 1 == count {
+            break;
+        }
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '|' => {
+                fields.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    fields.push(&s[start..]);
+    fields
+}
+
+// Escapes a field so it can't be confused with the separators around it
+pub fn escape(s: &str) -> String {
+    escape_with(s, true)
+}
+
+// The last field can't be cut short by a pipe, so they're left alone
+pub fn escape_last(s: &str) -> String {
+    escape_with(s, false)
+}
+
+fn escape_with(s: &str, pipes: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '|' if pipes => escaped.push_str("\\|"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn unescape(s: &str) -> Result<String, Error> {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\\') => unescaped.push('\\'),
+                Some('|') => unescaped.push('|'),
+                Some('n') => unescaped.push('\n'),
+                Some(c) => return Err(Error::InvalidEscape(format!("\\{}", c))),
+                None => return Err(Error::InvalidEscape(String::from("\\"))),
+            },
+            '\n' => return Err(Error::InvalidEscape(String::from("unescaped newline"))),
+            c => unescaped.push(c),
+        }
+    }
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod text_tests {
+    use super::*;
+
+    #[test]
+    fn test_fields() {
+        assert_eq!(fields("a|b|c", 3), vec!["a", "b", "c"]);
+        assert_eq!(fields("a|b|c|d", 3), vec!["a", "b", "c|d"]);
+        assert_eq!(fields("a\\|b|c", 3), vec!["a\\|b", "c"]);
+        assert_eq!(fields("a\\\\|b", 3), vec!["a\\\\", "b"]);
+        assert_eq!(fields("", 3), vec![""]);
+        assert_eq!(fields("a|b", 1), vec!["a|b"]);
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a|b\\c\nd"), "a\\|b\\\\c\\nd");
+        assert_eq!(escape_last("a|b\\c\nd"), "a|b\\\\c\\nd");
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape("a\\|b\\\\c\\nd").unwrap(), "a|b\\c\nd");
+        assert_eq!(unescape("a|b").unwrap(), "a|b");
+        assert!(unescape("a\\b").is_err());
+        assert!(unescape("a\\").is_err());
+        assert!(unescape("a\nb").is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Instant;
+
+/// How fast something may go: `per_second` on average, with up to `burst`
+/// at once after a quiet spell
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Rate {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * f64::from(rate.per_second)).min(f64::from(rate.burst));
+        self.updated = now;
+    }
+}
+
+/// A token bucket for each key. Every event takes a token, and each bucket
+/// refills at the same rate up to its burst size.
+pub struct RateLimiter<K: Eq + Hash + Clone> {
+    rate: Rate,
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K: Eq + Hash + Clone> RateLimiter<K> {
+    pub fn new(rate: Rate) -> Self {
+        RateLimiter {
+            rate,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token for `key`, returning false if there aren't any left
+    pub fn allow(&mut self, key: &K, now: Instant) -> bool {
+        let rate = self.rate;
+        let bucket = self.buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: f64::from(rate.burst),
+            updated: now,
+        });
+        bucket.refill(rate, now);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Forgets any buckets that have refilled completely, since a new bucket
+    /// would be just the same
+    pub fn prune(&mut self, now: Instant) {
+        let rate = self.rate;
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(rate, now);
+            bucket.tokens < f64::from(rate.burst)
+        });
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+}
+
+#[cfg(test)]
+mod rate_limit_tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_bursts_then_refills() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(Rate {
+            per_second: 2,
+            burst: 3,
+        });
+        for _ in 0..3 {
+            assert!(limiter.allow(&"ferris", now));
+        }
+        assert!(!limiter.allow(&"ferris", now));
+        assert!(limiter.allow(&"gopher", now));
+
+        // Half a second brings back one token at two per second
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.allow(&"ferris", later));
+        assert!(!limiter.allow(&"ferris", later));
+
+        // The bucket never holds more than the burst
+        let much_later = now + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.allow(&"ferris", much_later));
+        }
+        assert!(!limiter.allow(&"ferris", much_later));
+    }
+
+    #[test]
+    fn test_prunes_full_buckets() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(Rate {
+            per_second: 1,
+            burst: 2,
+        });
+        limiter.allow(&"ferris", now);
+        limiter.allow(&"gopher", now + Duration::from_millis(900));
+        limiter.prune(now + Duration::from_secs(1));
+        assert_eq!(limiter.len(), 1);
+        limiter.prune(now + Duration::from_secs(2));
+        assert_eq!(limiter.len(), 0);
+    }
+}
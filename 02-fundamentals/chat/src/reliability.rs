@@ -0,0 +1,219 @@
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+pub const INITIAL_RETRANSMIT_DELAY: Duration = Duration::from_millis(100);
+pub const MAX_RETRANSMIT_DELAY: Duration = Duration::from_secs(2);
+pub const MAX_ATTEMPTS: u32 = 6;
+
+// How many out-of-order sequence numbers we remember per sender
+const WINDOW_SIZE: usize = 1024;
+
+struct Pending<T> {
+    payload: T,
+    attempts: u32,
+    delay: Duration,
+    next_attempt: Instant,
+}
+
+/// Datagrams waiting for an ACK, keyed by destination and sequence number.
+/// Each unacknowledged datagram is resent with exponential backoff until it
+/// is acknowledged or runs out of attempts.
+pub struct RetransmitQueue<K: Eq + Hash + Clone, T: Clone> {
+    pending: HashMap<(K, u64), Pending<T>>,
+}
+
+impl<K: Eq + Hash + Clone, T: Clone> RetransmitQueue<K, T> {
+    pub fn new() -> Self {
+        RetransmitQueue {
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, destination: K, sequence: u64, payload: T, now: Instant) {
+        let pending = Pending {
+            payload,
+            attempts: 1,
+            delay: INITIAL_RETRANSMIT_DELAY,
+            next_attempt: now + INITIAL_RETRANSMIT_DELAY,
+        };
+        self.pending.insert((destination, sequence), pending);
+    }
+
+    pub fn acknowledge(&mut self, destination: &K, sequence: u64) -> bool {
+        self.pending
+            .remove(&(destination.clone(), sequence))
+            .is_some()
+    }
+
+    /// Returns everything that is due for another attempt, and forgets about
+    /// anything that has used up all of its attempts.
+    pub fn due(&mut self, now: Instant) -> Vec<(K, u64, T)> {
+        let mut due = Vec::new();
+        let mut expired = Vec::new();
+
+        for (key, pending) in self.pending.iter_mut() {
+            if pending.next_attempt > now {
+                continue;
+            }
+            if pending.attempts >= MAX_ATTEMPTS {
+                expired.push(key.clone());
+                continue;
+            }
+            pending.attempts += 1;
+            pending.delay = (pending.delay * 2).min(MAX_RETRANSMIT_DELAY);
+            pending.next_attempt = now + pending.delay;
+            due.push((key.0.clone(), key.1, pending.payload.clone()));
+        }
+
+        for key in expired {
+            warn!(
+                "Giving up on datagram {} after {} attempts",
+                key.1, MAX_ATTEMPTS
+            );
+            self.pending.remove(&key);
+        }
+
+        due
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn forget(&mut self, destination: &K) {
+        self.pending.retain(|(key, _), _| key != destination);
+    }
+}
+
+/// Tracks which sequence numbers have been seen from a single sender so
+/// retransmitted datagrams are only handled once.
+pub struct SequenceWindow {
+    // Every sequence number up to and including the floor has been seen
+    floor: u64,
+    seen: BTreeSet<u64>,
+}
+
+impl SequenceWindow {
+    pub fn new() -> Self {
+        SequenceWindow {
+            floor: 0,
+            seen: BTreeSet::new(),
+        }
+    }
+
+    /// Returns true if the sequence number hasn't been seen before.
+    pub fn insert(&mut self, sequence: u64) -> bool {
+        if sequence <= self.floor || !self.seen.insert(sequence) {
+            return false;
+        }
+
+        while self.seen.remove(&(self.floor + 1)) {
+            self.floor += 1;
+        }
+
+        // Give up on gaps that are never going to be filled
+        while self.seen.len() > WINDOW_SIZE {
+            let lowest = *self.seen.iter().next().unwrap();
+            self.seen.remove(&lowest);
+            self.floor = lowest;
+        }
+
+        true
+    }
+}
+
+/// Duplicate suppression for every sender we've heard from.
+pub struct DuplicateFilter<K: Eq + Hash> {
+    windows: HashMap<K, SequenceWindow>,
+}
+
+impl<K: Eq + Hash> DuplicateFilter<K> {
+    pub fn new() -> Self {
+        DuplicateFilter {
+            windows: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, sender: K, sequence: u64) -> bool {
+        self.windows
+            .entry(sender)
+            .or_insert_with(SequenceWindow::new)
+            .insert(sequence)
+    }
+
+    pub fn forget(&mut self, sender: &K) {
+        self.windows.remove(sender);
+    }
+}
+
+#[cfg(test)]
+mod reliability_tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_window_in_order() {
+        let mut window = SequenceWindow::new();
+        assert!(window.insert(1));
+        assert!(window.insert(2));
+        assert!(!window.insert(1));
+        assert!(!window.insert(2));
+        assert!(window.insert(3));
+    }
+
+    #[test]
+    fn test_sequence_window_out_of_order() {
+        let mut window = SequenceWindow::new();
+        assert!(window.insert(3));
+        assert!(window.insert(1));
+        assert!(!window.insert(3));
+        assert!(window.insert(2));
+        assert!(!window.insert(2));
+        assert_eq!(window.floor, 3);
+        assert!(window.seen.is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_filter_is_per_sender() {
+        let mut filter = DuplicateFilter::new();
+        assert!(filter.insert("a", 1));
+        assert!(filter.insert("b", 1));
+        assert!(!filter.insert("a", 1));
+    }
+
+    #[test]
+    fn test_retransmit_backoff() {
+        let start = Instant::now();
+        let mut queue = RetransmitQueue::new();
+        queue.push("a", 1, "hello", start);
+
+        assert!(queue.due(start).is_empty());
+
+        let first = start + INITIAL_RETRANSMIT_DELAY;
+        assert_eq!(queue.due(first), vec![("a", 1, "hello")]);
+
+        // The delay doubles after each attempt
+        assert!(queue.due(first + INITIAL_RETRANSMIT_DELAY).is_empty());
+        let second = first + INITIAL_RETRANSMIT_DELAY * 2;
+        assert_eq!(queue.due(second), vec![("a", 1, "hello")]);
+
+        assert!(queue.acknowledge(&"a", 1));
+        assert!(!queue.acknowledge(&"a", 1));
+        assert!(queue.due(second + MAX_RETRANSMIT_DELAY).is_empty());
+    }
+
+    #[test]
+    fn test_retransmit_gives_up() {
+        let mut now = Instant::now();
+        let mut queue = RetransmitQueue::new();
+        queue.push("a", 1, "hello", now);
+
+        let mut attempts = 1;
+        while !queue.is_empty() {
+            now += MAX_RETRANSMIT_DELAY;
+            attempts += queue.due(now).len() as u32;
+        }
+
+        assert_eq!(attempts, MAX_ATTEMPTS);
+    }
+}
@@ -0,0 +1,495 @@
+use crate::client::Client;
+use crate::protocol::{
+    is_pattern, validate_channel, validate_display_name, validate_pattern, validate_target,
+    Datagram, ModerateAction, PublishDatagram, MULTI_LEVEL_WILDCARD,
+};
+use std::io::{stdin, BufRead, Error};
+use std::sync::mpsc::{channel, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+// How long to wait for datagrams before checking for new input
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_HISTORY: u64 = 10;
+
+pub const HELP: &str = "Commands:
+  /join CHANNEL     subscribe to a channel and make it the current one (or
+                    to a pattern like rust.* or rust.#, just to read)
+  /leave [CHANNEL]  unsubscribe from a channel (the current one by default)
+  /switch CHANNEL   send to another channel you've joined
+  /nick NAME        change your display name
+  /msg NAME TEXT    send a direct message to whoever has that display name
+  /history [COUNT]  replay recent messages from the current channel
+  /list [PATTERN]   list the channels matching a pattern (all of them by default)
+  /who [CHANNEL]    list who is in a channel (the current one by default)
+  /kick WHO         unsubscribe a nick or IP address from the current channel
+  /ban IP           kick an IP address, and keep it out of the current channel
+  /unban IP         let it back in
+  /quit             leave every channel and exit
+Anything else is sent to the current channel.";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Say(String),
+    Join(String),
+    Leave(Option<String>),
+    Switch(String),
+    Nick(String),
+    // A direct message, and who it's for
+    Direct(String, String),
+    History(u64),
+    List(Option<String>),
+    Who(Option<String>),
+    // Only the channel's moderators may do this
+    Moderate(ModerateAction, String),
+    Help,
+    Quit,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        if !line.starts_with('/') {
+            return Ok(Command::Say(String::from(line)));
+        }
+
+        let mut words = line.splitn(2, ' ');
+        let name = words.next().unwrap_or("");
+        let argument = words.next().map(str::trim).filter(|s| !s.is_empty());
+        let required = |usage: &str| {
+            argument
+                .map(String::from)
+                .ok_or_else(|| format!("Usage: {}", usage))
+        };
+        match name {
+            "/join" => Ok(Command::Join(required("/join CHANNEL")?)),
+            "/leave" => Ok(Command::Leave(argument.map(String::from))),
+            "/switch" => Ok(Command::Switch(required("/switch CHANNEL")?)),
+            "/nick" => Ok(Command::Nick(required("/nick NAME")?)),
+            "/msg" => match argument.and_then(|argument| argument.split_once(' ')) {
+                Some((recipient, message)) => Ok(Command::Direct(
+                    String::from(recipient),
+                    String::from(message.trim_start()),
+                )),
+                None => Err(String::from("Usage: /msg NAME TEXT")),
+            },
+            "/history" => match argument {
+                Some(count) => count
+                    .parse()
+                    .map(Command::History)
+                    .map_err(|_| String::from("Usage: /history [COUNT]")),
+                None => Ok(Command::History(DEFAULT_HISTORY)),
+            },
+            "/list" => Ok(Command::List(argument.map(String::from))),
+            "/who" => Ok(Command::Who(argument.map(String::from))),
+            "/kick" => Ok(Command::Moderate(
+                ModerateAction::Kick,
+                required("/kick WHO")?,
+            )),
+            "/ban" => Ok(Command::Moderate(ModerateAction::Ban, required("/ban IP")?)),
+            "/unban" => Ok(Command::Moderate(
+                ModerateAction::Unban,
+                required("/unban IP")?,
+            )),
+            "/help" => Ok(Command::Help),
+            "/quit" => Ok(Command::Quit),
+            // Lets a message start with a slash, by doubling it
+            _ if line.starts_with("//") => Ok(Command::Say(String::from(&line[1..]))),
+            _ => Err(format!("Unknown command: {} (try /help)", name)),
+        }
+    }
+}
+
+/// Everything the REPL remembers between lines: who we are, and where
+/// messages go
+pub struct Session {
+    nick: String,
+    channels: Vec<String>,
+    current: Option<String>,
+}
+
+impl Session {
+    pub fn new<N: Into<String>>(nick: N) -> Self {
+        Session {
+            nick: nick.into(),
+            channels: Vec::new(),
+            current: None,
+        }
+    }
+
+    pub fn nick(&self) -> &str {
+        &self.nick
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    pub fn channels(&self) -> &[String] {
+        &self.channels
+    }
+
+    // Where messages go, which can't be a pattern
+    fn destination(&self) -> Result<String, String> {
+        match &self.current {
+            Some(channel) if is_pattern(channel) => Err(format!(
+                "Can't send to {}, switch to a channel first",
+                channel
+            )),
+            Some(channel) => Ok(channel.clone()),
+            None => Err(String::from("Join a channel first with /join CHANNEL")),
+        }
+    }
+
+    /// Updates the session, returning the datagrams to send to the server
+    pub fn handle(&mut self, command: Command) -> Result<Vec<Datagram>, String> {
+        match command {
+            Command::Say(message) => {
+                let channel = self.destination()?;
+                Ok(vec![Datagram::Publish(PublishDatagram {
+                    channel,
+                    display_name: self.nick.clone(),
+                    message,
+                })])
+            }
+            Command::Join(channel) => {
+                validate_pattern(&channel).map_err(|error| error.to_string())?;
+                self.current = Some(channel.clone());
+                if self.channels.contains(&channel) {
+                    return Ok(Vec::new());
+                }
+                self.channels.push(channel.clone());
+                Ok(vec![Datagram::subscribe(channel)])
+            }
+            Command::Leave(channel) => {
+                let channel = channel
+                    .or_else(|| self.current.clone())
+                    .ok_or("You haven't joined any channels")?;
+                if !self.channels.contains(&channel) {
+                    return Err(format!("You haven't joined {}", channel));
+                }
+                self.channels.retain(|c| *c != channel);
+                if self.current.as_ref() == Some(&channel) {
+                    self.current = self.channels.last().cloned();
+                }
+                Ok(vec![Datagram::unsubscribe(channel)])
+            }
+            Command::Switch(channel) => {
+                if !self.channels.contains(&channel) {
+                    return Err(format!("Join {} first with /join {}", channel, channel));
+                }
+                self.current = Some(channel);
+                Ok(Vec::new())
+            }
+            Command::Nick(nick) => {
+                validate_display_name(&nick).map_err(|error| error.to_string())?;
+                self.nick = nick.clone();
+                Ok(vec![Datagram::nick(nick)])
+            }
+            Command::Direct(recipient, message) => {
+                validate_display_name(&recipient).map_err(|error| error.to_string())?;
+                Ok(vec![Datagram::direct(
+                    recipient,
+                    self.nick.clone(),
+                    message,
+                )])
+            }
+            Command::History(count) => {
+                let channel = self.destination()?;
+                Ok(vec![Datagram::history(channel, count)])
+            }
+            Command::List(pattern) => {
+                let pattern = pattern.unwrap_or_else(|| String::from(MULTI_LEVEL_WILDCARD));
+                validate_pattern(&pattern).map_err(|error| error.to_string())?;
+                Ok(vec![Datagram::list(pattern)])
+            }
+            Command::Who(channel) => {
+                let channel = match channel {
+                    Some(channel) => channel,
+                    None => self.destination()?,
+                };
+                validate_channel(&channel).map_err(|error| error.to_string())?;
+                Ok(vec![Datagram::who(channel)])
+            }
+            Command::Moderate(action, target) => {
+                validate_target(&target).map_err(|error| error.to_string())?;
+                let channel = self.destination()?;
+                Ok(vec![Datagram::moderate(channel, action, target)])
+            }
+            Command::Help | Command::Quit => Ok(Vec::new()),
+        }
+    }
+}
+
+/// How the server's datagrams are shown to the user
+pub fn format(datagram: &Datagram) -> String {
+    match datagram {
+        Datagram::Publish(d) => format!("[{}] {}: {}", d.channel, d.display_name, d.message),
+        // Parentheses can't appear in channel names, so these stand out
+        Datagram::Direct(d) => format!("(DM) {}: {}", d.display_name, d.message),
+        Datagram::Presence(d) => format!("[{}] * {} {}", d.channel, d.who, d.event.as_str()),
+        Datagram::ChannelList(d) if d.channels.is_empty() => {
+            format!("No channels match {}", d.pattern)
+        }
+        Datagram::ChannelList(d) => {
+            let channels: Vec<String> = d
+                .channels
+                .iter()
+                .map(|(channel, count)| format!("{} ({})", channel, count))
+                .collect();
+            format!("Channels matching {}: {}", d.pattern, channels.join(", "))
+        }
+        Datagram::MemberList(d) if d.members.is_empty() => format!("Nobody is in {}", d.channel),
+        Datagram::MemberList(d) => format!("In {}: {}", d.channel, d.members.join(", ")),
+        Datagram::Goodbye(message) => format!("* {}", message),
+        datagram => datagram.serialize(),
+    }
+}
+
+pub fn send(client: &mut Client, datagram: &Datagram, reliable: bool) -> Result<(), Error> {
+    if reliable {
+        client.send_reliable(datagram).map(|_| ())
+    } else {
+        client.send(datagram)
+    }
+}
+
+/// Unsubscribes from every channel in the session, rather than waiting for
+/// the server to evict us, and waits for it to acknowledge everything
+pub fn leave_all(client: &mut Client, session: &Session, reliable: bool) {
+    for channel in session.channels() {
+        if let Err(error) = send(client, &Datagram::unsubscribe(channel.as_str()), reliable) {
+            eprintln!("Failed to send: {}", error);
+        }
+    }
+    if !client.flush(FLUSH_TIMEOUT) {
+        warn!("Server didn't acknowledge everything we sent");
+    }
+}
+
+/// Reads commands from stdin until `/quit` or the end of input, printing
+/// everything the server sends in the meantime.
+pub fn run(mut client: Client, mut session: Session, reliable: bool) {
+    let (line_sender, lines) = channel();
+    thread::spawn(move || {
+        for line in stdin().lock().lines() {
+            match line {
+                Ok(line) => {
+                    if line_sender.send(line).is_err() {
+                        return;
+                    }
+                }
+                Err(error) => {
+                    error!("Failed to read from stdin: {}", error);
+                    return;
+                }
+            }
+        }
+    });
+
+    'repl: loop {
+        loop {
+            let line = match lines.try_recv() {
+                Ok(line) => line,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break 'repl,
+            };
+            let command = match Command::parse(&line) {
+                Ok(Command::Quit) => break 'repl,
+                Ok(Command::Help) => {
+                    println!("{}", HELP);
+                    continue;
+                }
+                Ok(command) => command,
+                Err(error) => {
+                    eprintln!("{}", error);
+                    continue;
+                }
+            };
+            match session.handle(command) {
+                Ok(datagrams) => {
+                    for datagram in datagrams {
+                        if let Err(error) = send(&mut client, &datagram, reliable) {
+                            eprintln!("Failed to send: {}", error);
+                        }
+                    }
+                }
+                Err(error) => eprintln!("{}", error),
+            }
+        }
+
+        match client.listen(Some(POLL_INTERVAL)) {
+            Some(Ok(datagram)) => println!("{}", format(&datagram)),
+            Some(Err(error)) => eprintln!("Server error: {}", error),
+            None => {}
+        }
+    }
+
+    leave_all(&mut client, &session, reliable);
+}
+
+#[cfg(test)]
+mod repl_tests {
+    use super::*;
+    use crate::protocol::{ChannelListDatagram, MemberListDatagram, PresenceEvent};
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(
+            Command::parse("hello"),
+            Ok(Command::Say(String::from("hello")))
+        );
+        assert_eq!(
+            Command::parse("//shrug"),
+            Ok(Command::Say(String::from("/shrug")))
+        );
+        assert_eq!(
+            Command::parse("/join rust_club\n"),
+            Ok(Command::Join(String::from("rust_club")))
+        );
+        assert_eq!(Command::parse("/leave"), Ok(Command::Leave(None)));
+        assert_eq!(
+            Command::parse("/nick Ferris the Crab"),
+            Ok(Command::Nick(String::from("Ferris the Crab")))
+        );
+        assert_eq!(Command::parse("/history"), Ok(Command::History(10)));
+        assert_eq!(Command::parse("/history 3"), Ok(Command::History(3)));
+        assert_eq!(
+            Command::parse("/ban 10.0.0.1"),
+            Ok(Command::Moderate(
+                ModerateAction::Ban,
+                String::from("10.0.0.1")
+            ))
+        );
+        assert_eq!(
+            Command::parse("/msg ferris  psst, over here"),
+            Ok(Command::Direct(
+                String::from("ferris"),
+                String::from("psst, over here")
+            ))
+        );
+        assert!(Command::parse("/msg ferris").is_err());
+        assert_eq!(Command::parse("/list"), Ok(Command::List(None)));
+        assert_eq!(
+            Command::parse("/who rust_club"),
+            Ok(Command::Who(Some(String::from("rust_club"))))
+        );
+        assert_eq!(Command::parse("/quit"), Ok(Command::Quit));
+        assert!(Command::parse("/kick").is_err());
+        assert!(Command::parse("/join").is_err());
+        assert!(Command::parse("/history lots").is_err());
+        assert!(Command::parse("/dance").is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            format(&Datagram::publish("DM", "ferris", "hi")),
+            "[DM] ferris: hi"
+        );
+        assert_eq!(
+            format(&Datagram::direct("me", "ferris", "hi")),
+            "(DM) ferris: hi"
+        );
+        assert_eq!(
+            format(&Datagram::presence("a", PresenceEvent::Joined, "ferris")),
+            "[a] * ferris joined"
+        );
+        assert_eq!(
+            format(&Datagram::goodbye("Server is shutting down")),
+            "* Server is shutting down"
+        );
+        assert_eq!(
+            format(&Datagram::ChannelList(ChannelListDatagram {
+                pattern: String::from("#"),
+                channels: vec![(String::from("a"), 2), (String::from("b"), 0)],
+            })),
+            "Channels matching #: a (2), b (0)"
+        );
+        assert_eq!(
+            format(&Datagram::MemberList(MemberListDatagram {
+                channel: String::from("a"),
+                members: vec![],
+            })),
+            "Nobody is in a"
+        );
+    }
+
+    #[test]
+    fn test_session() {
+        let mut session = Session::new("me");
+        assert!(session.handle(Command::Say(String::from("hi"))).is_err());
+        assert!(session
+            .handle(Command::Join(String::from("not a channel")))
+            .is_err());
+
+        for channel in &["a", "b"] {
+            assert_eq!(
+                session.handle(Command::Join(String::from(*channel))),
+                Ok(vec![Datagram::subscribe(*channel)])
+            );
+        }
+        assert_eq!(session.current(), Some("b"));
+
+        assert!(session.handle(Command::Switch(String::from("c"))).is_err());
+        assert_eq!(
+            session.handle(Command::Switch(String::from("a"))),
+            Ok(vec![])
+        );
+        assert_eq!(
+            session.handle(Command::Nick(String::from("you"))),
+            Ok(vec![Datagram::nick("you")])
+        );
+        assert_eq!(session.nick(), "you");
+        assert_eq!(
+            session.handle(Command::Direct(String::from("them"), String::from("psst"))),
+            Ok(vec![Datagram::direct("them", "you", "psst")])
+        );
+        assert_eq!(
+            session.handle(Command::Say(String::from("hi"))),
+            Ok(vec![Datagram::publish("a", "you", "hi")])
+        );
+        assert_eq!(
+            session.handle(Command::History(5)),
+            Ok(vec![Datagram::history("a", 5)])
+        );
+        assert_eq!(
+            session.handle(Command::List(None)),
+            Ok(vec![Datagram::list("#")])
+        );
+        assert_eq!(
+            session.handle(Command::Who(None)),
+            Ok(vec![Datagram::who("a")])
+        );
+        assert_eq!(
+            session.handle(Command::Moderate(
+                ModerateAction::Kick,
+                String::from("gopher")
+            )),
+            Ok(vec![Datagram::moderate(
+                "a",
+                ModerateAction::Kick,
+                "gopher"
+            )])
+        );
+
+        assert_eq!(
+            session.handle(Command::Leave(None)),
+            Ok(vec![Datagram::unsubscribe("a")])
+        );
+        assert_eq!(session.current(), Some("b"));
+        assert_eq!(session.channels(), &[String::from("b")]);
+        assert!(session
+            .handle(Command::Leave(Some(String::from("a"))))
+            .is_err());
+
+        assert_eq!(
+            session.handle(Command::Join(String::from("rust.*"))),
+            Ok(vec![Datagram::subscribe("rust.*")])
+        );
+        assert!(session.handle(Command::Say(String::from("hi"))).is_err());
+        assert!(session.handle(Command::History(5)).is_err());
+        assert!(session.handle(Command::Who(None)).is_err());
+    }
+}
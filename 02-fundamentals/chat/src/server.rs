@@ -1,184 +1,1337 @@
-use crate::protocol::{Datagram, PublishDatagram, SubscribeDatagram, UnsubscribeDatagram};
+mod federation;
+mod subscriptions;
+
+use self::federation::Federation;
+use self::subscriptions::Subscriptions;
+use crate::acl::{Acl, Permission, Principal};
+use crate::auth::{self, Verifier};
+use crate::fragment::{Reassembler, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::history::ChannelHistory;
+use crate::net;
+use crate::nicks::NickRegistry;
+use crate::protocol::{
+    self, ChannelListDatagram, Datagram, DirectDatagram, Encoding, ErrorCode, ForwardDatagram,
+    FragmentDatagram, HistoryDatagram, InterestDatagram, ListDatagram, MemberListDatagram,
+    ModerateAction, ModerateDatagram, NickDatagram, PresenceEvent, PublishDatagram,
+    ReliableDatagram, SignedDatagram, SubscribeDatagram, UnsubscribeDatagram, WhoDatagram,
+    MAX_DATAGRAM_SIZE,
+};
+use crate::rate_limit::{Rate, RateLimiter};
+use crate::reliability::{DuplicateFilter, RetransmitQueue};
+use crate::storage::{Log, Record};
+use crate::transport::{self, Event, Outbox, Peer};
 use std::collections::{HashMap, HashSet};
 use std::io::Error;
-use std::iter::{once, FromIterator};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
-
-use std::str;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 pub struct Server {
-    socket: UdpSocket,
-    subscriptions: HashMap<String, HashSet<SocketAddr>>,
+    local_address: SocketAddr,
+    // Sends everything on other threads, so one slow subscriber can't hold up
+    // the rest. Also where replies go for peers on a TCP or WebSocket connection.
+    outbox: Outbox,
+    events: Receiver<Event>,
+    event_sender: Sender<Event>,
+    // Cleared to stop the reader threads when the server is dropped
+    running: Arc<AtomicBool>,
+    // Set by a `ServerHandle` to ask the server to shut down
+    stopping: Arc<AtomicBool>,
+    // New options from a `ServerHandle`, to apply without restarting
+    reloads: Receiver<Options>,
+    reload_sender: Sender<Options>,
+    // Keyed by channel, or by a pattern with wildcards
+    subscriptions: Subscriptions<Peer>,
+    reliable_subscribers: HashSet<Peer>,
+    outbound_sequences: HashMap<Peer, u64>,
+    retransmits: RetransmitQueue<Peer, Datagram>,
+    duplicates: DuplicateFilter<Peer>,
+    last_seen: HashMap<Peer, Instant>,
+    // Peers that talk to us in the binary encoding, and expect replies in it
+    binary_peers: HashSet<Peer>,
+    fragments: Reassembler<Peer>,
+    // Display names claimed with a NICK datagram, for as long as the peer is around
+    nicks: NickRegistry<Peer>,
+    // Checks signatures, when only datagrams signed with a known key are accepted
+    verifier: Option<Verifier>,
+    // Who may read, write and moderate each channel, and who's been banned
+    acl: Acl,
+    // Publishes allowed from each IP address, and into each channel, if limited
+    sender_limits: Option<RateLimiter<IpAddr>>,
+    channel_limits: Option<RateLimiter<String>>,
+    metrics: Metrics,
+    // What was last logged, so quiet periods don't fill the log
+    logged_metrics: Metrics,
+    history: HashMap<String, ChannelHistory>,
+    // Other servers we pass publishes to, and take them from
+    federation: Federation,
+    next_heartbeat: Instant,
+    heartbeat_nonce: u64,
+    next_log_sync: Instant,
+    log: Option<Log>,
+    // How many records the log was left with when last compacted, since the
+    // snapshot alone can outgrow compact_after
+    snapshot_len: usize,
+    options: Options,
+}
+
+pub struct Options {
+    pub heartbeat_interval: Duration,
+    // Subscribers and nick holders are evicted after this many unanswered
+    // heartbeats
+    pub max_missed_heartbeats: u32,
+    // How many recent messages to keep for each channel
+    pub history_size: usize,
+    // How many channels to keep history for, forgetting the one published to
+    // least recently first
+    pub history_channels: usize,
+    // Where to keep the log that lets us recover after a restart, if anywhere
+    pub data_dir: Option<PathBuf>,
+    // Compact the log once it holds this many records
+    pub compact_after: usize,
+    // The largest datagram we'll reassemble from fragments
+    pub max_message_size: usize,
+    // Only accept datagrams signed with one of the keys in this file, if set
+    pub key_file: Option<PathBuf>,
+    // Per-channel permissions, if set. Otherwise every channel is open.
+    pub acl_file: Option<PathBuf>,
+    // How fast each IP address may publish, if limited
+    pub sender_rate: Option<Rate>,
+    // How fast each channel may be published to, by everyone together
+    pub channel_rate: Option<Rate>,
+    // Other servers to link to, which have to list this one as a peer too
+    pub peers: Vec<SocketAddr>,
+    // How many datagrams may wait to be sent to each peer before more are dropped
+    pub outbound_queue: usize,
+    // How many threads send datagrams
+    pub sender_threads: usize,
+    // How long to wait for a slow subscriber to take what we're sending
+    pub write_timeout: Duration,
+    // The largest datagram we'll take over UDP, which clients fragment anything bigger than
+    pub max_datagram_size: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            heartbeat_interval: Duration::from_secs(10),
+            max_missed_heartbeats: 3,
+            history_size: 100,
+            history_channels: 1000,
+            data_dir: None,
+            compact_after: 10_000,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            key_file: None,
+            acl_file: None,
+            sender_rate: None,
+            channel_rate: None,
+            peers: Vec::new(),
+            outbound_queue: 1024,
+            sender_threads: 4,
+            write_timeout: Duration::from_secs(1),
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+        }
+    }
+}
+
+/// Lets another thread, or a signal handler, stop or reconfigure a running
+/// server
+#[derive(Clone)]
+pub struct ServerHandle {
+    stopping: Arc<AtomicBool>,
+    reloads: Sender<Options>,
+}
+
+impl ServerHandle {
+    /// Asks the server to say goodbye to everyone and stop, without waiting
+    /// for it to finish
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    /// Asks the server to switch to new options, as far as it can without
+    /// restarting
+    pub fn reload(&self, options: Options) {
+        // Nothing to reload once the server has gone
+        self.reloads.send(options).ok();
+    }
+}
+
+/// Counts of trouble the server has dealt with, logged with each heartbeat
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Metrics {
+    // Publishes dropped because their sender's address was over its rate limit
+    pub throttled_senders: u64,
+    // Publishes dropped because their channel was over its rate limit
+    pub throttled_channels: u64,
+    // Datagrams dropped because their recipient's outbound queue was full
+    pub dropped_outbound: u64,
 }
 
-const WRITE_TIMEOUT: Option<Duration> = Some(Duration::from_secs(1));
-const READ_TIMEOUT: Option<Duration> = None;
+// How often the UDP reader thread checks whether the server has gone away
+const READ_TIMEOUT: Option<Duration> = Some(Duration::from_millis(50));
+// Wake up regularly even when nothing arrives, so retransmits go out on time
+const TICK: Duration = Duration::from_millis(50);
+// How often to sync the log to disk, which is all a power cut can lose
+const LOG_SYNC_INTERVAL: Duration = Duration::from_millis(100);
+// How long to spend sending what's left when shutting down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 impl Server {
-    pub fn new(port: u16) -> Result<Self, Error> {
-        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port))?;
+    pub fn new(address: SocketAddr, options: Options) -> Result<Self, Error> {
+        let socket = net::bind(address)?;
         socket.set_read_timeout(READ_TIMEOUT)?;
-        socket.set_write_timeout(WRITE_TIMEOUT)?;
-        Ok(Server {
-            socket,
-            subscriptions: HashMap::new(),
-        })
+        socket.set_write_timeout(Some(options.write_timeout))?;
+        Server::from_socket(socket, options)
+    }
+
+    fn from_socket(socket: UdpSocket, options: Options) -> Result<Self, Error> {
+        let (event_sender, events) = channel();
+        let (reload_sender, reloads) = channel();
+        let running = Arc::new(AtomicBool::new(true));
+        // So datagrams from peers can be recognised by the address they come from
+        let local_address = socket.local_addr()?;
+        let peers = options
+            .peers
+            .iter()
+            .map(|peer| net::reachable_from(&local_address, *peer))
+            .collect();
+        transport::spawn_udp_reader(
+            socket.try_clone()?,
+            options.max_datagram_size,
+            event_sender.clone(),
+            running.clone(),
+        );
+        let outbox = Outbox::new(socket, options.outbound_queue, options.sender_threads);
+
+        let mut server = Server {
+            local_address,
+            outbox,
+            events,
+            event_sender,
+            running,
+            stopping: Arc::new(AtomicBool::new(false)),
+            reloads,
+            reload_sender,
+            subscriptions: Subscriptions::new(),
+            reliable_subscribers: HashSet::new(),
+            outbound_sequences: HashMap::new(),
+            retransmits: RetransmitQueue::new(),
+            duplicates: DuplicateFilter::new(),
+            last_seen: HashMap::new(),
+            binary_peers: HashSet::new(),
+            fragments: Reassembler::new(options.max_message_size),
+            nicks: NickRegistry::new(),
+            verifier: None,
+            acl: Acl::default(),
+            sender_limits: options.sender_rate.map(RateLimiter::new),
+            channel_limits: options.channel_rate.map(RateLimiter::new),
+            metrics: Metrics::default(),
+            logged_metrics: Metrics::default(),
+            history: HashMap::new(),
+            federation: Federation::new(rand::random(), peers),
+            next_heartbeat: Instant::now() + options.heartbeat_interval,
+            heartbeat_nonce: 0,
+            next_log_sync: Instant::now() + LOG_SYNC_INTERVAL,
+            log: None,
+            snapshot_len: 0,
+            options,
+        };
+
+        if let Some(key_file) = &server.options.key_file {
+            let keys = auth::load_keys(key_file)?;
+            info!("Loaded {} keys from {}", keys.len(), key_file.display());
+            server.verifier = Some(Verifier::new(keys));
+        }
+
+        if let Some(acl_file) = &server.options.acl_file {
+            server.acl = Acl::load(acl_file)?;
+            info!("Loaded channel permissions from {}", acl_file.display());
+        }
+
+        if let Some(data_dir) = &server.options.data_dir {
+            let (log, records) = Log::open(data_dir)?;
+            info!(
+                "Recovering {} records from {}",
+                records.len(),
+                data_dir.display()
+            );
+            server.restore(records);
+            server.log = Some(log);
+            server.compact()?;
+        }
+
+        // Peers that are already running can start forwarding to us straight away
+        server.advertise();
+        Ok(server)
+    }
+
+    /// The address we're receiving UDP datagrams on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_address
+    }
+
+    /// Accepts TCP connections as well, returning the address it's listening on
+    pub fn listen_tcp(&mut self, address: SocketAddr) -> Result<SocketAddr, Error> {
+        let listener = net::listen(address)?;
+        let address = listener.local_addr()?;
+        transport::spawn_tcp_listener(
+            listener,
+            self.event_sender.clone(),
+            self.options.max_message_size,
+        );
+        Ok(address)
+    }
+
+    /// Accepts WebSocket connections as well, returning the address it's
+    /// listening on
+    pub fn listen_websocket(&mut self, address: SocketAddr) -> Result<SocketAddr, Error> {
+        let listener = net::listen(address)?;
+        let address = listener.local_addr()?;
+        transport::spawn_websocket_listener(
+            listener,
+            self.event_sender.clone(),
+            self.options.max_message_size,
+        );
+        Ok(address)
+    }
+
+    fn restore(&mut self, records: Vec<Record>) {
+        let now = Instant::now();
+        for record in records {
+            match record {
+                Record::Publish(sequence, datagram) => {
+                    self.channel_history(&datagram.channel)
+                        .restore(sequence, datagram);
+                }
+                Record::Subscribe(channel, address) => {
+                    self.subscriptions.insert(&channel, Peer::Udp(address));
+                    // Give everyone a chance to answer a heartbeat
+                    self.last_seen.insert(Peer::Udp(address), now);
+                }
+                Record::Unsubscribe(channel, address) => {
+                    self.subscriptions.remove(&channel, &Peer::Udp(address));
+                }
+            }
+        }
+    }
+
+    // Everything needed to rebuild the current state, and nothing more
+    fn snapshot(&self) -> Vec<Record> {
+        let subscriptions = self
+            .subscriptions
+            .iter()
+            .filter_map(|(channel, peer)| match peer {
+                Peer::Udp(address) => Some(Record::Subscribe(channel, *address)),
+                Peer::Tcp(_) | Peer::WebSocket(_) => None,
+            });
+        let history = self.history.values().flat_map(|history| {
+            history
+                .entries()
+                .map(|(sequence, datagram)| Record::Publish(*sequence, datagram.clone()))
+        });
+        subscriptions.chain(history).collect()
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        let snapshot = self.snapshot();
+        if let Some(log) = self.log.as_mut() {
+            debug!(
+                "Compacting log from {} to {} records",
+                log.len(),
+                snapshot.len()
+            );
+            log.compact(&snapshot)?;
+            self.snapshot_len = snapshot.len();
+        }
+        Ok(())
+    }
+
+    fn persist(&mut self, record: Record) {
+        let log = match self.log.as_mut() {
+            Some(log) => log,
+            None => return,
+        };
+        if let Err(error) = log.append(&record) {
+            error!("Error appending to log: {}", error);
+        }
+        if log.len() >= self.snapshot_len + self.options.compact_after {
+            if let Err(error) = self.compact() {
+                error!("Error compacting log: {}", error);
+            }
+        }
+    }
+
+    // Connections don't survive a restart, so only UDP subscriptions are kept
+    fn persist_subscription<F>(&mut self, peer: Peer, record: F)
+    where
+        F: FnOnce(SocketAddr) -> Record,
+    {
+        if let Peer::Udp(address) = peer {
+            self.persist(record(address));
+        }
+    }
+
+    fn send_datagram(&mut self, datagram: &Datagram, address: &Peer) {
+        let encoding = if self.binary_peers.contains(address) {
+            Encoding::Binary
+        } else {
+            Encoding::Text
+        };
+        if !self.outbox.push(*address, datagram.clone(), encoding) {
+            debug!("Outbound queue for {} is full, dropping datagram", address);
+            self.metrics.dropped_outbound += 1;
+        }
+    }
+
+    pub fn send(&mut self, publish_datagram: &PublishDatagram, address: &Peer) {
+        self.deliver(Datagram::Publish(publish_datagram.clone()), address);
+    }
+
+    // Sends a message reliably to anyone who subscribed reliably, and to peers
+    fn deliver(&mut self, datagram: Datagram, address: &Peer) {
+        if !self.reliable_subscribers.contains(address) && !self.is_peer(address) {
+            self.send_datagram(&datagram, address);
+            return;
+        }
+
+        let sequence = self.outbound_sequences.entry(*address).or_insert(0);
+        *sequence += 1;
+        let datagram = Datagram::reliable(*sequence, datagram);
+        self.retransmits
+            .push(*address, *sequence, datagram.clone(), Instant::now());
+        self.send_datagram(&datagram, address);
+    }
+
+    fn is_peer(&self, address: &Peer) -> bool {
+        match address {
+            Peer::Udp(address) => self.federation.is_peer(address),
+            _ => false,
+        }
+    }
+
+    // Tells every peer which patterns our subscribers have
+    fn advertise(&mut self) {
+        if self.federation.peers().is_empty() {
+            return;
+        }
+        let mut patterns: Vec<String> = self
+            .subscriptions
+            .iter()
+            .map(|(pattern, _)| pattern)
+            .collect();
+        patterns.sort();
+        patterns.dedup();
+        let datagram = Datagram::Interest(self.federation.advertise(patterns));
+        for peer in self.federation.peers().to_vec() {
+            self.send_datagram(&datagram, &Peer::Udp(peer));
+        }
+    }
+
+    // Peers only need to hear about a pattern gaining its first subscriber or
+    // losing its last, rather than waiting for the next heartbeat
+    fn subscriptions_changed(&mut self, pattern: &str) {
+        if self.subscriptions.get(pattern).map_or(0, HashSet::len) <= 1 {
+            self.advertise();
+        }
+    }
+
+    fn allows(&self, channel: &str, permission: Permission, peer: &Peer) -> bool {
+        self.acl.allows(channel, permission, peer.ip())
+    }
+
+    // How a peer is known to everyone else: their nick, or their address if
+    // they haven't claimed one
+    fn name(&self, peer: &Peer) -> String {
+        match self.nicks.nick(peer) {
+            Some(nick) => String::from(nick),
+            None => peer.address().to_string(),
+        }
+    }
+
+    // Tells everyone else who can see a channel that `peer` joined or left it.
+    // Patterns aren't channels anyone can be seen in, so they're not announced.
+    fn announce(&mut self, channel: &str, event: PresenceEvent, peer: &Peer) {
+        if protocol::is_pattern(channel) {
+            return;
+        }
+        let datagram = Datagram::presence(channel, event, self.name(peer));
+        let addresses: Vec<Peer> = self
+            .subscriptions
+            .matching(channel)
+            .into_iter()
+            .filter(|address| address != peer)
+            .filter(|address| self.allows(channel, Permission::Read, address))
+            .collect();
+        for address in addresses {
+            self.deliver(datagram.clone(), &address);
+        }
+    }
+
+    fn handle_subscribe(&mut self, datagram: SubscribeDatagram, address: Peer) {
+        if !self.allows(&datagram.channel, Permission::Read, &address) {
+            let message = format!("Not allowed to read: {}", datagram.channel);
+            return self.reject(ErrorCode::Forbidden, message, &address);
+        }
+        if !self.subscriptions.insert(&datagram.channel, address) {
+            return;
+        }
+        self.announce(&datagram.channel, PresenceEvent::Joined, &address);
+        self.subscriptions_changed(&datagram.channel);
+        self.persist_subscription(address, |address| {
+            Record::Subscribe(datagram.channel, address)
+        });
+    }
+
+    fn reject<M: Into<String>>(&mut self, code: ErrorCode, message: M, address: &Peer) {
+        self.send_datagram(&Datagram::rejection(code, message), address);
+    }
+
+    fn handle_unsubscribe(&mut self, datagram: UnsubscribeDatagram, address: Peer) {
+        if self.subscriptions.remove(&datagram.channel, &address) {
+            self.announce(&datagram.channel, PresenceEvent::Left, &address);
+            self.subscriptions_changed(&datagram.channel);
+            self.persist_subscription(address, |address| {
+                Record::Unsubscribe(datagram.channel, address)
+            });
+            self.unsubscribed(&address);
+        } else {
+            let message = format!("Not subscribed to: {}", datagram.channel);
+            self.reject(ErrorCode::UnknownChannel, message, &address);
+        }
+    }
+
+    fn handle_nick(&mut self, datagram: NickDatagram, address: Peer) {
+        if let Err(error) = self.nicks.register(address, &datagram.display_name) {
+            self.reject(ErrorCode::NickInUse, error.to_string(), &address);
+        }
+    }
+
+    // Checks the rate limits before doing anything else with a message, so a
+    // flood can't be amplified by fanning it out to every subscriber. Direct
+    // messages only count against their sender.
+    fn throttle(&mut self, channel: Option<&str>, sender: &Peer) -> bool {
+        let now = Instant::now();
+        let ip = sender.ip();
+        if let Some(limits) = self.sender_limits.as_mut() {
+            if !limits.allow(&ip, now) {
+                self.metrics.throttled_senders += 1;
+                let message = format!("Too many messages from: {}", ip);
+                self.reject(ErrorCode::RateLimited, message, sender);
+                return true;
+            }
+        }
+        if let (Some(limits), Some(channel)) = (self.channel_limits.as_mut(), channel) {
+            if !limits.allow(&String::from(channel), now) {
+                self.metrics.throttled_channels += 1;
+                let message = format!("Too many messages on: {}", channel);
+                self.reject(ErrorCode::RateLimited, message, sender);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn handle_publish(&mut self, datagram: PublishDatagram, sender: Peer) {
+        if self.throttle(Some(&datagram.channel), &sender) {
+            return;
+        }
+        if let Err(error) = self.nicks.check(&sender, &datagram.display_name) {
+            self.reject(ErrorCode::Forbidden, error.to_string(), &sender);
+            return;
+        }
+        if !self.allows(&datagram.channel, Permission::Write, &sender) {
+            let message = format!("Not allowed to write: {}", datagram.channel);
+            return self.reject(ErrorCode::Forbidden, message, &sender);
+        }
+        self.record_history(&datagram);
+
+        let forward = ForwardDatagram {
+            origin: self.federation.id(),
+            id: self.federation.next_message_id(),
+            publish: datagram,
+        };
+        let delivered = self.fan_out(&forward.publish);
+        let forwarded = self.forward(&forward, None);
+        if !delivered && !forwarded {
+            // It's kept in the history, but nobody will see it right now
+            let message = format!("No subscribers on: {}", forward.publish.channel);
+            self.reject(ErrorCode::UnknownChannel, message, &sender);
+        }
+    }
+
+    // Sends a publish to our own subscribers, returning false if there aren't any
+    fn fan_out(&mut self, datagram: &PublishDatagram) -> bool {
+        // Wildcard subscribers might not be allowed to read every channel they match
+        let addresses: Vec<Peer> = self
+            .subscriptions
+            .matching(&datagram.channel)
+            .into_iter()
+            .filter(|peer| self.allows(&datagram.channel, Permission::Read, peer))
+            .collect();
+        for address in &addresses {
+            self.send(datagram, address);
+        }
+        !addresses.is_empty()
+    }
+
+    // Passes a publish on towards every other server with a subscriber for it,
+    // except back the way it came, returning false if there aren't any
+    fn forward(&mut self, datagram: &ForwardDatagram, from: Option<SocketAddr>) -> bool {
+        let routes = self.federation.routes(&datagram.publish.channel, from);
+        for route in &routes {
+            self.deliver(Datagram::Forward(datagram.clone()), &Peer::Udp(*route));
+        }
+        !routes.is_empty()
+    }
+
+    fn handle_forward(&mut self, datagram: ForwardDatagram, address: Peer) {
+        let from = match address {
+            Peer::Udp(from) if self.federation.is_peer(&from) => from,
+            _ => {
+                let message = "Only peers may forward publishes";
+                return self.reject(ErrorCode::Forbidden, message, &address);
+            }
+        };
+        if !self.federation.first_sighting(datagram.origin, datagram.id) {
+            debug!(
+                "Ignoring publish {} from server {} that we've already seen",
+                datagram.id, datagram.origin
+            );
+            return;
+        }
+        // The server it was published on has checked who sent it
+        self.record_history(&datagram.publish);
+        self.fan_out(&datagram.publish);
+        self.forward(&datagram, Some(from));
+    }
+
+    fn handle_interest(&mut self, datagram: InterestDatagram, address: Peer) {
+        let from = match address {
+            Peer::Udp(from) if self.federation.is_peer(&from) => from,
+            _ => {
+                let message = "Only peers may advertise subscriptions";
+                return self.reject(ErrorCode::Forbidden, message, &address);
+            }
+        };
+        if !self.federation.learn(&datagram, from, Instant::now()) {
+            return;
+        }
+        let relay = Datagram::Interest(datagram);
+        for peer in self.federation.peers().to_vec() {
+            if peer != from {
+                self.send_datagram(&relay, &Peer::Udp(peer));
+            }
+        }
     }
 
-    pub fn send(&self, publish_datagram: &PublishDatagram, address: &SocketAddr) {
-        let datagram = Datagram::Publish(publish_datagram.copy());
-        let result = self
-            .socket
-            .send_to(datagram.serialize().as_bytes(), address);
-        if let Err(error) = result {
-            error!("Error sending datagram: {}", error);
+    fn handle_direct(&mut self, datagram: DirectDatagram, sender: Peer) {
+        if self.throttle(None, &sender) {
+            return;
+        }
+        if let Err(error) = self.nicks.check(&sender, &datagram.display_name) {
+            return self.reject(ErrorCode::Forbidden, error.to_string(), &sender);
+        }
+        match self.nicks.owner(&datagram.recipient) {
+            Some(recipient) => {
+                let recipient = *recipient;
+                self.deliver(Datagram::Direct(datagram), &recipient);
+            }
+            None => {
+                let message = format!("Nobody is called: {}", datagram.recipient);
+                self.reject(ErrorCode::UnknownNick, message, &sender);
+            }
         }
     }
 
-    fn handle_subscribe(&mut self, datagram: SubscribeDatagram, address: SocketAddr) {
-        match self.subscriptions.get_mut(&datagram.channel) {
-            Some(addresses) => {
-                addresses.insert(address);
+    fn channel_history(&mut self, channel: &str) -> &mut ChannelHistory {
+        if !self.history.contains_key(channel) {
+            // Make room by forgetting whichever channels have been quiet longest
+            while self.history.len() >= self.options.history_channels {
+                let quietest = self
+                    .history
+                    .iter()
+                    .min_by_key(|(_, history)| history.updated())
+                    .map(|(channel, _)| channel.clone());
+                match quietest {
+                    Some(quietest) => self.history.remove(&quietest),
+                    None => break,
+                };
             }
+        }
+        let history_size = self.options.history_size;
+        self.history
+            .entry(String::from(channel))
+            .or_insert_with(|| ChannelHistory::new(history_size))
+    }
+
+    fn record_history(&mut self, datagram: &PublishDatagram) {
+        let sequence = self
+            .channel_history(&datagram.channel)
+            .push(datagram.clone());
+        self.persist(Record::Publish(sequence, datagram.clone()));
+    }
+
+    fn handle_history(&mut self, datagram: HistoryDatagram, address: Peer) {
+        if !self.allows(&datagram.channel, Permission::Read, &address) {
+            let message = format!("Not allowed to read: {}", datagram.channel);
+            return self.reject(ErrorCode::Forbidden, message, &address);
+        }
+        let replay: Vec<PublishDatagram> = match self.history.get(&datagram.channel) {
+            Some(history) => history.last(datagram.count).into_iter().cloned().collect(),
             None => {
-                let addresses = HashSet::from_iter(once(address));
-                self.subscriptions.insert(datagram.channel, addresses);
+                let message = format!("No history for: {}", datagram.channel);
+                self.reject(ErrorCode::UnknownChannel, message, &address);
+                return;
             }
         };
+        for datagram in replay {
+            self.send(&datagram, &address);
+        }
+    }
+
+    fn handle_list(&mut self, datagram: ListDatagram, address: Peer) {
+        // Channels with history still exist once everyone has left them
+        let mut names: Vec<String> = self
+            .subscriptions
+            .iter()
+            .map(|(channel, _)| channel)
+            .filter(|channel| !protocol::is_pattern(channel))
+            .chain(self.history.keys().cloned())
+            .filter(|channel| protocol::matches_pattern(&datagram.pattern, channel))
+            .filter(|channel| self.allows(channel, Permission::Read, &address))
+            .collect();
+        names.sort();
+        names.dedup();
+        let channels = names
+            .into_iter()
+            .map(|channel| {
+                let count = self.subscriptions.get(&channel).map_or(0, HashSet::len);
+                (channel, count as u64)
+            })
+            .collect();
+        let reply = Datagram::ChannelList(ChannelListDatagram {
+            pattern: datagram.pattern,
+            channels,
+        });
+        self.send_datagram(&reply, &address);
+    }
+
+    fn handle_who(&mut self, datagram: WhoDatagram, address: Peer) {
+        if !self.allows(&datagram.channel, Permission::Read, &address) {
+            let message = format!("Not allowed to read: {}", datagram.channel);
+            return self.reject(ErrorCode::Forbidden, message, &address);
+        }
+        let mut members: Vec<String> = match self.subscriptions.get(&datagram.channel) {
+            Some(peers) => peers.iter().map(|peer| self.name(peer)).collect(),
+            None => Vec::new(),
+        };
+        members.sort();
+        let reply = Datagram::MemberList(MemberListDatagram {
+            channel: datagram.channel,
+            members,
+        });
+        self.send_datagram(&reply, &address);
     }
 
-    fn handle_unsubscribe(&mut self, datagram: UnsubscribeDatagram, address: SocketAddr) {
-        if let Some(addresses) = self.subscriptions.get_mut(&datagram.channel) {
-            addresses.remove(&address);
+    fn handle_moderate(&mut self, datagram: ModerateDatagram, moderator: Peer) {
+        if !self.allows(&datagram.channel, Permission::Moderate, &moderator) {
+            let message = format!("Not a moderator of: {}", datagram.channel);
+            return self.reject(ErrorCode::Forbidden, message, &moderator);
+        }
+        let target = Principal::parse(&datagram.target);
+        match datagram.action {
+            ModerateAction::Kick => {}
+            ModerateAction::Ban => {
+                if !self.acl.ban(&datagram.channel, target.clone()) {
+                    let message = format!("Only IP addresses can be banned: {}", target);
+                    return self.reject(ErrorCode::Parse, message, &moderator);
+                }
+            }
+            ModerateAction::Unban => {
+                if !self.acl.unban(&datagram.channel, &target) {
+                    let message = format!("Not banned from {}: {}", datagram.channel, target);
+                    self.reject(ErrorCode::UnknownChannel, message, &moderator);
+                }
+                return;
+            }
+        }
+        info!(
+            "{} {} {} from {}",
+            moderator,
+            datagram.action.as_str(),
+            target,
+            datagram.channel
+        );
+
+        // Moderators can't kick themselves by accident with a broad target
+        let kicked: Vec<Peer> = match self.subscriptions.get(&datagram.channel) {
+            Some(addresses) => addresses
+                .iter()
+                .filter(|peer| **peer != moderator)
+                .filter(|peer| target.matches(peer.ip(), self.nicks.nick(peer)))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
         };
+        if kicked.is_empty() && datagram.action == ModerateAction::Kick {
+            let message = format!("Nobody matching {} on: {}", target, datagram.channel);
+            return self.reject(ErrorCode::UnknownChannel, message, &moderator);
+        }
+        let message = format!(
+            "You were {} from: {}",
+            past_tense(datagram.action),
+            datagram.channel
+        );
+        for peer in kicked {
+            self.subscriptions.remove(&datagram.channel, &peer);
+            self.announce(&datagram.channel, PresenceEvent::Left, &peer);
+            self.subscriptions_changed(&datagram.channel);
+            let channel = datagram.channel.clone();
+            self.persist_subscription(peer, |address| Record::Unsubscribe(channel, address));
+            self.unsubscribed(&peer);
+            self.reject(ErrorCode::Forbidden, message.as_str(), &peer);
+        }
+    }
+
+    // Reliable delivery only lasts as long as some subscription does. Their
+    // sequence numbers carry on from where they were, since the peer would
+    // take a fresh start for duplicates if they subscribed reliably again.
+    fn unsubscribed(&mut self, peer: &Peer) {
+        if !self
+            .subscriptions
+            .iter()
+            .any(|(_, subscriber)| subscriber == peer)
+        {
+            self.reliable_subscribers.remove(peer);
+        }
     }
 
-    fn handle_publish(&mut self, datagram: PublishDatagram) {
-        if let Some(addresses) = self.subscriptions.get(&datagram.channel) {
-            for address in addresses.into_iter() {
-                self.send(&datagram, &address);
+    fn handle_reliable(&mut self, datagram: ReliableDatagram, address: Peer) {
+        // Always ACK, even duplicates, in case our previous ACK was lost
+        self.send_datagram(&Datagram::Ack(datagram.sequence), &address);
+
+        if !self.duplicates.insert(address, datagram.sequence) {
+            debug!(
+                "Ignoring duplicate datagram {} from {}",
+                datagram.sequence, address
+            );
+            return;
+        }
+
+        // Subscribing reliably opts in to reliable delivery of publishes
+        if let Datagram::Subscribe(_) = *datagram.datagram {
+            self.reliable_subscribers.insert(address);
+        }
+
+        self.handle_datagram(*datagram.datagram, address);
+    }
+
+    fn handle_fragment(&mut self, fragment: FragmentDatagram, address: Peer) {
+        match self.fragments.push(address, fragment, Instant::now()) {
+            Ok(Some(buf)) => self.handle_datagram_buffer(&buf, address),
+            Ok(None) => {}
+            Err(error) => {
+                error!("Error reassembling datagram from {}: {}", address, error);
+                self.reject(ErrorCode::from(&error), error.to_string(), &address);
             }
+        }
+    }
+
+    fn handle_signed(&mut self, datagram: SignedDatagram, address: Peer) {
+        let verifier = match self.verifier.as_mut() {
+            Some(verifier) => verifier,
+            // Nobody's checking, so the signature doesn't matter
+            None => return self.handle_datagram(*datagram.datagram, address),
         };
+        match verifier.verify(datagram, SystemTime::now()) {
+            Ok(datagram) => self.handle_datagram(datagram, address),
+            Err(error) => {
+                warn!("Rejecting datagram from {}: {}", address, error);
+                self.reject(ErrorCode::Unauthorized, error.to_string(), &address);
+            }
+        }
     }
 
-    fn handle_datagram(&mut self, datagram: Datagram, address: SocketAddr) {
+    fn handle_datagram(&mut self, datagram: Datagram, address: Peer) {
         debug!("Handling: {}", datagram.serialize());
 
         match datagram {
             Datagram::Subscribe(d) => self.handle_subscribe(d, address),
             Datagram::Unsubscribe(d) => self.handle_unsubscribe(d, address),
-            Datagram::Publish(d) => self.handle_publish(d),
+            Datagram::Publish(d) => self.handle_publish(d, address),
+            Datagram::Direct(d) => self.handle_direct(d, address),
+            Datagram::Nick(d) => self.handle_nick(d, address),
+            Datagram::Signed(d) => self.handle_signed(d, address),
+            Datagram::History(d) => self.handle_history(d, address),
+            Datagram::Moderate(d) => self.handle_moderate(d, address),
+            Datagram::Forward(d) => self.handle_forward(d, address),
+            Datagram::Interest(d) => self.handle_interest(d, address),
+            Datagram::List(d) => self.handle_list(d, address),
+            Datagram::Who(d) => self.handle_who(d, address),
+            Datagram::Reliable(d) => self.handle_reliable(d, address),
+            Datagram::Fragment(d) => self.handle_fragment(d, address),
+            Datagram::Ack(sequence) => {
+                self.retransmits.acknowledge(&address, sequence);
+            }
+            Datagram::Ping(nonce) => self.send_datagram(&Datagram::Pong(nonce), &address),
+            // Any datagram counts as a sign of life, so there's nothing else to do
+            Datagram::Pong(_) => {}
+            Datagram::Goodbye(_) => {
+                info!("{} said goodbye", address);
+                self.evict(&address);
+            }
             Datagram::Error(e) => {
                 debug!("Recieved Datagram::Error: {}", e);
             }
+            // Only the server sends these
+            Datagram::ChannelList(_) | Datagram::MemberList(_) | Datagram::Presence(_) => {
+                debug!("Ignoring reply from {}", address);
+            }
         };
     }
 
-    fn handle_datagram_string(&mut self, string: &str, address: SocketAddr) {
-        match Datagram::parse(string) {
-            Ok(datagram) => self.handle_datagram(datagram, address),
-            Err(error) => {
-                error!("Error parsing datagram: {:?}", error);
+    fn evict(&mut self, address: &Peer) {
+        info!("Forgetting about silent peer: {}", address);
+        for channel in self.subscriptions.remove_all(address) {
+            self.announce(&channel, PresenceEvent::Left, address);
+            self.subscriptions_changed(&channel);
+            self.persist_subscription(*address, |address| Record::Unsubscribe(channel, address));
+        }
+        self.reliable_subscribers.remove(address);
+        self.outbound_sequences.remove(address);
+        self.retransmits.forget(address);
+        self.duplicates.forget(address);
+        self.last_seen.remove(address);
+        self.binary_peers.remove(address);
+        self.fragments.forget(address);
+        self.nicks.forget(address);
+        self.outbox.close(address);
+    }
+
+    fn heartbeat(&mut self) {
+        let now = Instant::now();
+        if now < self.next_heartbeat {
+            return;
+        }
+        self.next_heartbeat = now + self.options.heartbeat_interval;
+        self.heartbeat_nonce += 1;
+
+        // Subscribers and nick holders are pinged, since they may just be
+        // listening quietly
+        let nick_holders = self
+            .last_seen
+            .keys()
+            .filter(|peer| self.nicks.nick(peer).is_some());
+        let listeners: HashSet<Peer> = self
+            .subscriptions
+            .iter()
+            .map(|(_, peer)| *peer)
+            .chain(nick_holders.cloned())
+            .collect();
+        let max_silence = self.options.heartbeat_interval * self.options.max_missed_heartbeats;
+
+        // Forget about anyone else who has gone quiet
+        let stale: Vec<Peer> = self
+            .last_seen
+            .iter()
+            .filter(|(address, _)| !listeners.contains(*address))
+            .filter(|(_, last_seen)| now - **last_seen >= max_silence)
+            .map(|(address, _)| *address)
+            .collect();
+        for address in stale {
+            self.evict(&address);
+        }
+
+        for address in listeners {
+            let silence = self
+                .last_seen
+                .get(&address)
+                .map_or(max_silence, |last_seen| now - *last_seen);
+            if silence >= max_silence {
+                self.evict(&address);
+            } else {
+                self.send_datagram(&Datagram::Ping(self.heartbeat_nonce), &address);
             }
         }
+
+        self.federation.expire(now, max_silence);
+        self.advertise();
+
+        for limits in self.sender_limits.iter_mut() {
+            limits.prune(now);
+        }
+        for limits in self.channel_limits.iter_mut() {
+            limits.prune(now);
+        }
+        if self.metrics != self.logged_metrics {
+            warn!(
+                "Dropped {} publishes from throttled senders, {} on throttled channels and {} \
+                 datagrams to subscribers too slow to keep up so far",
+                self.metrics.throttled_senders,
+                self.metrics.throttled_channels,
+                self.metrics.dropped_outbound
+            );
+            self.logged_metrics = self.metrics;
+        }
     }
 
-    fn handle_datagram_buffer(&mut self, buf: &mut [u8], address: SocketAddr) {
-        match str::from_utf8(buf) {
-            Ok(string) => self.handle_datagram_string(string, address),
+    fn handle_datagram_buffer(&mut self, buf: &[u8], address: Peer) {
+        match Datagram::decode(buf) {
+            Ok((datagram, encoding)) => {
+                match encoding {
+                    Encoding::Binary => self.binary_peers.insert(address),
+                    Encoding::Text => self.binary_peers.remove(&address),
+                };
+                match datagram {
+                    // Fragments get checked once they've been reassembled
+                    Datagram::Signed(_) | Datagram::Fragment(_) => {}
+                    // Peers are only trusted because of the address they send from
+                    _ if self.verifier.is_some() && !self.is_peer(&address) => {
+                        let error = auth::Error::Unsigned;
+                        warn!("Rejecting datagram from {}: {}", address, error);
+                        self.reject(ErrorCode::Unauthorized, error.to_string(), &address);
+                        return;
+                    }
+                    _ => {}
+                }
+                self.handle_datagram(datagram, address)
+            }
             Err(error) => {
-                error!("Error parsing buffer to UTF8: {}", error);
+                error!("Error parsing datagram from {}: {}", address, error);
+                // Let the sender know why we're ignoring them
+                self.reject(ErrorCode::from(&error), error.to_string(), &address);
+            }
+        }
+    }
+
+    fn retransmit(&mut self) {
+        for (address, sequence, datagram) in self.retransmits.due(Instant::now()) {
+            debug!("Retransmitting datagram {} to {}", sequence, address);
+            self.send_datagram(&datagram, &address);
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Received(address, buf) => {
+                self.last_seen.insert(address, Instant::now());
+                if let Peer::Udp(_) = address {
+                    if buf.len() > self.options.max_datagram_size {
+                        let limit = self.options.max_datagram_size;
+                        let message = format!("Datagrams are limited to {} bytes", limit);
+                        self.reject(ErrorCode::Oversize, message, &address);
+                        return;
+                    }
+                }
+                self.handle_datagram_buffer(&buf, address)
+            }
+            Event::Connected(address, connection) => {
+                info!("Accepted connection from {}", address);
+                if let Err(error) = connection.set_write_timeout(Some(self.options.write_timeout)) {
+                    error!("Error setting write timeout: {}", error);
+                }
+                self.outbox.connect(address, connection);
+            }
+            Event::Disconnected(address) => {
+                if self.outbox.contains(&address) {
+                    self.evict(&address);
+                }
+            }
+        }
+    }
+
+    // Keys and permissions are only replaced if their files load, and the rest
+    // of the new options are only used if they both do
+    fn reload(&mut self, mut options: Options) {
+        let keys = match &options.key_file {
+            Some(key_file) => match auth::load_keys(key_file) {
+                Ok(keys) => Some(keys),
+                Err(error) => {
+                    error!("Not reloading, error in {}: {}", key_file.display(), error);
+                    return;
+                }
+            },
+            None => None,
+        };
+        let acl = match &options.acl_file {
+            Some(acl_file) => match Acl::load(acl_file) {
+                Ok(acl) => acl,
+                Err(error) => {
+                    error!("Not reloading, error in {}: {}", acl_file.display(), error);
+                    return;
+                }
+            },
+            None => Acl::default(),
+        };
+        // Keeping the verifier and ACL keeps the nonces seen and bans issued
+        match (keys, self.verifier.as_mut()) {
+            (Some(keys), Some(verifier)) => verifier.set_keys(keys),
+            (keys, _) => self.verifier = keys.map(Verifier::new),
+        }
+        self.acl.set_rules(acl);
+        if options.write_timeout != self.options.write_timeout {
+            if let Err(error) = self.outbox.set_write_timeout(options.write_timeout) {
+                error!("Error setting write timeout: {}", error);
             }
         }
+
+        if options.sender_rate != self.options.sender_rate {
+            self.sender_limits = options.sender_rate.map(RateLimiter::new);
+        }
+        if options.channel_rate != self.options.channel_rate {
+            self.channel_limits = options.channel_rate.map(RateLimiter::new);
+        }
+        if options.max_message_size != self.options.max_message_size {
+            self.fragments = Reassembler::new(options.max_message_size);
+        }
+        if options.peers != self.options.peers {
+            let peers = options
+                .peers
+                .iter()
+                .map(|peer| net::reachable_from(&self.local_address, *peer))
+                .collect();
+            self.federation.set_peers(peers);
+        }
+        self.outbox.set_capacity(options.outbound_queue);
+
+        // These are fixed once the server is running
+        if options.data_dir != self.options.data_dir
+            || options.sender_threads != self.options.sender_threads
+            || options.max_datagram_size != self.options.max_datagram_size
+        {
+            warn!("Restart the server to change its data directory, send threads or datagram size");
+        }
+        options.data_dir = self.options.data_dir.take();
+        options.sender_threads = self.options.sender_threads;
+        options.max_datagram_size = self.options.max_datagram_size;
+        self.options = options;
+        info!("Reloaded options");
+        self.advertise();
     }
 
     fn handle_next(&mut self) {
-        let mut buf = [0; 1024];
-        match self.socket.recv_from(&mut buf) {
-            Ok((n, address)) => self.handle_datagram_buffer(&mut buf[..n], address),
-            Err(error) => {
-                error!("Error recieving next message: {}", error);
+        // Nothing can disconnect the channel while we hold a sender
+        if let Ok(event) = self.events.recv_timeout(TICK) {
+            self.handle_event(event);
+        }
+        while let Ok(options) = self.reloads.try_recv() {
+            self.reload(options);
+        }
+        self.fragments.expire(Instant::now());
+        self.retransmit();
+        self.heartbeat();
+        self.sync_log();
+    }
+
+    fn sync_log(&mut self) {
+        let now = Instant::now();
+        if now < self.next_log_sync {
+            return;
+        }
+        self.next_log_sync = now + LOG_SYNC_INTERVAL;
+        if let Some(log) = self.log.as_mut() {
+            if let Err(error) = log.sync() {
+                error!("Error syncing log: {}", error);
             }
         }
     }
 
-    pub fn run(mut self) -> ! {
-        loop {
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            stopping: self.stopping.clone(),
+            reloads: self.reload_sender.clone(),
+        }
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Handles whatever arrives until the deadline, or until the server is
+    /// asked to shut down
+    #[cfg(test)]
+    pub fn run_until(&mut self, deadline: Instant) {
+        while Instant::now() < deadline && !self.is_stopping() {
+            self.handle_next();
+        }
+    }
+
+    /// Runs until asked to shut down by a `ServerHandle`, then says goodbye to
+    /// everyone and saves what it needs to restart
+    pub fn run(mut self) -> Result<(), Error> {
+        while !self.is_stopping() {
             self.handle_next();
         }
+        self.shut_down()
+    }
+
+    fn shut_down(&mut self) -> Result<(), Error> {
+        info!("Shutting down");
+        let goodbye = Datagram::goodbye("Server is shutting down");
+        let everyone: Vec<Peer> = self.last_seen.keys().cloned().collect();
+        for address in &everyone {
+            self.send_datagram(&goodbye, address);
+        }
+        if !self.outbox.flush(SHUTDOWN_TIMEOUT) {
+            warn!("Gave up on sending everything before shutting down");
+        }
+        self.compact()
+    }
+}
+
+fn past_tense(action: ModerateAction) -> &'static str {
+    match action {
+        ModerateAction::Kick => "kicked",
+        ModerateAction::Ban => "banned",
+        ModerateAction::Unban => "unbanned",
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
     }
 }
 
 #[cfg(test)]
 mod server_tests {
     use super::*;
+    use crate::auth::Signer;
+    use crate::protocol::ErrorDatagram;
+    use crate::storage::storage_tests::test_directory;
     use crate::Client;
+    use std::iter::{once, FromIterator};
+    use std::net::{Ipv4Addr, Ipv6Addr};
     use std::thread;
+    use tungstenite::Message;
 
-    fn loopback(port: u16) -> SocketAddrV4 {
-        SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port)
+    fn loopback(port: u16) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)
     }
 
     fn test_server() -> (Server, SocketAddr) {
-        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)).unwrap();
+        test_server_with_options(Options::default())
+    }
+
+    fn test_server_with_options(options: Options) -> (Server, SocketAddr) {
+        let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)).unwrap();
 
         socket
             .set_read_timeout(Some(Duration::from_millis(100)))
             .unwrap();
 
         let address = socket.local_addr().unwrap();
-        let server = Server {
-            socket,
-            subscriptions: HashMap::new(),
-        };
+        let server = Server::from_socket(socket, options).unwrap();
 
         (server, address)
     }
 
+    fn rejection(code: ErrorCode, message: &str) -> ErrorDatagram {
+        ErrorDatagram {
+            code,
+            message: String::from(message),
+        }
+    }
+
     fn test_client(server_port: u16) -> Client {
-        Client::new(0, loopback(server_port)).unwrap()
+        Client::new(loopback(0), loopback(server_port)).unwrap()
     }
 
-    #[test]
-    fn basic_server() {
-        let (mut server, server_address) = test_server();
-        let server_port = server_address.port();
+    // Stands in for a lossy network between a single client and the server:
+    // every `drop_every`th datagram in each direction is silently discarded.
+    // Returns the port the client should use instead of the server's.
+    fn lossy_proxy(server_port: u16, drop_every: usize) -> u16 {
+        let idle_timeout = Some(Duration::from_secs(3));
+        let front = UdpSocket::bind(loopback(0)).unwrap();
+        let back = UdpSocket::bind(loopback(0)).unwrap();
+        front.set_read_timeout(idle_timeout).unwrap();
+        back.set_read_timeout(idle_timeout).unwrap();
+        let port = front.local_addr().unwrap().port();
 
-        let server_thread = thread::spawn(move || {
-            // handle a max of 10 events
-            for _ in 0..10 {
-                server.handle_next();
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let mut count = 0;
+            let mut downstream = None;
+            while let Ok((n, client_address)) = front.recv_from(&mut buf) {
+                // Start relaying replies once we know where the client is
+                if downstream.is_none() {
+                    let (front, back) = (front.try_clone().unwrap(), back.try_clone().unwrap());
+                    downstream = Some(thread::spawn(move || {
+                        let mut buf = [0; 1024];
+                        let mut count = 0;
+                        while let Ok(n) = back.recv(&mut buf) {
+                            count += 1;
+                            if count % drop_every != 0 {
+                                front.send_to(&buf[..n], client_address).unwrap();
+                            }
+                        }
+                    }));
+                }
+
+                count += 1;
+                if count % drop_every != 0 {
+                    back.send_to(&buf[..n], loopback(server_port)).unwrap();
+                }
             }
         });
 
+        port
+    }
+
+    #[test]
+    fn basic_server() {
+        let (mut server, server_address) = test_server();
+        let server_port = server_address.port();
+
+        let handle = server.handle();
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_secs(2));
+        });
+
+        let mut client_2 = test_client(server_port);
+        let client_2_name = client_2.local_addr().unwrap().to_string();
+
         let client_thread_1 = thread::spawn(move || {
-            let client_1 = test_client(server_port);
+            let mut client_1 = test_client(server_port);
             client_1.send(&Datagram::subscribe("testing123")).unwrap();
             client_1.send(&Datagram::subscribe("nope")).unwrap();
             client_1.send(&Datagram::unsubscribe("nope")).unwrap();
 
             assert_eq!(
                 client_1.listen(Some(Duration::from_millis(200))),
-                Some(Datagram::publish("testing123", "sender", "hi clients!"))
+                Some(Ok(Datagram::presence(
+                    "testing123",
+                    PresenceEvent::Joined,
+                    client_2_name
+                )))
+            );
+
+            assert_eq!(
+                client_1.listen(Some(Duration::from_millis(200))),
+                Some(Ok(Datagram::publish("testing123", "sender", "hi clients!")))
             );
 
             assert_eq!(client_1.listen(Some(Duration::from_millis(200))), None);
         });
 
         let client_thread_2 = thread::spawn(move || {
-            let client_2 = test_client(server_port);
+            // After client 1, so it's the one who hears about the other joining
+            thread::sleep(Duration::from_millis(50));
             client_2.send(&Datagram::subscribe("testing123")).unwrap();
             client_2.send(&Datagram::subscribe("client2")).unwrap();
 
             assert_eq!(
                 client_2.listen(Some(Duration::from_millis(200))),
-                Some(Datagram::publish("testing123", "sender", "hi clients!"))
+                Some(Ok(Datagram::publish("testing123", "sender", "hi clients!")))
             );
 
             assert_eq!(
                 client_2.listen(Some(Duration::from_millis(200))),
-                Some(Datagram::publish("client2", "sender", "hi client 2!"))
+                Some(Ok(Datagram::publish("client2", "sender", "hi client 2!")))
             );
 
             assert_eq!(client_2.listen(Some(Duration::from_millis(200))), None);
@@ -187,7 +1340,7 @@ mod server_tests {
         // zzzz
         thread::sleep(Duration::from_millis(100));
 
-        let sender = test_client(server_port);
+        let mut sender = test_client(server_port);
 
         sender
             .send(&Datagram::publish("testing123", "sender", "hi clients!"))
@@ -201,9 +1354,1432 @@ mod server_tests {
             .send(&Datagram::publish("nope", "sender", "bad!"))
             .unwrap();
 
-
-        server_thread.join().unwrap();
         client_thread_1.join().unwrap();
         client_thread_2.join().unwrap();
+        handle.shutdown();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn stops_delivering_reliably_after_last_unsubscribe() {
+        let (mut server, _) = test_server();
+        let peer = Peer::Udp(loopback(1234));
+        for (sequence, channel) in (1..).zip(&["first", "second"]) {
+            let subscribe = Datagram::reliable(sequence, Datagram::subscribe(*channel));
+            server.handle_datagram(subscribe, peer);
+        }
+        assert!(server.reliable_subscribers.contains(&peer));
+        let publisher = Peer::Udp(loopback(5678));
+        server.handle_datagram(Datagram::publish("first", "me", "hello"), publisher);
+
+        server.handle_datagram(Datagram::unsubscribe("first"), peer);
+        assert!(server.reliable_subscribers.contains(&peer));
+        server.handle_datagram(Datagram::unsubscribe("second"), peer);
+        assert!(!server.reliable_subscribers.contains(&peer));
+
+        // Resubscribing reliably carries on with the same sequence numbers
+        let sequence = server.outbound_sequences[&peer];
+        let subscribe = Datagram::reliable(3, Datagram::subscribe("first"));
+        server.handle_datagram(subscribe, peer);
+        server.handle_datagram(Datagram::publish("first", "me", "hello"), publisher);
+        assert!(server.outbound_sequences[&peer] > sequence);
+    }
+
+    #[test]
+    fn reliable_delivery_over_lossy_network() {
+        let (mut server, server_address) = test_server();
+        let server_port = server_address.port();
+        let messages: Vec<String> = (0..10).map(|n| format!("message {}", n)).collect();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_secs(4));
+        });
+
+        let expected = messages.clone();
+        let subscriber_thread = thread::spawn(move || {
+            let mut subscriber = test_client(lossy_proxy(server_port, 3));
+            subscriber
+                .send_reliable(&Datagram::subscribe("lossy"))
+                .unwrap();
+
+            let mut received = Vec::new();
+            while let Some(datagram) = subscriber.listen(Some(Duration::from_secs(2))) {
+                received.push(datagram.unwrap());
+            }
+
+            // Everything arrives exactly once, although not necessarily in order
+            let mut expected: Vec<Datagram> = expected
+                .iter()
+                .map(|message| Datagram::publish("lossy", "sender", message.as_str()))
+                .collect();
+            expected.sort_by_key(|d| d.serialize());
+            received.sort_by_key(|d| d.serialize());
+            assert_eq!(received, expected);
+        });
+
+        thread::sleep(Duration::from_millis(500));
+
+        let mut sender = test_client(lossy_proxy(server_port, 4));
+        for message in messages.iter() {
+            sender
+                .send_reliable(&Datagram::publish("lossy", "sender", message.as_str()))
+                .unwrap();
+        }
+        assert!(sender.flush(Duration::from_secs(3)));
+
+        subscriber_thread.join().unwrap();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn evicts_silent_subscribers() {
+        let (mut server, server_address) = test_server_with_options(Options {
+            heartbeat_interval: Duration::from_millis(100),
+            max_missed_heartbeats: 2,
+            ..Options::default()
+        });
+        let server_port = server_address.port();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(800));
+            server
+        });
+
+        let mut alive = test_client(server_port);
+        let mut silent = test_client(server_port);
+        alive.send(&Datagram::subscribe("heartbeat")).unwrap();
+        silent.send(&Datagram::subscribe("heartbeat")).unwrap();
+        let alive_address = Peer::Udp(loopback(alive.local_addr().unwrap().port()));
+        let silent_name = silent.local_addr().unwrap().to_string();
+
+        assert_eq!(
+            alive.listen(Some(Duration::from_millis(200))),
+            Some(Ok(Datagram::presence(
+                "heartbeat",
+                PresenceEvent::Joined,
+                silent_name.as_str()
+            )))
+        );
+        // Pings are answered inside listen, and never returned to us
+        assert_eq!(
+            alive.listen(Some(Duration::from_millis(600))),
+            Some(Ok(Datagram::presence(
+                "heartbeat",
+                PresenceEvent::Left,
+                silent_name
+            )))
+        );
+        assert_eq!(alive.listen(Some(Duration::from_millis(600))), None);
+
+        let server = server_thread.join().unwrap();
+        let subscribers = server.subscriptions.get("heartbeat").unwrap();
+        assert_eq!(subscribers, &HashSet::from_iter(once(alive_address)));
+        assert_eq!(server.last_seen.len(), 1);
+    }
+
+    #[test]
+    fn keeps_nick_holders_who_answer_pings() {
+        let (mut server, server_address) = test_server_with_options(Options {
+            heartbeat_interval: Duration::from_millis(100),
+            max_missed_heartbeats: 2,
+            ..Options::default()
+        });
+        let server_port = server_address.port();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(800));
+            server
+        });
+
+        let mut alive = test_client(server_port);
+        let mut silent = test_client(server_port);
+        alive.send(&Datagram::nick("ferris")).unwrap();
+        silent.send(&Datagram::nick("gopher")).unwrap();
+        // Pings are answered inside listen, and never returned to us
+        assert_eq!(alive.listen(Some(Duration::from_millis(800))), None);
+
+        let server = server_thread.join().unwrap();
+        assert!(server.nicks.owner("ferris").is_some());
+        assert!(server.nicks.owner("gopher").is_none());
+    }
+
+    #[test]
+    fn replays_history_before_live_traffic() {
+        let (mut server, server_address) = test_server_with_options(Options {
+            history_size: 2,
+            ..Options::default()
+        });
+        let server_port = server_address.port();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(1000));
+        });
+
+        let mut sender = test_client(server_port);
+        for n in 1..=3 {
+            let message = format!("message {}", n);
+            sender
+                .send(&Datagram::publish("history", "sender", message))
+                .unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let mut client = test_client(server_port);
+        client.send(&Datagram::history("history", 5)).unwrap();
+        client.send(&Datagram::subscribe("history")).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        sender
+            .send(&Datagram::publish("history", "sender", "message 4"))
+            .unwrap();
+
+        // Only the last two messages were kept
+        for n in 2..=4 {
+            assert_eq!(
+                client.listen(Some(Duration::from_millis(200))),
+                Some(Ok(Datagram::publish(
+                    "history",
+                    "sender",
+                    format!("message {}", n)
+                )))
+            );
+        }
+
+        client.send(&Datagram::history("history", 1)).unwrap();
+        assert_eq!(
+            client.listen(Some(Duration::from_millis(200))),
+            Some(Ok(Datagram::publish("history", "sender", "message 4")))
+        );
+        assert_eq!(client.listen(Some(Duration::from_millis(200))), None);
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn replies_in_the_senders_encoding() {
+        let (mut server, server_address) = test_server();
+        let server_port = server_address.port();
+
+        let handle = server.handle();
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_secs(2));
+        });
+
+        let mut text = test_client(server_port);
+        let mut binary = test_client(server_port);
+        binary.set_encoding(Encoding::Binary);
+        text.send(&Datagram::subscribe("encodings")).unwrap();
+        binary.send(&Datagram::subscribe("encodings")).unwrap();
+        assert_eq!(
+            text.listen(Some(Duration::from_millis(200))),
+            Some(Ok(Datagram::presence(
+                "encodings",
+                PresenceEvent::Joined,
+                binary.local_addr().unwrap().to_string()
+            )))
+        );
+
+        let publish = Datagram::publish("encodings", "c|d", "hello\nworld");
+        binary.send(&publish).unwrap();
+
+        assert_eq!(
+            binary.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish.clone()))
+        );
+        assert_eq!(
+            text.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish))
+        );
+
+        handle.shutdown();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn replies_to_invalid_datagrams() {
+        let (mut server, server_address) = test_server();
+        let server_port = server_address.port();
+
+        let handle = server.handle();
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_secs(2));
+            server
+        });
+
+        let mut client = test_client(server_port);
+        client.send(&Datagram::subscribe("not a channel")).unwrap();
+        assert_eq!(
+            client.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::Parse,
+                "Invalid channel: not a channel"
+            )))
+        );
+
+        client
+            .send(&Datagram::publish("rust_club", "", "hi"))
+            .unwrap();
+        assert_eq!(
+            client.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(ErrorCode::Parse, "Invalid display name: ")))
+        );
+
+        handle.shutdown();
+        let server = server_thread.join().unwrap();
+        assert!(server.subscriptions.is_empty());
+    }
+
+    #[test]
+    fn replies_with_error_codes() {
+        let (mut server, server_address) = test_server();
+        let server_port = server_address.port();
+
+        let handle = server.handle();
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_secs(2));
+        });
+
+        let socket = UdpSocket::bind(loopback(0)).unwrap();
+        socket.connect(loopback(server_port)).unwrap();
+        let mut reply = [0; MAX_DATAGRAM_SIZE];
+        let mut send_raw = |bytes: &[u8]| {
+            socket.send(bytes).unwrap();
+            let n = socket.recv(&mut reply).unwrap();
+            Datagram::decode(&reply[..n]).unwrap().0
+        };
+
+        let message = format!("P|rust_club|me|{}", "a".repeat(MAX_DATAGRAM_SIZE));
+        assert_eq!(
+            send_raw(message.as_bytes()),
+            Datagram::rejection(ErrorCode::Oversize, "Datagrams are limited to 1024 bytes")
+        );
+        assert_eq!(
+            send_raw(&[b'S', b'|', 0xC3, 0x28]),
+            Datagram::rejection(ErrorCode::Utf8, "Datagram is not UTF8")
+        );
+
+        let mut client = test_client(server_port);
+        client.send(&Datagram::unsubscribe("nowhere")).unwrap();
+        assert_eq!(
+            client.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::UnknownChannel,
+                "Not subscribed to: nowhere"
+            )))
+        );
+        client
+            .send(&Datagram::publish("nowhere", "me", "hello?"))
+            .unwrap();
+        assert_eq!(
+            client.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::UnknownChannel,
+                "No subscribers on: nowhere"
+            )))
+        );
+
+        handle.shutdown();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn enforces_registered_nicks() {
+        let (mut server, server_address) = test_server();
+        let server_port = server_address.port();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(800));
+        });
+
+        let mut ferris = test_client(server_port);
+        let mut other = test_client(server_port);
+        ferris.send(&Datagram::nick("ferris")).unwrap();
+        ferris.send(&Datagram::subscribe("rust_club")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        other.send(&Datagram::nick("Ferris")).unwrap();
+        assert_eq!(
+            other.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::NickInUse,
+                "Nick already in use: Ferris"
+            )))
+        );
+        other
+            .send(&Datagram::publish("rust_club", "ferris", "it's me!"))
+            .unwrap();
+        assert_eq!(
+            other.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::Forbidden,
+                "Nick belongs to someone else: ferris"
+            )))
+        );
+
+        ferris
+            .send(&Datagram::publish("rust_club", "crab", "hi"))
+            .unwrap();
+        assert_eq!(
+            ferris.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::Forbidden,
+                "You're registered as: ferris"
+            )))
+        );
+        let publish = Datagram::publish("rust_club", "ferris", "hi");
+        ferris.send(&publish).unwrap();
+        assert_eq!(
+            ferris.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish))
+        );
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn delivers_wildcard_subscriptions_once() {
+        let directory = test_directory();
+        std::fs::create_dir_all(&directory).unwrap();
+        let acl_file = directory.join("acl");
+        std::fs::write(&acl_file, "rust.secret read 10.9.9.9\n").unwrap();
+        let (mut server, server_address) = test_server_with_options(Options {
+            acl_file: Some(acl_file),
+            ..Options::default()
+        });
+        let server_port = server_address.port();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(800));
+            server
+        });
+
+        let mut overlapping = test_client(server_port);
+        let mut single = test_client(server_port);
+        for pattern in &["rust.club", "rust.*", "rust.#", "#"] {
+            overlapping.send(&Datagram::subscribe(*pattern)).unwrap();
+        }
+        single.send(&Datagram::subscribe("rust.*")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let publish = Datagram::publish("rust.club", "ferris", "hi");
+        overlapping.send(&publish).unwrap();
+        assert_eq!(
+            overlapping.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish.clone()))
+        );
+        assert_eq!(
+            single.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish))
+        );
+        assert_eq!(overlapping.listen(Some(Duration::from_millis(100))), None);
+
+        // Only `rust.#` and `#` match, and neither can read this one
+        let nested = Datagram::publish("rust.secret.plans", "ferris", "shh");
+        single.send(&nested).unwrap();
+        assert_eq!(
+            overlapping.listen(Some(Duration::from_millis(200))),
+            Some(Ok(nested))
+        );
+        assert_eq!(single.listen(Some(Duration::from_millis(100))), None);
+
+        single
+            .send(&Datagram::publish("rust.secret", "gopher", "shh"))
+            .unwrap();
+        assert_eq!(
+            single.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::UnknownChannel,
+                "No subscribers on: rust.secret"
+            )))
+        );
+
+        overlapping.send(&Datagram::unsubscribe("rust.*")).unwrap();
+        let server = server_thread.join().unwrap();
+        let mut patterns: Vec<String> = server
+            .subscriptions
+            .iter()
+            .map(|(pattern, _)| pattern)
+            .collect();
+        patterns.sort();
+        assert_eq!(patterns, vec!["#", "rust.#", "rust.*", "rust.club"]);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn lists_channels_and_members() {
+        let directory = test_directory();
+        std::fs::create_dir_all(&directory).unwrap();
+        let acl_file = directory.join("acl");
+        std::fs::write(&acl_file, "secret read 127.0.0.2\n").unwrap();
+        let (mut server, server_address) = test_server_with_options(Options {
+            acl_file: Some(acl_file),
+            ..Options::default()
+        });
+        let server_port = server_address.port();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(1000));
+        });
+
+        let ferris_address = SocketAddr::from((Ipv4Addr::new(127, 0, 0, 2), 0));
+        let mut ferris = Client::new(ferris_address, loopback(server_port)).unwrap();
+        let mut gopher = test_client(server_port);
+        let gopher_name = gopher.local_addr().unwrap().to_string();
+        ferris.send(&Datagram::nick("ferris")).unwrap();
+        for channel in &["rust.club", "secret"] {
+            ferris.send(&Datagram::subscribe(*channel)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+        for channel in &["rust.club", "go.club", "rust.#"] {
+            gopher.send(&Datagram::subscribe(*channel)).unwrap();
+        }
+
+        // Subscribing to a pattern isn't joining a channel
+        assert_eq!(
+            ferris.listen(Some(Duration::from_millis(200))),
+            Some(Ok(Datagram::presence(
+                "rust.club",
+                PresenceEvent::Joined,
+                gopher_name.as_str()
+            )))
+        );
+        assert_eq!(ferris.listen(Some(Duration::from_millis(100))), None);
+
+        // Nobody can see the channels they aren't allowed to read
+        gopher.send(&Datagram::list("#")).unwrap();
+        assert_eq!(
+            gopher.listen(Some(Duration::from_millis(200))),
+            Some(Ok(Datagram::ChannelList(ChannelListDatagram {
+                pattern: String::from("#"),
+                channels: vec![(String::from("go.club"), 1), (String::from("rust.club"), 2)],
+            })))
+        );
+        gopher.send(&Datagram::who("secret")).unwrap();
+        assert_eq!(
+            gopher.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::Forbidden,
+                "Not allowed to read: secret"
+            )))
+        );
+
+        ferris.send(&Datagram::who("rust.club")).unwrap();
+        assert_eq!(
+            ferris.listen(Some(Duration::from_millis(200))),
+            Some(Ok(Datagram::MemberList(MemberListDatagram {
+                channel: String::from("rust.club"),
+                members: vec![gopher_name.clone(), String::from("ferris")],
+            })))
+        );
+
+        gopher.send(&Datagram::unsubscribe("rust.club")).unwrap();
+        assert_eq!(
+            ferris.listen(Some(Duration::from_millis(200))),
+            Some(Ok(Datagram::presence(
+                "rust.club",
+                PresenceEvent::Left,
+                gopher_name
+            )))
+        );
+        ferris.send(&Datagram::list("rust.*")).unwrap();
+        assert_eq!(
+            ferris.listen(Some(Duration::from_millis(200))),
+            Some(Ok(Datagram::ChannelList(ChannelListDatagram {
+                pattern: String::from("rust.*"),
+                channels: vec![(String::from("rust.club"), 1)],
+            })))
+        );
+
+        server_thread.join().unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn forwards_publishes_between_linked_servers() {
+        // Every server is linked to both of the others, so each publish can
+        // reach a server more than one way
+        let sockets: Vec<UdpSocket> = (0..3)
+            .map(|_| UdpSocket::bind(loopback(0)).unwrap())
+            .collect();
+        let addresses: Vec<SocketAddr> = sockets
+            .iter()
+            .map(|socket| socket.local_addr().unwrap())
+            .collect();
+        let servers: Vec<Server> = sockets
+            .into_iter()
+            .map(|socket| {
+                let address = socket.local_addr().unwrap();
+                socket
+                    .set_read_timeout(Some(Duration::from_millis(100)))
+                    .unwrap();
+                let peers = addresses
+                    .iter()
+                    .cloned()
+                    .filter(|a| *a != address)
+                    .collect();
+                Server::from_socket(
+                    socket,
+                    Options {
+                        peers,
+                        ..Options::default()
+                    },
+                )
+                .unwrap()
+            })
+            .collect();
+        let server_threads: Vec<_> = servers
+            .into_iter()
+            .map(|mut server| {
+                thread::spawn(move || {
+                    server.run_until(Instant::now() + Duration::from_millis(1500));
+                })
+            })
+            .collect();
+
+        let mut a = test_client(addresses[0].port());
+        let mut b = test_client(addresses[1].port());
+        let mut c = test_client(addresses[2].port());
+        b.send(&Datagram::subscribe("federated")).unwrap();
+        c.send(&Datagram::subscribe("federated.#")).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let publish = Datagram::publish("federated", "me", "hello from A");
+        a.send(&publish).unwrap();
+        for client in &mut [&mut b, &mut c] {
+            assert_eq!(
+                client.listen(Some(Duration::from_millis(200))),
+                Some(Ok(publish.clone()))
+            );
+        }
+        for client in &mut [&mut a, &mut b, &mut c] {
+            assert_eq!(client.listen(Some(Duration::from_millis(200))), None);
+        }
+
+        let forward = Datagram::Forward(ForwardDatagram {
+            origin: 1,
+            id: 1,
+            publish: PublishDatagram {
+                channel: String::from("federated"),
+                display_name: String::from("me"),
+                message: String::from("spoofed"),
+            },
+        });
+        a.send(&forward).unwrap();
+        assert_eq!(
+            a.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::Forbidden,
+                "Only peers may forward publishes"
+            )))
+        );
+        assert_eq!(b.listen(Some(Duration::from_millis(100))), None);
+
+        for server_thread in server_threads {
+            server_thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn routes_direct_messages() {
+        let (mut server, server_address) = test_server();
+        let server_port = server_address.port();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(800));
+        });
+
+        let mut ferris = test_client(server_port);
+        let mut gopher = test_client(server_port);
+        let mut bystander = test_client(server_port);
+        ferris.send(&Datagram::nick("ferris")).unwrap();
+        gopher.send(&Datagram::nick("gopher")).unwrap();
+        bystander.send(&Datagram::subscribe("rust_club")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let direct = Datagram::direct("Ferris", "gopher", "psst");
+        gopher.send(&direct).unwrap();
+        assert_eq!(
+            ferris.listen(Some(Duration::from_millis(200))),
+            Some(Ok(direct))
+        );
+
+        gopher
+            .send(&Datagram::direct("corro", "gopher", "hello?"))
+            .unwrap();
+        assert_eq!(
+            gopher.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::UnknownNick,
+                "Nobody is called: corro"
+            )))
+        );
+
+        bystander
+            .send(&Datagram::direct("ferris", "gopher", "it's me!"))
+            .unwrap();
+        assert_eq!(
+            bystander.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::Forbidden,
+                "Nick belongs to someone else: gopher"
+            )))
+        );
+        assert_eq!(ferris.listen(Some(Duration::from_millis(100))), None);
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn enforces_acls_on_dual_stack_sockets() {
+        let directory = test_directory();
+        std::fs::create_dir_all(&directory).unwrap();
+        let acl_file = directory.join("acl");
+        std::fs::write(&acl_file, "secret_club read 127.0.0.1\n").unwrap();
+        // IPv4 clients show up as ::ffff:127.0.0.1 here
+        let socket = UdpSocket::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let server_port = socket.local_addr().unwrap().port();
+        let options = Options {
+            acl_file: Some(acl_file),
+            ..Options::default()
+        };
+        let mut server = Server::from_socket(socket, options).unwrap();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(500));
+        });
+
+        let mut client = test_client(server_port);
+        client.send(&Datagram::who("secret_club")).unwrap();
+        assert_eq!(
+            client.listen(Some(Duration::from_millis(200))),
+            Some(Ok(Datagram::MemberList(MemberListDatagram {
+                channel: String::from("secret_club"),
+                members: Vec::new(),
+            })))
+        );
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn enforces_channel_acls() {
+        let directory = test_directory();
+        std::fs::create_dir_all(&directory).unwrap();
+        let acl_file = directory.join("acl");
+        std::fs::write(
+            &acl_file,
+            "rust_club write 127.0.0.1\nrust_club moderate 127.0.0.2\nsecret_club read 127.0.0.1\n",
+        )
+        .unwrap();
+        let (mut server, server_address) = test_server_with_options(Options {
+            acl_file: Some(acl_file),
+            ..Options::default()
+        });
+        let server_port = server_address.port();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(1500));
+        });
+
+        // Rules only name addresses, so everyone needs their own
+        let client =
+            |ip: Ipv4Addr| Client::new(SocketAddr::from((ip, 0)), loopback(server_port)).unwrap();
+        let mut ferris = test_client(server_port);
+        let mut corro = client(Ipv4Addr::new(127, 0, 0, 2));
+        let mut lurker = client(Ipv4Addr::new(127, 0, 0, 3));
+        for (client, nick) in &mut [(&mut ferris, "ferris"), (&mut lurker, "lurker")] {
+            client.send(&Datagram::nick(*nick)).unwrap();
+            client.send(&Datagram::subscribe("rust_club")).unwrap();
+        }
+        corro.send(&Datagram::nick("corro")).unwrap();
+        assert_eq!(
+            ferris.listen(Some(Duration::from_millis(200))),
+            Some(Ok(Datagram::presence(
+                "rust_club",
+                PresenceEvent::Joined,
+                "lurker"
+            )))
+        );
+
+        // Denied publishes go nowhere, not even into the history
+        lurker
+            .send(&Datagram::publish("rust_club", "lurker", "first!"))
+            .unwrap();
+        assert_eq!(
+            lurker.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::Forbidden,
+                "Not allowed to write: rust_club"
+            )))
+        );
+        assert_eq!(ferris.listen(Some(Duration::from_millis(100))), None);
+
+        lurker.send(&Datagram::subscribe("secret_club")).unwrap();
+        assert_eq!(
+            lurker.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::Forbidden,
+                "Not allowed to read: secret_club"
+            )))
+        );
+
+        ferris
+            .send(&Datagram::moderate(
+                "rust_club",
+                ModerateAction::Ban,
+                "lurker",
+            ))
+            .unwrap();
+        assert_eq!(
+            ferris.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::Forbidden,
+                "Not a moderator of: rust_club"
+            )))
+        );
+
+        // Anyone could take the nick while lurker's away
+        corro
+            .send(&Datagram::moderate(
+                "rust_club",
+                ModerateAction::Ban,
+                "Lurker",
+            ))
+            .unwrap();
+        assert_eq!(
+            corro.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::Parse,
+                "Only IP addresses can be banned: Lurker"
+            )))
+        );
+
+        corro
+            .send(&Datagram::moderate(
+                "rust_club",
+                ModerateAction::Ban,
+                "127.0.0.3",
+            ))
+            .unwrap();
+        assert_eq!(
+            lurker.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::Forbidden,
+                "You were banned from: rust_club"
+            )))
+        );
+        assert_eq!(
+            ferris.listen(Some(Duration::from_millis(200))),
+            Some(Ok(Datagram::presence(
+                "rust_club",
+                PresenceEvent::Left,
+                "lurker"
+            )))
+        );
+        lurker.send(&Datagram::subscribe("rust_club")).unwrap();
+        assert_eq!(
+            lurker.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::Forbidden,
+                "Not allowed to read: rust_club"
+            )))
+        );
+
+        let publish = Datagram::publish("rust_club", "ferris", "hi");
+        ferris.send(&publish).unwrap();
+        assert_eq!(
+            ferris.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish))
+        );
+        assert_eq!(lurker.listen(Some(Duration::from_millis(100))), None);
+
+        server_thread.join().unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn throttles_floods() {
+        let (mut server, server_address) = test_server_with_options(Options {
+            sender_rate: Some(Rate {
+                per_second: 1,
+                burst: 3,
+            }),
+            channel_rate: Some(Rate {
+                per_second: 1,
+                burst: 2,
+            }),
+            ..Options::default()
+        });
+        let server_port = server_address.port();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(800));
+            server
+        });
+
+        let mut subscriber = test_client(server_port);
+        let mut flooder = test_client(server_port);
+        subscriber.send(&Datagram::subscribe("rust_club")).unwrap();
+        subscriber.send(&Datagram::subscribe("other_club")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let publishes = [
+            Datagram::publish("rust_club", "flood", "1"),
+            Datagram::publish("rust_club", "flood", "2"),
+            Datagram::publish("rust_club", "flood", "3"),
+            Datagram::publish("other_club", "flood", "4"),
+        ];
+        for publish in &publishes {
+            flooder.send(publish).unwrap();
+        }
+
+        assert_eq!(
+            flooder.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::RateLimited,
+                "Too many messages on: rust_club"
+            )))
+        );
+        assert_eq!(
+            flooder.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::RateLimited,
+                "Too many messages from: 127.0.0.1"
+            )))
+        );
+        for publish in &publishes[..2] {
+            assert_eq!(
+                subscriber.listen(Some(Duration::from_millis(200))),
+                Some(Ok(publish.clone()))
+            );
+        }
+        assert_eq!(subscriber.listen(Some(Duration::from_millis(100))), None);
+
+        let server = server_thread.join().unwrap();
+        assert_eq!(
+            server.metrics,
+            Metrics {
+                throttled_senders: 1,
+                throttled_channels: 1,
+                dropped_outbound: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn requires_signed_datagrams() {
+        let directory = test_directory();
+        std::fs::create_dir_all(&directory).unwrap();
+        let key_file = directory.join("keys");
+        std::fs::write(&key_file, "ferris:crab\n").unwrap();
+        let (mut server, server_address) = test_server_with_options(Options {
+            key_file: Some(key_file),
+            ..Options::default()
+        });
+        let server_port = server_address.port();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(800));
+        });
+
+        let mut unsigned = test_client(server_port);
+        unsigned.send(&Datagram::subscribe("rust_club")).unwrap();
+        assert_eq!(
+            unsigned.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::Unauthorized,
+                "Datagrams must be signed"
+            )))
+        );
+
+        let mut forger = test_client(server_port);
+        forger.set_signer(Signer::new("ferris", b"lobster".to_vec()));
+        forger.send(&Datagram::subscribe("rust_club")).unwrap();
+        assert_eq!(
+            forger.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(ErrorCode::Unauthorized, "Bad signature")))
+        );
+
+        // Signatures survive fragmentation and reliable delivery
+        let mut signed = test_client(server_port);
+        signed.set_signer(Signer::new("ferris", b"crab".to_vec()));
+        signed
+            .send_reliable(&Datagram::subscribe("rust_club"))
+            .unwrap();
+        assert!(signed.flush(Duration::from_millis(200)));
+        let publish = Datagram::publish("rust_club", "ferris", "a".repeat(3000));
+        signed.send(&publish).unwrap();
+        assert_eq!(
+            signed.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish))
+        );
+
+        // Anyone listening in can't send the same thing again
+        let socket = UdpSocket::bind(loopback(0)).unwrap();
+        socket.connect(loopback(server_port)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let ping =
+            Signer::new("ferris", b"crab".to_vec()).sign(&Datagram::Ping(1), SystemTime::now());
+        let mut reply = [0; MAX_DATAGRAM_SIZE];
+        socket.send(&ping.encode(Encoding::Text)).unwrap();
+        let n = socket.recv(&mut reply).unwrap();
+        assert_eq!(Datagram::decode(&reply[..n]).unwrap().0, Datagram::Pong(1));
+        socket.send(&ping.encode(Encoding::Text)).unwrap();
+        let n = socket.recv(&mut reply).unwrap();
+        match Datagram::decode(&reply[..n]).unwrap().0 {
+            Datagram::Error(error) => {
+                assert_eq!(error.code, ErrorCode::Unauthorized);
+                assert!(error.message.starts_with("Replayed nonce"));
+            }
+            datagram => panic!("Expected an error, got {:?}", datagram),
+        }
+
+        server_thread.join().unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn relays_encrypted_channels_unchanged() {
+        let (mut server, server_address) = test_server();
+        let server_port = server_address.port();
+
+        // Deriving keys takes a while, so get it out of the way first
+        let mut alice = test_client(server_port);
+        let mut bob = test_client(server_port);
+        let mut eve = test_client(server_port);
+        alice.encrypt_channel("secret_club", b"hunter2");
+        bob.encrypt_channel("secret_club", b"hunter2");
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(800));
+            server
+        });
+
+        for client in &mut [&mut alice, &mut bob, &mut eve] {
+            client.send(&Datagram::subscribe("secret_club")).unwrap();
+        }
+        assert_eq!(
+            bob.listen(Some(Duration::from_millis(200))),
+            Some(Ok(Datagram::presence(
+                "secret_club",
+                PresenceEvent::Joined,
+                eve.local_addr().unwrap().to_string()
+            )))
+        );
+
+        let publish = Datagram::publish("secret_club", "alice", "meet at noon");
+        alice.send_reliable(&publish).unwrap();
+        assert_eq!(
+            bob.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish))
+        );
+        let ciphertext = match eve.listen(Some(Duration::from_millis(200))) {
+            Some(Ok(Datagram::Publish(publish))) => publish.message,
+            reply => panic!("Expected a publish, got {:?}", reply),
+        };
+        assert!(!ciphertext.contains("noon"));
+
+        eve.send(&Datagram::publish(
+            "secret_club",
+            "alice",
+            "e2e1:not even hex",
+        ))
+        .unwrap();
+        match bob.listen(Some(Duration::from_millis(200))) {
+            Some(Err(error)) => assert_eq!(error.code, ErrorCode::Undecryptable),
+            reply => panic!("Expected an error, got {:?}", reply),
+        }
+
+        let server = server_thread.join().unwrap();
+        let history = server.history.get("secret_club").unwrap();
+        assert_eq!(history.entries().next().unwrap().1.message, ciphertext);
+    }
+
+    #[test]
+    fn fragments_large_messages() {
+        let (mut server, server_address) = test_server_with_options(Options {
+            max_message_size: 8 * 1024,
+            ..Options::default()
+        });
+        let server_port = server_address.port();
+
+        let handle = server.handle();
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_secs(2));
+        });
+
+        let mut text = test_client(server_port);
+        let mut binary = test_client(server_port);
+        binary.set_encoding(Encoding::Binary);
+        text.send(&Datagram::subscribe("large")).unwrap();
+        binary.send(&Datagram::subscribe("large")).unwrap();
+        assert_eq!(
+            text.listen(Some(Duration::from_millis(200))),
+            Some(Ok(Datagram::presence(
+                "large",
+                PresenceEvent::Joined,
+                binary.local_addr().unwrap().to_string()
+            )))
+        );
+
+        let publish = Datagram::publish("large", "me", "ünïcödé|".repeat(300));
+        binary.send(&publish).unwrap();
+        assert_eq!(
+            text.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish.clone()))
+        );
+        assert_eq!(
+            binary.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish))
+        );
+
+        text.send(&Datagram::publish("large", "me", "a".repeat(10 * 1024)))
+            .unwrap();
+        assert_eq!(
+            text.listen(Some(Duration::from_millis(200))),
+            Some(Err(rejection(
+                ErrorCode::Oversize,
+                "Messages are limited to 8192 bytes"
+            )))
+        );
+        assert_eq!(text.listen(Some(Duration::from_millis(200))), None);
+
+        handle.shutdown();
+        server_thread.join().unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn serves_ipv4_and_ipv6_clients() {
+        let address = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0);
+        let mut server = Server::new(address, Options::default()).unwrap();
+        let server_port = server.local_addr().port();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(500));
+        });
+
+        let ipv6_server = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), server_port);
+        let mut ipv6 = Client::new(net::unspecified_for(&ipv6_server, 0), ipv6_server).unwrap();
+        let mut ipv4 = test_client(server_port);
+        ipv6.send(&Datagram::subscribe("dual_stack")).unwrap();
+        ipv4.send(&Datagram::subscribe("dual_stack")).unwrap();
+        // Named by an IPv4-mapped address, which the client doesn't know
+        match ipv6.listen(Some(Duration::from_millis(200))) {
+            Some(Ok(Datagram::Presence(presence))) => {
+                assert_eq!(presence.event, PresenceEvent::Joined)
+            }
+            reply => panic!("Expected a presence notification, got {:?}", reply),
+        }
+
+        let publish = Datagram::publish("dual_stack", "me", "hello from ::1");
+        ipv6.send(&publish).unwrap();
+        assert_eq!(
+            ipv6.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish.clone()))
+        );
+        assert_eq!(
+            ipv4.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish))
+        );
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn fans_out_across_transports() {
+        let (mut server, server_address) = test_server();
+        let tcp_address = server.listen_tcp(loopback(0)).unwrap();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(800));
+            server
+        });
+
+        let mut tcp = Client::connect_tcp(tcp_address).unwrap();
+        let mut udp = test_client(server_address.port());
+        tcp.set_encoding(Encoding::Binary);
+        tcp.send(&Datagram::subscribe("transports")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        udp.send(&Datagram::subscribe("transports")).unwrap();
+        assert_eq!(
+            tcp.listen(Some(Duration::from_millis(200))),
+            Some(Ok(Datagram::presence(
+                "transports",
+                PresenceEvent::Joined,
+                udp.local_addr().unwrap().to_string()
+            )))
+        );
+
+        // Sent whole over TCP, but in fragments over UDP
+        let publish = Datagram::publish("transports", "tcp", "a".repeat(3000));
+        tcp.send(&publish).unwrap();
+        assert_eq!(
+            udp.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish.clone()))
+        );
+        assert_eq!(
+            tcp.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish))
+        );
+
+        let publish = Datagram::publish("transports", "udp", "hello over TCP");
+        udp.send(&publish).unwrap();
+        assert_eq!(
+            tcp.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish.clone()))
+        );
+        assert_eq!(
+            udp.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish))
+        );
+
+        // Closing the connection unsubscribes it straight away
+        drop(tcp);
+        let udp_address = Peer::Udp(loopback(udp.local_addr().unwrap().port()));
+        let server = server_thread.join().unwrap();
+        let subscribers = server.subscriptions.get("transports").unwrap();
+        assert_eq!(subscribers, &HashSet::from_iter(once(udp_address)));
+        assert_eq!(server.outbox.connections(), 0);
+    }
+
+    #[test]
+    fn slow_subscribers_only_hold_up_themselves() {
+        let (mut server, _) = test_server_with_options(Options {
+            outbound_queue: 8,
+            ..Options::default()
+        });
+        let tcp_address = server.listen_tcp(loopback(0)).unwrap();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_secs(3));
+            server
+        });
+
+        // Never reads anything, so once the socket buffers fill up every
+        // write to it blocks until it times out
+        let mut slow = Client::connect_tcp(tcp_address).unwrap();
+        slow.send(&Datagram::subscribe("slow")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let mut fast = Client::connect_tcp(tcp_address).unwrap();
+        fast.send(&Datagram::subscribe("slow")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let start = Instant::now();
+        for n in 0..300 {
+            let publish = Datagram::publish("slow", "fast", format!("{:040000}", n));
+            fast.send(&publish).unwrap();
+            assert_eq!(
+                fast.listen(Some(Duration::from_millis(500))),
+                Some(Ok(publish))
+            );
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let server = server_thread.join().unwrap();
+        assert!(server.metrics.dropped_outbound > 0);
+    }
+
+    #[test]
+    fn bridges_websockets_and_udp() {
+        let (mut server, server_address) = test_server();
+        let websocket_address = server.listen_websocket(loopback(0)).unwrap();
+
+        let server_thread = thread::spawn(move || {
+            server.run_until(Instant::now() + Duration::from_millis(800));
+            server
+        });
+
+        let url = format!("ws://{}", websocket_address);
+        let (mut browser, _) = tungstenite::connect(url).unwrap();
+        let mut udp = test_client(server_address.port());
+        browser.send(Message::Text("S|bridge".into())).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        udp.send(&Datagram::subscribe("bridge")).unwrap();
+        let joined = format!("J|bridge|joined|{}", udp.local_addr().unwrap());
+        assert_eq!(browser.read().unwrap(), Message::Text(joined));
+
+        udp.send(&Datagram::publish("bridge", "udp", "hello browser"))
+            .unwrap();
+        assert_eq!(
+            browser.read().unwrap(),
+            Message::Text("P|bridge|udp|hello browser".into())
+        );
+        assert!(udp.listen(Some(Duration::from_millis(200))).is_some());
+
+        // Binary messages get binary replies
+        let publish = Datagram::publish("bridge", "browser", "hello udp");
+        let encoded = publish.encode(Encoding::Binary);
+        browser.send(Message::Binary(encoded.clone())).unwrap();
+        assert_eq!(
+            udp.listen(Some(Duration::from_millis(200))),
+            Some(Ok(publish))
+        );
+        assert_eq!(browser.read().unwrap(), Message::Binary(encoded));
+
+        browser.close(None).unwrap();
+        let udp_address = Peer::Udp(loopback(udp.local_addr().unwrap().port()));
+        let server = server_thread.join().unwrap();
+        let subscribers = server.subscriptions.get("bridge").unwrap();
+        assert_eq!(subscribers, &HashSet::from_iter(once(udp_address)));
+        assert_eq!(server.outbox.connections(), 0);
+    }
+
+    #[test]
+    fn forgets_history_of_quiet_channels() {
+        let (mut server, _) = test_server_with_options(Options {
+            history_channels: 2,
+            ..Options::default()
+        });
+        let sender = Peer::Udp(loopback(1234));
+        for channel in &["first", "second", "first", "third"] {
+            let publish = Datagram::publish(*channel, "me", "hello");
+            server.handle_datagram(publish, sender);
+            // So every publish is at a different time
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut channels: Vec<&String> = server.history.keys().collect();
+        channels.sort();
+        assert_eq!(channels, vec!["first", "third"]);
+    }
+
+    #[test]
+    fn recovers_state_after_restart() {
+        let data_dir = test_directory();
+        let options = || Options {
+            data_dir: Some(data_dir.clone()),
+            compact_after: 4,
+            ..Options::default()
+        };
+        let (mut server, _) = test_server_with_options(options());
+        let subscriber = Peer::Udp(loopback(1234));
+        let other = Peer::Udp(loopback(5678));
+
+        server.handle_datagram(Datagram::subscribe("durable"), subscriber);
+        server.handle_datagram(Datagram::subscribe("durable"), other);
+        server.handle_datagram(Datagram::unsubscribe("durable"), other);
+        for n in 1..=3 {
+            let message = format!("message {}", n);
+            server.handle_datagram(Datagram::publish("durable", "me", message), other);
+        }
+        // Compaction has thrown away the subscribe and unsubscribe that cancel out
+        assert!(server.log.as_ref().unwrap().len() < 6);
+        drop(server);
+
+        let (server, _) = test_server_with_options(options());
+        let subscribers = server.subscriptions.get("durable").unwrap();
+        assert_eq!(subscribers, &HashSet::from_iter(once(subscriber)));
+
+        let history: Vec<(u64, &str)> = server.history["durable"]
+            .entries()
+            .map(|(sequence, d)| (*sequence, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            history,
+            vec![(1, "message 1"), (2, "message 2"), (3, "message 3")]
+        );
+    }
+
+    #[test]
+    fn compacts_after_appending_past_the_snapshot() {
+        let data_dir = test_directory();
+        let (mut server, _) = test_server_with_options(Options {
+            data_dir: Some(data_dir),
+            compact_after: 2,
+            ..Options::default()
+        });
+        let peers: Vec<Peer> = (1..=4).map(|port| Peer::Udp(loopback(port))).collect();
+        for peer in &peers {
+            server.handle_datagram(Datagram::subscribe("durable"), *peer);
+        }
+        // The second compaction leaves more records than compact_after
+        assert_eq!(server.snapshot_len, 4);
+        server.handle_datagram(Datagram::unsubscribe("durable"), peers[0]);
+        assert_eq!(server.log.as_ref().unwrap().len(), 5);
+        server.handle_datagram(Datagram::unsubscribe("durable"), peers[1]);
+        assert_eq!(server.log.as_ref().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reloads_options() {
+        let directory = test_directory();
+        std::fs::create_dir_all(&directory).unwrap();
+        let acl_file = directory.join("acl");
+        std::fs::write(&acl_file, "secret read 10.9.9.9\n").unwrap();
+        let options = || Options {
+            acl_file: Some(acl_file.clone()),
+            ..Options::default()
+        };
+        let (mut server, _) = test_server_with_options(options());
+        let gopher = Peer::Udp(loopback(1234));
+        assert!(!server.allows("secret", Permission::Read, &gopher));
+
+        server.acl.ban("lobby", Principal::Ip(gopher.ip()));
+        std::fs::write(&acl_file, "secret read 10.9.9.9 127.0.0.1\n").unwrap();
+        server.reload(Options {
+            channel_rate: Some(Rate {
+                per_second: 1,
+                burst: 1,
+            }),
+            ..options()
+        });
+        assert!(server.allows("secret", Permission::Read, &gopher));
+        assert!(server.channel_limits.is_some());
+        // Bans outlast reloads
+        assert!(!server.allows("lobby", Permission::Read, &gopher));
+
+        // Nothing changes if the new options don't all load
+        let handle = server.handle();
+        handle.reload(Options {
+            key_file: Some(directory.join("missing")),
+            ..Options::default()
+        });
+        server.run_until(Instant::now() + Duration::from_millis(100));
+        assert!(server.allows("secret", Permission::Read, &gopher));
+        assert!(server.verifier.is_none());
+        assert!(server.channel_limits.is_some());
+
+        handle.reload(Options::default());
+        server.run_until(Instant::now() + Duration::from_millis(100));
+        assert!(server.channel_limits.is_none());
+        assert!(server.options.acl_file.is_none());
+        assert!(!server.allows("lobby", Permission::Read, &gopher));
+    }
+
+    #[test]
+    fn says_goodbye_when_shut_down() {
+        let data_dir = test_directory();
+        let (server, server_address) = test_server_with_options(Options {
+            data_dir: Some(data_dir.clone()),
+            ..Options::default()
+        });
+        let handle = server.handle();
+        let server_thread = thread::spawn(move || server.run());
+
+        let mut subscriber = test_client(server_address.port());
+        let mut leaver = test_client(server_address.port());
+        subscriber.send(&Datagram::subscribe("farewell")).unwrap();
+        for _ in 0..2 {
+            leaver.send(&Datagram::subscribe("leaving")).unwrap();
+            leaver.send(&Datagram::unsubscribe("leaving")).unwrap();
+        }
+        // Saying goodbye first means the server won't say it back
+        leaver.send(&Datagram::goodbye("Bye")).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        handle.shutdown();
+        assert_eq!(
+            subscriber.listen(Some(Duration::from_millis(500))),
+            Some(Ok(Datagram::goodbye("Server is shutting down")))
+        );
+        assert_eq!(leaver.listen(Some(Duration::from_millis(100))), None);
+        server_thread.join().unwrap().unwrap();
+
+        // Only what's needed to restart is left in the log
+        let (_, records) = Log::open(&data_dir).unwrap();
+        assert_eq!(records.len(), 1);
+    }
+}
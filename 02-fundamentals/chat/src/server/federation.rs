@@ -0,0 +1,192 @@
+use crate::protocol::{matches_pattern, InterestDatagram};
+use crate::reliability::DuplicateFilter;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// What we've heard about another server in the federation
+struct Node {
+    version: u64,
+    patterns: Vec<String>,
+    // The peer that passed on its latest advert first, which is the quickest
+    // way to reach it
+    via: SocketAddr,
+    updated: Instant,
+}
+
+/// The other servers this one is linked to, and which patterns every server
+/// they've told us about has subscribers for. Publishes are only forwarded
+/// towards servers that want them, and each server numbers its own
+/// publishes so nobody handles one twice, even if the links form a loop.
+pub struct Federation {
+    id: u64,
+    peers: Vec<SocketAddr>,
+    version: u64,
+    nodes: HashMap<u64, Node>,
+    seen: DuplicateFilter<u64>,
+    next_message_id: u64,
+}
+
+impl Federation {
+    pub fn new(id: u64, peers: Vec<SocketAddr>) -> Self {
+        Federation {
+            id,
+            peers,
+            version: 0,
+            nodes: HashMap::new(),
+            seen: DuplicateFilter::new(),
+            next_message_id: 0,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn peers(&self) -> &[SocketAddr] {
+        &self.peers
+    }
+
+    /// Switches to a new set of peers, forgetting whatever we heard through
+    /// the ones that are gone
+    pub fn set_peers(&mut self, peers: Vec<SocketAddr>) {
+        self.nodes.retain(|_, node| peers.contains(&node.via));
+        self.peers = peers;
+    }
+
+    pub fn is_peer(&self, address: &SocketAddr) -> bool {
+        self.peers.contains(address)
+    }
+
+    /// A new advert of the patterns our own subscribers have
+    pub fn advertise(&mut self, patterns: Vec<String>) -> InterestDatagram {
+        self.version += 1;
+        InterestDatagram {
+            node: self.id,
+            version: self.version,
+            patterns,
+        }
+    }
+
+    /// Returns true if the advert is news, and should be passed on to our
+    /// other peers
+    pub fn learn(&mut self, interest: &InterestDatagram, from: SocketAddr, now: Instant) -> bool {
+        if interest.node == self.id {
+            return false;
+        }
+        if let Some(node) = self.nodes.get(&interest.node) {
+            if node.version >= interest.version {
+                return false;
+            }
+        }
+        let node = Node {
+            version: interest.version,
+            patterns: interest.patterns.clone(),
+            via: from,
+            updated: now,
+        };
+        self.nodes.insert(interest.node, node);
+        true
+    }
+
+    /// Numbers a publish made on this server
+    pub fn next_message_id(&mut self) -> u64 {
+        self.next_message_id += 1;
+        self.next_message_id
+    }
+
+    /// Returns false for publishes that have been handled already, including
+    /// our own coming back around
+    pub fn first_sighting(&mut self, origin: u64, id: u64) -> bool {
+        origin != self.id && self.seen.insert(origin, id)
+    }
+
+    /// The peers to forward a publish on `channel` to, so it reaches every
+    /// other server with a subscriber for it
+    pub fn routes(&self, channel: &str, except: Option<SocketAddr>) -> Vec<SocketAddr> {
+        let mut routes: Vec<SocketAddr> = self
+            .nodes
+            .values()
+            .filter(|node| node.patterns.iter().any(|p| matches_pattern(p, channel)))
+            .map(|node| node.via)
+            .filter(|via| Some(*via) != except)
+            .collect();
+        routes.sort();
+        routes.dedup();
+        routes
+    }
+
+    /// Forgets about servers we haven't heard from in a while
+    pub fn expire(&mut self, now: Instant, max_silence: Duration) {
+        let seen = &mut self.seen;
+        self.nodes.retain(|id, node| {
+            let alive = now.saturating_duration_since(node.updated) < max_silence;
+            if !alive {
+                seen.forget(id);
+            }
+            alive
+        });
+    }
+}
+
+#[cfg(test)]
+mod federation_tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn interest(node: u64, version: u64, patterns: &[&str]) -> InterestDatagram {
+        InterestDatagram {
+            node,
+            version,
+            patterns: patterns.iter().map(|p| String::from(*p)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_routes_towards_interested_servers() {
+        let now = Instant::now();
+        let mut federation = Federation::new(1, vec![peer(2), peer(3)]);
+        assert!(federation.learn(&interest(2, 1, &["rust.#"]), peer(2), now));
+        assert!(federation.learn(&interest(4, 1, &["rust.club", "go.*"]), peer(3), now));
+        // The same advert arriving the long way round doesn't change anything
+        assert!(!federation.learn(&interest(4, 1, &["rust.club"]), peer(2), now));
+        assert!(!federation.learn(&interest(1, 9, &["#"]), peer(2), now));
+
+        assert_eq!(federation.routes("rust.club", None), vec![peer(2), peer(3)]);
+        assert_eq!(federation.routes("rust.club", Some(peer(3))), vec![peer(2)]);
+        assert_eq!(federation.routes("go.club", None), vec![peer(3)]);
+        assert!(federation.routes("elsewhere", None).is_empty());
+
+        // Newer adverts replace older ones
+        assert!(federation.learn(&interest(4, 2, &[]), peer(2), now));
+        assert_eq!(federation.routes("go.club", None), vec![]);
+    }
+
+    #[test]
+    fn test_handles_each_publish_once() {
+        let mut federation = Federation::new(1, vec![peer(2)]);
+        assert_eq!(federation.next_message_id(), 1);
+        assert!(!federation.first_sighting(1, 1));
+        assert!(federation.first_sighting(2, 1));
+        assert!(!federation.first_sighting(2, 1));
+        assert!(federation.first_sighting(3, 1));
+    }
+
+    #[test]
+    fn test_expires_silent_servers() {
+        let now = Instant::now();
+        let mut federation = Federation::new(1, vec![peer(2)]);
+        federation.learn(&interest(2, 1, &["#"]), peer(2), now);
+        federation.learn(
+            &interest(3, 1, &["#"]),
+            peer(2),
+            now + Duration::from_secs(5),
+        );
+        federation.expire(now + Duration::from_secs(10), Duration::from_secs(8));
+        assert!(federation.learn(&interest(2, 1, &["#"]), peer(2), now));
+        assert!(!federation.learn(&interest(3, 1, &["#"]), peer(2), now));
+    }
+}
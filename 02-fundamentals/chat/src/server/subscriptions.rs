@@ -0,0 +1,271 @@
+use crate::protocol::{MULTI_LEVEL_WILDCARD, SINGLE_LEVEL_WILDCARD};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+// One level of the channel hierarchy, and everyone subscribed at or below it
+struct Node<K> {
+    // Subscribed to exactly the pattern that leads here
+    subscribers: HashSet<K>,
+    // Subscribed to this pattern followed by `#`
+    descendants: HashSet<K>,
+    // `*` is kept here too, since it can't be a level of a channel name
+    children: HashMap<String, Node<K>>,
+}
+
+impl<K> Node<K> {
+    fn new() -> Self {
+        Node {
+            subscribers: HashSet::new(),
+            descendants: HashSet::new(),
+            children: HashMap::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.subscribers.is_empty() && self.descendants.is_empty() && self.children.is_empty()
+    }
+}
+
+/// Who is subscribed to which channels, as a trie of the levels of each
+/// pattern, so matching a channel only visits the branches that could match
+pub struct Subscriptions<K: Eq + Hash + Clone> {
+    root: Node<K>,
+}
+
+fn split(pattern: &str) -> (Vec<&str>, bool) {
+    let mut levels: Vec<&str> = pattern.split('.').collect();
+    let descendants = levels.last() == Some(&MULTI_LEVEL_WILDCARD);
+    if descendants {
+        levels.pop();
+    }
+    (levels, descendants)
+}
+
+impl<K: Eq + Hash + Clone> Subscriptions<K> {
+    pub fn new() -> Self {
+        Subscriptions { root: Node::new() }
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.root.is_empty()
+    }
+
+    /// Returns false if they were already subscribed to the pattern
+    pub fn insert(&mut self, pattern: &str, subscriber: K) -> bool {
+        let (levels, descendants) = split(pattern);
+        let mut node = &mut self.root;
+        for level in levels {
+            node = node
+                .children
+                .entry(String::from(level))
+                .or_insert_with(Node::new);
+        }
+        if descendants {
+            node.descendants.insert(subscriber)
+        } else {
+            node.subscribers.insert(subscriber)
+        }
+    }
+
+    /// Returns false if they weren't subscribed to the pattern
+    pub fn remove(&mut self, pattern: &str, subscriber: &K) -> bool {
+        let (levels, descendants) = split(pattern);
+        remove(&mut self.root, &levels, descendants, subscriber)
+    }
+
+    /// Removes every subscription `subscriber` has, returning their patterns
+    pub fn remove_all(&mut self, subscriber: &K) -> Vec<String> {
+        let patterns: Vec<String> = self
+            .iter()
+            .filter(|(_, s)| *s == subscriber)
+            .map(|(pattern, _)| pattern)
+            .collect();
+        for pattern in &patterns {
+            self.remove(pattern, subscriber);
+        }
+        patterns
+    }
+
+    /// Who's subscribed to exactly this pattern
+    pub fn get(&self, pattern: &str) -> Option<&HashSet<K>> {
+        let (levels, descendants) = split(pattern);
+        let mut node = &self.root;
+        for level in levels {
+            node = node.children.get(level)?;
+        }
+        let subscribers = if descendants {
+            &node.descendants
+        } else {
+            &node.subscribers
+        };
+        Some(subscribers).filter(|subscribers| !subscribers.is_empty())
+    }
+
+    /// Everyone with a subscription matching `channel`, once each however
+    /// many of their patterns match
+    pub fn matching(&self, channel: &str) -> HashSet<K> {
+        let levels: Vec<&str> = channel.split('.').collect();
+        let mut matches = HashSet::new();
+        collect(&self.root, &levels, &mut matches);
+        matches
+    }
+
+    /// Every pattern and subscriber
+    pub fn iter(&self) -> impl Iterator<Item = (String, &K)> {
+        let mut entries = Vec::new();
+        walk(&self.root, &mut Vec::new(), &mut entries);
+        entries.into_iter()
+    }
+}
+
+fn remove<K: Eq + Hash>(node: &mut Node<K>, levels: &[&str], descendants: bool, key: &K) -> bool {
+    let (level, rest) = match levels.split_first() {
+        Some(split) => split,
+        None if descendants => return node.descendants.remove(key),
+        None => return node.subscribers.remove(key),
+    };
+    let child = match node.children.get_mut(*level) {
+        Some(child) => child,
+        None => return false,
+    };
+    let removed = remove(child, rest, descendants, key);
+    // Don't keep branches around for channels nobody's interested in any more
+    if child.is_empty() {
+        node.children.remove(*level);
+    }
+    removed
+}
+
+fn collect<K: Eq + Hash + Clone>(node: &Node<K>, levels: &[&str], matches: &mut HashSet<K>) {
+    // `#` matches the level it's on as well as everything below
+    matches.extend(node.descendants.iter().cloned());
+    let (level, rest) = match levels.split_first() {
+        Some(split) => split,
+        None => {
+            matches.extend(node.subscribers.iter().cloned());
+            return;
+        }
+    };
+    if let Some(child) = node.children.get(*level) {
+        collect(child, rest, matches);
+    }
+    if let Some(child) = node.children.get(SINGLE_LEVEL_WILDCARD) {
+        collect(child, rest, matches);
+    }
+}
+
+fn walk<'a, K>(node: &'a Node<K>, path: &mut Vec<&'a str>, entries: &mut Vec<(String, &'a K)>) {
+    let pattern = path.join(".");
+    for subscriber in &node.subscribers {
+        entries.push((pattern.clone(), subscriber));
+    }
+    for subscriber in &node.descendants {
+        let pattern = if path.is_empty() {
+            String::from(MULTI_LEVEL_WILDCARD)
+        } else {
+            format!("{}.{}", pattern, MULTI_LEVEL_WILDCARD)
+        };
+        entries.push((pattern, subscriber));
+    }
+    for (level, child) in &node.children {
+        path.push(level);
+        walk(child, path, entries);
+        path.pop();
+    }
+}
+
+#[cfg(test)]
+mod subscriptions_tests {
+    use super::*;
+
+    fn matching(subscriptions: &Subscriptions<&'static str>, channel: &str) -> Vec<&'static str> {
+        let mut matches: Vec<&str> = subscriptions.matching(channel).into_iter().collect();
+        matches.sort();
+        matches
+    }
+
+    #[test]
+    fn test_wildcards() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.insert("rust.club", "exact");
+        subscriptions.insert("rust.*", "single");
+        subscriptions.insert("rust.#", "multi");
+        subscriptions.insert("*.club", "suffix");
+        subscriptions.insert("#", "everything");
+
+        assert_eq!(
+            matching(&subscriptions, "rust.club"),
+            vec!["everything", "exact", "multi", "single", "suffix"]
+        );
+        assert_eq!(
+            matching(&subscriptions, "rust"),
+            vec!["everything", "multi"]
+        );
+        assert_eq!(
+            matching(&subscriptions, "rust.club.meetup"),
+            vec!["everything", "multi"]
+        );
+        assert_eq!(
+            matching(&subscriptions, "go.club"),
+            vec!["everything", "suffix"]
+        );
+        assert_eq!(matching(&subscriptions, "rust_club"), vec!["everything"]);
+    }
+
+    #[test]
+    fn test_overlapping_patterns_match_once() {
+        let mut subscriptions = Subscriptions::new();
+        for pattern in &["rust.club", "rust.*", "rust.#", "#", "*.*"] {
+            assert!(subscriptions.insert(pattern, "ferris"));
+        }
+        assert!(!subscriptions.insert("rust.*", "ferris"));
+        assert_eq!(matching(&subscriptions, "rust.club"), vec!["ferris"]);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.insert("rust.club", "ferris");
+        subscriptions.insert("rust.#", "ferris");
+        subscriptions.insert("rust.club", "gopher");
+
+        assert!(!subscriptions.remove("rust.*", &"ferris"));
+        assert!(subscriptions.remove("rust.#", &"ferris"));
+        assert!(!subscriptions.remove("rust.#", &"ferris"));
+        assert_eq!(
+            matching(&subscriptions, "rust.club"),
+            vec!["ferris", "gopher"]
+        );
+        assert_eq!(subscriptions.get("rust.#"), None);
+
+        let mut removed = subscriptions.remove_all(&"ferris");
+        removed.sort();
+        assert_eq!(removed, vec!["rust.club"]);
+        assert_eq!(matching(&subscriptions, "rust.club"), vec!["gopher"]);
+
+        subscriptions.remove("rust.club", &"gopher");
+        assert!(subscriptions.is_empty());
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.insert("rust.*", "ferris");
+        subscriptions.insert("#", "gopher");
+        subscriptions.insert("rust_club", "gopher");
+        let mut entries: Vec<(String, &str)> = subscriptions
+            .iter()
+            .map(|(pattern, subscriber)| (pattern, *subscriber))
+            .collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![
+                (String::from("#"), "gopher"),
+                (String::from("rust.*"), "ferris"),
+                (String::from("rust_club"), "gopher"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,291 @@
+use crate::protocol::PublishDatagram;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+const LOG_FILE: &str = "chat.log";
+const COMPACTION_FILE: &str = "chat.log.compact";
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Record {
+    Publish(u64, PublishDatagram),
+    Subscribe(String, SocketAddr),
+    Unsubscribe(String, SocketAddr),
+}
+
+impl Record {
+    fn parse(s: &str) -> Option<Self> {
+        let mut iter = s.splitn(3, '|');
+        match (iter.next(), iter.next(), iter.next()) {
+            (Some("P"), Some(sequence), Some(rest)) => Some(Record::Publish(
+                sequence.parse().ok()?,
+                PublishDatagram::parse(rest).ok()?,
+            )),
+            (Some("S"), Some(address), Some(channel)) => Some(Record::Subscribe(
+                String::from(channel),
+                address.parse().ok()?,
+            )),
+            (Some("U"), Some(address), Some(channel)) => Some(Record::Unsubscribe(
+                String::from(channel),
+                address.parse().ok()?,
+            )),
+            _ => None,
+        }
+    }
+
+    fn serialize(&self) -> String {
+        match self {
+            Record::Publish(sequence, d) => format!("P|{}|{}", sequence, d.serialize()),
+            Record::Subscribe(channel, address) => format!("S|{}|{}", address, channel),
+            Record::Unsubscribe(channel, address) => format!("U|{}|{}", address, channel),
+        }
+    }
+}
+
+// FNV-1a, which is plenty to spot a torn or garbled record
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+// Each record is stored as "$LENGTH $CHECKSUM $RECORD\n", so a record that
+// was only partly written before a crash can be detected and thrown away
+fn encode(record: &Record) -> Vec<u8> {
+    let payload = record.serialize();
+    let mut bytes = format!("{} {:08x} ", payload.len(), checksum(payload.as_bytes())).into_bytes();
+    bytes.extend_from_slice(payload.as_bytes());
+    bytes.push(b'\n');
+    bytes
+}
+
+// Returns the record at the start of the buffer and its encoded length
+fn decode(buf: &[u8]) -> Option<(Record, usize)> {
+    let mut fields = buf.splitn(3, |byte| *byte == b' ');
+    let length_field = fields.next()?;
+    let checksum_field = fields.next()?;
+    let length: usize = std::str::from_utf8(length_field).ok()?.parse().ok()?;
+    let expected = u32::from_str_radix(std::str::from_utf8(checksum_field).ok()?, 16).ok()?;
+
+    let start = length_field.len() + checksum_field.len() + 2;
+    let end = start + length;
+    if buf.len() <= end || buf[end] != b'\n' {
+        return None;
+    }
+
+    let payload = &buf[start..end];
+    if checksum(payload) != expected {
+        return None;
+    }
+    let record = Record::parse(std::str::from_utf8(payload).ok()?)?;
+    Some((record, end + 1))
+}
+
+/// An append-only log of everything the server needs to rebuild its state
+/// after a restart. Appends aren't synced to disk until `sync` is called, so
+/// a batch of them can share one sync.
+pub struct Log {
+    directory: PathBuf,
+    file: File,
+    records: usize,
+    // Appended to since the last sync
+    unsynced: bool,
+}
+
+impl Log {
+    /// Opens (or creates) the log in the given directory, returning every
+    /// record that was successfully written. Anything after the first bad
+    /// record is assumed to be from an interrupted write and is truncated.
+    pub fn open(directory: &Path) -> Result<(Self, Vec<Record>), Error> {
+        fs::create_dir_all(directory)?;
+        let path = directory.join(LOG_FILE);
+
+        let mut buf = Vec::new();
+        if path.exists() {
+            File::open(&path)?.read_to_end(&mut buf)?;
+        }
+
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while offset < buf.len() {
+            match decode(&buf[offset..]) {
+                Some((record, length)) => {
+                    records.push(record);
+                    offset += length;
+                }
+                None => {
+                    warn!(
+                        "Discarding {} bytes from the end of {}",
+                        buf.len() - offset,
+                        path.display()
+                    );
+                    break;
+                }
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.set_len(offset as u64)?;
+        file.sync_all()?;
+
+        let log = Log {
+            directory: directory.to_path_buf(),
+            file,
+            records: records.len(),
+            unsynced: false,
+        };
+        Ok((log, records))
+    }
+
+    pub fn append(&mut self, record: &Record) -> Result<(), Error> {
+        self.file.write_all(&encode(record))?;
+        self.records += 1;
+        self.unsynced = true;
+        Ok(())
+    }
+
+    /// Makes sure everything appended so far is on disk
+    pub fn sync(&mut self) -> Result<(), Error> {
+        if self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// Number of records in the log, including ones that no longer matter
+    pub fn len(&self) -> usize {
+        self.records
+    }
+
+    /// Replaces the log with a snapshot of the current state. The snapshot is
+    /// written to a separate file first, so a crash part way through leaves
+    /// the original log untouched.
+    pub fn compact(&mut self, records: &[Record]) -> Result<(), Error> {
+        let path = self.directory.join(LOG_FILE);
+        let compaction_path = self.directory.join(COMPACTION_FILE);
+
+        let mut compacted = File::create(&compaction_path)?;
+        for record in records {
+            compacted.write_all(&encode(record))?;
+        }
+        compacted.sync_all()?;
+        fs::rename(&compaction_path, &path)?;
+        if let Ok(directory) = File::open(&self.directory) {
+            // Not every platform lets us sync a directory, which is fine
+            directory.sync_all().ok();
+        }
+
+        self.file = OpenOptions::new().append(true).open(&path)?;
+        self.records = records.len();
+        self.unsynced = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod storage_tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_DIRECTORY: AtomicUsize = AtomicUsize::new(0);
+
+    // A fresh, empty directory for each test
+    pub fn test_directory() -> PathBuf {
+        let n = NEXT_DIRECTORY.fetch_add(1, Ordering::SeqCst);
+        let directory =
+            std::env::temp_dir().join(format!("chat-test-{}-{}", std::process::id(), n));
+        fs::remove_dir_all(&directory).ok();
+        directory
+    }
+
+    fn records() -> Vec<Record> {
+        let address = "127.0.0.1:1234".parse().unwrap();
+        vec![
+            Record::Subscribe(String::from("rust_club"), address),
+            Record::Publish(
+                1,
+                PublishDatagram {
+                    channel: String::from("rust_club"),
+                    display_name: String::from("me"),
+                    message: String::from("hello world! |||\n||| yo"),
+                },
+            ),
+            Record::Unsubscribe(String::from("rust_club"), address),
+        ]
+    }
+
+    #[test]
+    fn test_log_round_trip() {
+        let directory = test_directory();
+        let (mut log, recovered) = Log::open(&directory).unwrap();
+        assert!(recovered.is_empty());
+        for record in records() {
+            log.append(&record).unwrap();
+        }
+        assert!(log.unsynced);
+        log.sync().unwrap();
+        assert!(!log.unsynced);
+
+        let (log, recovered) = Log::open(&directory).unwrap();
+        assert_eq!(recovered, records());
+        assert_eq!(log.len(), 3);
+    }
+
+    #[test]
+    fn test_log_discards_torn_writes() {
+        let directory = test_directory();
+        let (mut log, _) = Log::open(&directory).unwrap();
+        for record in records() {
+            log.append(&record).unwrap();
+        }
+
+        // Simulate a crash part way through writing the last record
+        let path = directory.join(LOG_FILE);
+        let length = fs::metadata(&path).unwrap().len();
+        log.file.set_len(length - 3).unwrap();
+
+        let (mut log, recovered) = Log::open(&directory).unwrap();
+        assert_eq!(recovered, &records()[..2]);
+
+        // New records go after the last good one
+        log.append(&records()[2]).unwrap();
+        let (_, recovered) = Log::open(&directory).unwrap();
+        assert_eq!(recovered, records());
+    }
+
+    #[test]
+    fn test_log_discards_corrupt_records() {
+        let directory = test_directory();
+        let (mut log, _) = Log::open(&directory).unwrap();
+        for record in records() {
+            log.append(&record).unwrap();
+        }
+
+        let path = directory.join(LOG_FILE);
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::write(&path, contents.replace("hello", "jello")).unwrap();
+
+        let (_, recovered) = Log::open(&directory).unwrap();
+        assert_eq!(recovered, &records()[..1]);
+    }
+
+    #[test]
+    fn test_log_compaction() {
+        let directory = test_directory();
+        let (mut log, _) = Log::open(&directory).unwrap();
+        for record in records() {
+            log.append(&record).unwrap();
+        }
+
+        log.compact(&records()[1..2]).unwrap();
+        assert_eq!(log.len(), 1);
+        log.append(&records()[0]).unwrap();
+
+        let (log, recovered) = Log::open(&directory).unwrap();
+        assert_eq!(recovered, vec![records()[1].clone(), records()[0].clone()]);
+        assert_eq!(log.len(), 2);
+        assert!(!directory.join(COMPACTION_FILE).exists());
+    }
+}
@@ -0,0 +1,301 @@
+mod outbox;
+mod websocket;
+
+pub use self::outbox::Outbox;
+pub use self::websocket::spawn_websocket_listener;
+
+use crate::protocol::{Datagram, Encoding, MAX_DATAGRAM_SIZE};
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Someone the server talks to, and how it reaches them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Peer {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    WebSocket(SocketAddr),
+}
+
+impl Peer {
+    pub fn address(&self) -> SocketAddr {
+        match self {
+            Peer::Udp(address) | Peer::Tcp(address) | Peer::WebSocket(address) => *address,
+        }
+    }
+
+    /// Their IP address, as IPv4 if a dual-stack socket saw it mapped into IPv6
+    pub fn ip(&self) -> IpAddr {
+        self.address().ip().to_canonical()
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Udp(address) => write!(f, "udp://{}", address),
+            Peer::Tcp(address) => write!(f, "tcp://{}", address),
+            Peer::WebSocket(address) => write!(f, "ws://{}", address),
+        }
+    }
+}
+
+/// Everything the reader threads pass on to the server
+pub enum Event {
+    Received(Peer, Vec<u8>),
+    // The connection is for writing replies, while a reader thread owns a clone
+    Connected(Peer, Connection),
+    Disconnected(Peer),
+}
+
+/// The writing half of a connection to a peer
+pub enum Connection {
+    Tcp(TcpStream),
+    WebSocket(Box<websocket::Writer>),
+}
+
+impl Connection {
+    pub fn send(&mut self, datagram: &Datagram, encoding: Encoding) -> Result<(), Error> {
+        match self {
+            Connection::Tcp(stream) => write_frame(stream, &datagram.encode(encoding)),
+            Connection::WebSocket(writer) => writer.send(datagram, encoding),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        match self {
+            Connection::Tcp(stream) => stream.set_write_timeout(timeout),
+            Connection::WebSocket(writer) => writer.set_write_timeout(timeout),
+        }
+    }
+
+    /// Closes the connection, which stops its reader thread too
+    pub fn close(self) {
+        match self {
+            Connection::Tcp(stream) => {
+                stream.shutdown(Shutdown::Both).ok();
+            }
+            Connection::WebSocket(writer) => writer.close(),
+        }
+    }
+}
+
+// Each frame on a stream is a big endian u32 length, then an encoded datagram
+pub fn write_frame<W: Write>(writer: &mut W, buf: &[u8]) -> Result<(), Error> {
+    let mut frame = Vec::with_capacity(buf.len() + 4);
+    frame.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+    frame.extend_from_slice(buf);
+    writer.write_all(&frame)
+}
+
+/// Collects bytes from a stream until a whole frame has arrived, so reads can
+/// time out part way through a frame without losing track of where it ends.
+pub struct FrameReader {
+    buf: Vec<u8>,
+    max_frame_size: usize,
+}
+
+impl FrameReader {
+    pub fn new(max_frame_size: usize) -> Self {
+        FrameReader {
+            buf: Vec::new(),
+            max_frame_size,
+        }
+    }
+
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if self.buf.len() < 4 {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes(self.buf[..4].try_into().unwrap()) as usize;
+        if length > self.max_frame_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Frames are limited to {} bytes", self.max_frame_size),
+            ));
+        }
+        if self.buf.len() < length + 4 {
+            return Ok(None);
+        }
+        let frame = self.buf[4..length + 4].to_vec();
+        self.buf.drain(..length + 4);
+        Ok(Some(frame))
+    }
+
+    pub fn read<R: Read>(&mut self, reader: &mut R) -> Result<Vec<u8>, Error> {
+        let mut chunk = [0; MAX_DATAGRAM_SIZE];
+        loop {
+            if let Some(frame) = self.next_frame()? {
+                return Ok(frame);
+            }
+            match reader.read(&mut chunk)? {
+                0 => return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed")),
+                n => self.buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+}
+
+/// Passes on everything that arrives on the socket until `running` is
+/// cleared, reading datagrams of up to `max_size` bytes. The socket needs a
+/// read timeout, so the flag gets checked.
+pub fn spawn_udp_reader(
+    socket: UdpSocket,
+    max_size: usize,
+    events: Sender<Event>,
+    running: Arc<AtomicBool>,
+) {
+    thread::spawn(move || {
+        // One byte extra, so the server can tell when a datagram has been truncated
+        let mut buf = vec![0; max_size + 1];
+        while running.load(Ordering::SeqCst) {
+            match socket.recv_from(&mut buf) {
+                Ok((n, address)) => {
+                    let event = Event::Received(Peer::Udp(address), buf[..n].to_vec());
+                    if events.send(event).is_err() {
+                        return;
+                    }
+                }
+                Err(ref error)
+                    if error.kind() == ErrorKind::WouldBlock
+                        || error.kind() == ErrorKind::TimedOut => {}
+                Err(error) => error!("Error recieving next message: {}", error),
+            }
+        }
+    });
+}
+
+/// Accepts connections, with a reader thread for each one
+pub fn spawn_tcp_listener(listener: TcpListener, events: Sender<Event>, max_frame_size: usize) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    error!("Error accepting connection: {}", error);
+                    continue;
+                }
+            };
+            let (address, reader) = match stream
+                .peer_addr()
+                .and_then(|a| Ok((a, stream.try_clone()?)))
+            {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    error!("Error accepting connection: {}", error);
+                    continue;
+                }
+            };
+            let connection = Connection::Tcp(stream);
+            if events
+                .send(Event::Connected(Peer::Tcp(address), connection))
+                .is_err()
+            {
+                return;
+            }
+            spawn_tcp_reader(address, reader, events.clone(), max_frame_size);
+        }
+    });
+}
+
+fn spawn_tcp_reader(
+    address: SocketAddr,
+    mut stream: TcpStream,
+    events: Sender<Event>,
+    max_frame_size: usize,
+) {
+    thread::spawn(move || {
+        let mut frames = FrameReader::new(max_frame_size);
+        loop {
+            match frames.read(&mut stream) {
+                Ok(frame) => {
+                    if events
+                        .send(Event::Received(Peer::Tcp(address), frame))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Err(error) => {
+                    if error.kind() != ErrorKind::UnexpectedEof {
+                        warn!("Closing connection from {}: {}", address, error);
+                    }
+                    events.send(Event::Disconnected(Peer::Tcp(address))).ok();
+                    return;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod transport_tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Hands out the bytes it was given a few at a time, then times out
+    struct Trickle {
+        bytes: Vec<u8>,
+        offset: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            if self.offset == self.bytes.len() {
+                return Err(Error::new(ErrorKind::WouldBlock, "Nothing to read"));
+            }
+            let n = buf.len().min(3).min(self.bytes.len() - self.offset);
+            buf[..n].copy_from_slice(&self.bytes[self.offset..self.offset + n]);
+            self.offset += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_frame_round_trip() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, b"S|rust_club").unwrap();
+        write_frame(&mut bytes, b"").unwrap();
+        write_frame(&mut bytes, b"P|rust_club|me|hi").unwrap();
+
+        let mut frames = FrameReader::new(1024);
+        let mut reader = Cursor::new(bytes);
+        assert_eq!(frames.read(&mut reader).unwrap(), b"S|rust_club");
+        assert_eq!(frames.read(&mut reader).unwrap(), b"");
+        assert_eq!(frames.read(&mut reader).unwrap(), b"P|rust_club|me|hi");
+        let error = frames.read(&mut reader).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_frames_survive_timeouts() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, b"S|rust_club").unwrap();
+        let mut reader = Trickle {
+            bytes: bytes[..6].to_vec(),
+            offset: 0,
+        };
+
+        let mut frames = FrameReader::new(1024);
+        let error = frames.read(&mut reader).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::WouldBlock);
+
+        reader.bytes = bytes;
+        assert_eq!(frames.read(&mut reader).unwrap(), b"S|rust_club");
+    }
+
+    #[test]
+    fn test_frames_are_limited() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &[b'a'; 11]).unwrap();
+        let mut frames = FrameReader::new(10);
+        let error = frames.read(&mut Cursor::new(bytes)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
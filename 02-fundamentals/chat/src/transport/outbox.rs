@@ -0,0 +1,401 @@
+use super::{Connection, Peer};
+use crate::fragment;
+use crate::protocol::{Datagram, Encoding};
+use std::collections::{HashMap, VecDeque};
+use std::io::Error;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Everything waiting to go to one peer
+struct Queue {
+    datagrams: VecDeque<(Datagram, Encoding)>,
+    // Taken by whichever sender thread is writing to it
+    connection: Option<Connection>,
+    // A sender thread is writing to this peer, so nobody else may
+    busy: bool,
+    // Waiting in line for a sender thread
+    ready: bool,
+    // Tells this queue apart from any the peer had before it was closed
+    generation: u64,
+}
+
+impl Queue {
+    fn new(connection: Option<Connection>, generation: u64) -> Self {
+        Queue {
+            datagrams: VecDeque::new(),
+            connection,
+            busy: false,
+            ready: false,
+            generation,
+        }
+    }
+}
+
+struct State {
+    queues: HashMap<Peer, Queue>,
+    // Peers with something queued and nobody writing to them, in the order
+    // they're next in line
+    ready: VecDeque<Peer>,
+    next_generation: u64,
+    running: bool,
+}
+
+impl State {
+    fn open(&mut self, peer: Peer, connection: Option<Connection>) -> Option<Queue> {
+        self.next_generation += 1;
+        let queue = Queue::new(connection, self.next_generation);
+        self.queues.insert(peer, queue)
+    }
+
+    // Puts the peer in line for a sender thread, unless it's already in line
+    // or being written to, returning whether it was
+    fn make_ready(&mut self, peer: Peer) -> bool {
+        match self.queues.get_mut(&peer) {
+            Some(queue) if !queue.ready && !queue.busy && !queue.datagrams.is_empty() => {
+                queue.ready = true;
+                self.ready.push_back(peer);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// A datagram a sender thread has taken off a peer's queue
+struct Sending {
+    peer: Peer,
+    datagram: Datagram,
+    encoding: Encoding,
+    connection: Option<Connection>,
+    generation: u64,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    ready: Condvar,
+    // Signalled whenever a peer's queue empties
+    drained: Condvar,
+    socket: UdpSocket,
+    capacity: AtomicUsize,
+    next_fragment_id: AtomicU64,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // Sender threads never panic while holding the lock
+        self.state.lock().unwrap()
+    }
+}
+
+/// A bounded queue of datagrams for each peer, and the threads that send
+/// them, so that a slow peer only holds up itself. Each peer is written to by
+/// one thread at a time, so its datagrams go out in order, and peers take
+/// turns so nobody hogs the threads.
+pub struct Outbox {
+    shared: Arc<Shared>,
+}
+
+impl Outbox {
+    pub fn new(socket: UdpSocket, capacity: usize, threads: usize) -> Self {
+        let outbox = Outbox::idle(socket, capacity);
+        for _ in 0..threads.max(1) {
+            let shared = outbox.shared.clone();
+            thread::spawn(move || send_until_stopped(&shared));
+        }
+        outbox
+    }
+
+    // Without any sender threads, so nothing gets sent
+    fn idle(socket: UdpSocket, capacity: usize) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queues: HashMap::new(),
+                ready: VecDeque::new(),
+                next_generation: 0,
+                running: true,
+            }),
+            ready: Condvar::new(),
+            drained: Condvar::new(),
+            socket,
+            capacity: AtomicUsize::new(capacity),
+            next_fragment_id: AtomicU64::new(0),
+        });
+        Outbox { shared }
+    }
+
+    /// Changes how many datagrams may wait for each peer. Anything already
+    /// queued beyond that is still sent.
+    pub fn set_capacity(&self, capacity: usize) {
+        self.shared.capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    /// Changes how long sending a UDP datagram may block
+    pub fn set_write_timeout(&self, timeout: Duration) -> Result<(), Error> {
+        self.shared.socket.set_write_timeout(Some(timeout))
+    }
+
+    /// Datagrams for connected peers go out on their connection
+    pub fn connect(&self, peer: Peer, connection: Connection) {
+        let mut state = self.shared.lock();
+        if let Some(queue) = state.open(peer, Some(connection)) {
+            if let Some(connection) = queue.connection {
+                connection.close();
+            }
+        }
+    }
+
+    /// Queues a datagram for a peer, returning false if their queue is
+    /// already full, so it was dropped instead
+    pub fn push(&self, peer: Peer, datagram: Datagram, encoding: Encoding) -> bool {
+        let mut state = self.shared.lock();
+        if !state.queues.contains_key(&peer) {
+            match peer {
+                Peer::Udp(_) => {
+                    state.open(peer, None);
+                }
+                // Nowhere to send it, since they've disconnected
+                _ => return true,
+            }
+        }
+        let queue = state.queues.get_mut(&peer).unwrap();
+        if queue.datagrams.len() >= self.shared.capacity.load(Ordering::Relaxed) {
+            return false;
+        }
+        queue.datagrams.push_back((datagram, encoding));
+        if state.make_ready(peer) {
+            self.shared.ready.notify_one();
+        }
+        true
+    }
+
+    /// Whether the peer is connected, or has anything waiting to be sent
+    pub fn contains(&self, peer: &Peer) -> bool {
+        self.shared.lock().queues.contains_key(peer)
+    }
+
+    /// Drops anything still queued for the peer, and closes their connection
+    pub fn close(&self, peer: &Peer) {
+        let mut state = self.shared.lock();
+        let connection = state.queues.remove(peer).and_then(|queue| queue.connection);
+        state.ready.retain(|ready| ready != peer);
+        // If a sender thread has the connection, it closes it when it's done,
+        // and leaves alone any queue the peer has by then
+        if let Some(connection) = connection {
+            connection.close();
+        }
+    }
+
+    /// Waits for everything queued so far to be sent, returning false if
+    /// that took longer than the timeout
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.lock();
+        loop {
+            let pending = state
+                .queues
+                .values()
+                .any(|queue| queue.busy || !queue.datagrams.is_empty());
+            let now = Instant::now();
+            if !pending {
+                return true;
+            }
+            if now >= deadline {
+                return false;
+            }
+            state = self
+                .shared
+                .drained
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn connections(&self) -> usize {
+        let state = self.shared.lock();
+        state
+            .queues
+            .iter()
+            .filter(|(peer, _)| !matches!(peer, Peer::Udp(_)))
+            .count()
+    }
+}
+
+impl Drop for Outbox {
+    // The sender threads finish off whatever's already queued
+    fn drop(&mut self) {
+        self.shared.lock().running = false;
+        self.shared.ready.notify_all();
+    }
+}
+
+fn send_until_stopped(shared: &Shared) {
+    while let Some(mut sending) = take(shared) {
+        let result = match (sending.peer, sending.connection.as_mut()) {
+            (Peer::Udp(address), _) => {
+                let id = shared.next_fragment_id.fetch_add(1, Ordering::Relaxed);
+                fragment::encode(&sending.datagram, sending.encoding, id)
+                    .iter()
+                    .try_for_each(|buf| shared.socket.send_to(buf, address).map(|_| ()))
+            }
+            (_, Some(connection)) => connection.send(&sending.datagram, sending.encoding),
+            (_, None) => Ok(()),
+        };
+        if let Err(error) = result {
+            error!("Error sending datagram to {}: {}", sending.peer, error);
+        }
+        finish(shared, sending);
+    }
+}
+
+// Waits for the next peer in line, and takes their next datagram, or returns
+// None once the outbox is dropped and there's nothing left to send
+fn take(shared: &Shared) -> Option<Sending> {
+    let mut state = shared.lock();
+    loop {
+        if let Some(peer) = state.ready.pop_front() {
+            let queue = match state.queues.get_mut(&peer) {
+                Some(queue) if queue.ready => queue,
+                // Closed since it got in line
+                _ => continue,
+            };
+            queue.ready = false;
+            if let Some((datagram, encoding)) = queue.datagrams.pop_front() {
+                queue.busy = true;
+                return Some(Sending {
+                    peer,
+                    datagram,
+                    encoding,
+                    connection: queue.connection.take(),
+                    generation: queue.generation,
+                });
+            }
+            continue;
+        }
+        if !state.running {
+            return None;
+        }
+        state = shared.ready.wait(state).unwrap();
+    }
+}
+
+fn finish(shared: &Shared, sending: Sending) {
+    let mut state = shared.lock();
+    let peer = sending.peer;
+    let queue = match state.queues.get_mut(&peer) {
+        Some(queue) if queue.generation == sending.generation => queue,
+        // Closed while we were sending, and maybe opened again since
+        _ => {
+            if let Some(connection) = sending.connection {
+                connection.close();
+            }
+            shared.drained.notify_all();
+            return;
+        }
+    };
+    queue.busy = false;
+    queue.connection = sending.connection;
+    if state.make_ready(peer) {
+        shared.ready.notify_one();
+        return;
+    }
+    if let Peer::Udp(_) = peer {
+        // Only connections need remembering while there's nothing to send
+        state.queues.remove(&peer);
+    }
+    shared.drained.notify_all();
+}
+
+#[cfg(test)]
+mod outbox_tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    fn socket() -> UdpSocket {
+        let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        socket
+    }
+
+    #[test]
+    fn test_sends_in_order() {
+        let outbox = Outbox::new(socket(), 100, 4);
+        let receiver = socket();
+        let peer = Peer::Udp(receiver.local_addr().unwrap());
+        for n in 0..50 {
+            assert!(outbox.push(peer, Datagram::Ping(n), Encoding::Text));
+        }
+        let mut buf = [0; 64];
+        for n in 0..50 {
+            let length = receiver.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..length], format!("I|{}", n).as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_flush() {
+        let receiver = socket();
+        let peer = Peer::Udp(receiver.local_addr().unwrap());
+        let idle = Outbox::idle(socket(), 10);
+        assert!(idle.flush(Duration::from_millis(10)));
+        assert!(idle.push(peer, Datagram::Ping(1), Encoding::Text));
+        assert!(!idle.flush(Duration::from_millis(10)));
+
+        let outbox = Outbox::new(socket(), 10, 1);
+        for n in 0..10 {
+            assert!(outbox.push(peer, Datagram::Ping(n), Encoding::Text));
+        }
+        assert!(outbox.flush(Duration::from_secs(1)));
+        assert!(!outbox.contains(&peer));
+    }
+
+    #[test]
+    fn test_queues_are_bounded() {
+        let outbox = Outbox::idle(socket(), 2);
+        let slow = Peer::Udp(SocketAddr::from((Ipv4Addr::LOCALHOST, 1)));
+        let other = Peer::Udp(SocketAddr::from((Ipv4Addr::LOCALHOST, 2)));
+        assert!(outbox.push(slow, Datagram::Ping(1), Encoding::Text));
+        assert!(outbox.push(slow, Datagram::Ping(2), Encoding::Text));
+        assert!(!outbox.push(slow, Datagram::Ping(3), Encoding::Text));
+        assert!(outbox.push(other, Datagram::Ping(1), Encoding::Text));
+
+        outbox.close(&slow);
+        assert!(!outbox.contains(&slow));
+        assert!(outbox.push(slow, Datagram::Ping(4), Encoding::Text));
+
+        // Peers who were never connected are ignored
+        let gone = Peer::Tcp(SocketAddr::from((Ipv4Addr::LOCALHOST, 3)));
+        assert!(outbox.push(gone, Datagram::Ping(1), Encoding::Text));
+        assert!(!outbox.contains(&gone));
+    }
+
+    #[test]
+    fn test_close_while_sending() {
+        let outbox = Outbox::idle(socket(), 10);
+        let peer = Peer::Udp(SocketAddr::from((Ipv4Addr::LOCALHOST, 1)));
+        assert!(outbox.push(peer, Datagram::Ping(1), Encoding::Text));
+        let sending = take(&outbox.shared).unwrap();
+        outbox.close(&peer);
+        assert!(outbox.push(peer, Datagram::Ping(2), Encoding::Text));
+        assert!(outbox.push(peer, Datagram::Ping(3), Encoding::Text));
+
+        // Finishing the closed queue's datagram leaves the new queue alone
+        finish(&outbox.shared, sending);
+        assert_eq!(outbox.shared.lock().ready.len(), 1);
+        let sending = take(&outbox.shared).unwrap();
+        assert_eq!(sending.datagram, Datagram::Ping(2));
+        assert!(outbox.shared.lock().ready.is_empty());
+        finish(&outbox.shared, sending);
+        let sending = take(&outbox.shared).unwrap();
+        assert_eq!(sending.datagram, Datagram::Ping(3));
+        finish(&outbox.shared, sending);
+        assert!(!outbox.contains(&peer));
+    }
+}
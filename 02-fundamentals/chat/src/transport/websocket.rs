@@ -0,0 +1,119 @@
+use super::{Connection, Event, Peer};
+use crate::protocol::{Datagram, Encoding};
+use std::io::Error;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+use tungstenite::protocol::{Role, WebSocketConfig};
+use tungstenite::{Message, WebSocket};
+
+// Each WebSocket message carries one datagram. Text messages hold text
+// datagrams and binary messages binary ones, so browsers can use either.
+
+/// Sends datagrams to a WebSocket peer
+pub struct Writer {
+    socket: WebSocket<TcpStream>,
+}
+
+impl Writer {
+    pub fn send(&mut self, datagram: &Datagram, encoding: Encoding) -> Result<(), Error> {
+        let message = match encoding {
+            Encoding::Text => Message::Text(datagram.serialize()),
+            Encoding::Binary => Message::Binary(datagram.encode(Encoding::Binary)),
+        };
+        self.socket.send(message).map_err(io_error)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.socket.get_ref().set_write_timeout(timeout)
+    }
+
+    pub fn close(mut self) {
+        self.socket.close(None).ok();
+        self.socket.flush().ok();
+        self.socket.get_ref().shutdown(Shutdown::Both).ok();
+    }
+}
+
+fn io_error(error: tungstenite::Error) -> Error {
+    match error {
+        tungstenite::Error::Io(error) => error,
+        error => Error::other(error),
+    }
+}
+
+fn config(max_message_size: usize) -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(max_message_size),
+        ..WebSocketConfig::default()
+    }
+}
+
+/// Accepts WebSocket connections, with a thread for each one that runs the
+/// handshake and then reads from it
+pub fn spawn_websocket_listener(
+    listener: TcpListener,
+    events: Sender<Event>,
+    max_message_size: usize,
+) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let events = events.clone();
+                    thread::spawn(move || read_websocket(stream, events, max_message_size));
+                }
+                Err(error) => error!("Error accepting connection: {}", error),
+            }
+        }
+    });
+}
+
+fn accept(
+    stream: TcpStream,
+    max_message_size: usize,
+) -> Result<(SocketAddr, WebSocket<TcpStream>, Writer), Box<dyn std::error::Error>> {
+    let address = stream.peer_addr()?;
+    let writer = stream.try_clone()?;
+    let reader = tungstenite::accept_with_config(stream, Some(config(max_message_size)))?;
+    let writer = Writer {
+        socket: WebSocket::from_raw_socket(writer, Role::Server, Some(config(max_message_size))),
+    };
+    Ok((address, reader, writer))
+}
+
+fn read_websocket(stream: TcpStream, events: Sender<Event>, max_message_size: usize) {
+    let (address, mut reader, writer) = match accept(stream, max_message_size) {
+        Ok(accepted) => accepted,
+        Err(error) => {
+            warn!("Error accepting WebSocket connection: {}", error);
+            return;
+        }
+    };
+    let peer = Peer::WebSocket(address);
+    let connection = Connection::WebSocket(Box::new(writer));
+    if events.send(Event::Connected(peer, connection)).is_err() {
+        return;
+    }
+
+    loop {
+        let buf = match reader.read() {
+            Ok(Message::Text(text)) => text.into_bytes(),
+            Ok(Message::Binary(bytes)) => bytes,
+            // Pings and closes are answered by tungstenite itself
+            Ok(_) => continue,
+            Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => {
+                break
+            }
+            Err(error) => {
+                warn!("Closing WebSocket from {}: {}", address, error);
+                break;
+            }
+        };
+        if events.send(Event::Received(peer, buf)).is_err() {
+            return;
+        }
+    }
+    events.send(Event::Disconnected(peer)).ok();
+}
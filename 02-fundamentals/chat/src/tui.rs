@@ -0,0 +1,509 @@
+use crate::client::Client;
+use crate::protocol::{matches_pattern, Datagram, DirectDatagram, ErrorDatagram};
+use crate::repl::{self, Command, Session};
+use chrono::{DateTime, Local};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, Paragraph};
+use ratatui::Frame;
+use std::collections::VecDeque;
+use std::io::Error;
+use std::time::Duration;
+
+// How long to wait for a key press before checking for datagrams
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// How many messages to remember for each channel
+const SCROLLBACK: usize = 1000;
+const CHANNEL_LIST_WIDTH: u16 = 24;
+
+// One message in a channel's scrollback
+struct Message {
+    time: String,
+    display_name: String,
+    message: String,
+    // Sent just to us, rather than to the channel
+    direct: bool,
+}
+
+// A channel we've joined, and everything we've seen on it
+struct Pane {
+    channel: String,
+    messages: VecDeque<Message>,
+    unread: usize,
+    // How many messages up from the bottom we've scrolled
+    scroll: usize,
+}
+
+impl Pane {
+    fn new(channel: String) -> Self {
+        Pane {
+            channel,
+            messages: VecDeque::new(),
+            unread: 0,
+            scroll: 0,
+        }
+    }
+
+    fn push(&mut self, message: Message) {
+        if self.messages.len() == SCROLLBACK {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+        // Keep whatever we were reading in view
+        if self.scroll > 0 {
+            self.scroll = (self.scroll + 1).min(self.messages.len() - 1);
+        }
+    }
+}
+
+/// Everything on screen: a pane for each channel in the session, the line
+/// being typed, and the last thing worth telling the user
+pub struct App {
+    session: Session,
+    panes: Vec<Pane>,
+    current: usize,
+    input: String,
+    status: String,
+    quit: bool,
+}
+
+impl App {
+    pub fn new(session: Session) -> Self {
+        let mut app = App {
+            session,
+            panes: Vec::new(),
+            current: 0,
+            input: String::new(),
+            status: String::from("Type /help for commands, Tab to switch channels"),
+            quit: false,
+        };
+        app.sync_panes();
+        app
+    }
+
+    // Adds and removes panes to match the session, and selects its current channel
+    fn sync_panes(&mut self) {
+        let channels = self.session.channels();
+        self.panes.retain(|pane| channels.contains(&pane.channel));
+        for channel in channels {
+            if !self.panes.iter().any(|pane| pane.channel == *channel) {
+                self.panes.push(Pane::new(channel.clone()));
+            }
+        }
+        if let Some(current) = self.session.current() {
+            self.current = self
+                .panes
+                .iter()
+                .position(|pane| pane.channel == current)
+                .unwrap_or(0);
+        }
+        if let Some(pane) = self.panes.get_mut(self.current) {
+            pane.unread = 0;
+        }
+    }
+
+    fn current_pane(&mut self) -> Option<&mut Pane> {
+        self.panes.get_mut(self.current)
+    }
+
+    pub fn receive(&mut self, datagram: Datagram, now: DateTime<Local>) {
+        let publish = match datagram {
+            Datagram::Publish(publish) => publish,
+            Datagram::Direct(direct) => return self.receive_direct(direct, now),
+            // Replies to LIST and WHO, and who's come and gone, are only
+            // worth a glance
+            datagram => {
+                self.status = repl::format(&datagram);
+                return;
+            }
+        };
+        let current = self.current;
+        // Messages matching a pattern we've joined go in its pane, unless we've
+        // joined their channel too
+        let index = self
+            .panes
+            .iter()
+            .position(|pane| pane.channel == publish.channel)
+            .or_else(|| {
+                self.panes
+                    .iter()
+                    .position(|pane| matches_pattern(&pane.channel, &publish.channel))
+            });
+        let (index, pane) = match index {
+            Some(index) => (index, &mut self.panes[index]),
+            // Stragglers from a channel we've just left
+            None => return,
+        };
+        pane.push(Message {
+            time: now.format("%H:%M:%S").to_string(),
+            display_name: publish.display_name,
+            message: publish.message,
+            direct: false,
+        });
+        if index != current {
+            pane.unread += 1;
+        }
+    }
+
+    // Direct messages show up wherever we're looking
+    fn receive_direct(&mut self, direct: DirectDatagram, now: DateTime<Local>) {
+        let pane = match self.current_pane() {
+            Some(pane) => pane,
+            None => {
+                self.status = format!("(DM) {}: {}", direct.display_name, direct.message);
+                return;
+            }
+        };
+        pane.push(Message {
+            time: now.format("%H:%M:%S").to_string(),
+            display_name: direct.display_name,
+            message: direct.message,
+            direct: true,
+        });
+    }
+
+    pub fn error(&mut self, error: ErrorDatagram) {
+        self.status = format!("Server error: {}", error);
+    }
+
+    pub fn set_status<S: Into<String>>(&mut self, status: S) {
+        self.status = status.into();
+    }
+
+    pub fn should_quit(&self) -> bool {
+        self.quit
+    }
+
+    /// Handles a key press, returning the datagrams to send to the server
+    pub fn key(&mut self, key: KeyEvent) -> Vec<Datagram> {
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Enter => return self.submit(),
+            KeyCode::Tab => self.switch(1),
+            KeyCode::BackTab => self.switch(self.panes.len().saturating_sub(1)),
+            KeyCode::PageUp => {
+                if let Some(pane) = self.current_pane() {
+                    pane.scroll = (pane.scroll + 10).min(pane.messages.len().saturating_sub(1));
+                }
+            }
+            KeyCode::PageDown => {
+                if let Some(pane) = self.current_pane() {
+                    pane.scroll = pane.scroll.saturating_sub(10);
+                }
+            }
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    // Moves `offset` panes along, wrapping around at the end
+    fn switch(&mut self, offset: usize) {
+        if self.panes.is_empty() {
+            return;
+        }
+        let index = (self.current + offset) % self.panes.len();
+        let channel = self.panes[index].channel.clone();
+        self.handle(Command::Switch(channel));
+    }
+
+    fn submit(&mut self) -> Vec<Datagram> {
+        let line = std::mem::take(&mut self.input);
+        if line.is_empty() {
+            return Vec::new();
+        }
+        match Command::parse(&line) {
+            Ok(Command::Quit) => {
+                self.quit = true;
+                Vec::new()
+            }
+            Ok(Command::Help) => {
+                // Too tall for the status line, so squash it onto one
+                self.status = repl::HELP
+                    .lines()
+                    .skip(1)
+                    .map(str::trim)
+                    .collect::<Vec<_>>()
+                    .join(" | ");
+                Vec::new()
+            }
+            Ok(command) => self.handle(command),
+            Err(error) => {
+                self.status = error;
+                Vec::new()
+            }
+        }
+    }
+
+    fn handle(&mut self, command: Command) -> Vec<Datagram> {
+        match self.session.handle(command) {
+            Ok(datagrams) => {
+                self.sync_panes();
+                datagrams
+            }
+            Err(error) => {
+                self.status = error;
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn draw(&self, frame: &mut Frame) {
+        let [sidebar, main] =
+            Layout::horizontal([Constraint::Length(CHANNEL_LIST_WIDTH), Constraint::Min(0)])
+                .areas(frame.area());
+        let [scrollback, status, input] = Layout::vertical([
+            Constraint::Min(0),
+            Constraint::Length(1),
+            Constraint::Length(3),
+        ])
+        .areas(main);
+
+        let channels: Vec<ListItem> = self
+            .panes
+            .iter()
+            .enumerate()
+            .map(|(index, pane)| {
+                let label = if pane.unread > 0 {
+                    format!("{} ({})", pane.channel, pane.unread)
+                } else {
+                    pane.channel.clone()
+                };
+                let style = if index == self.current {
+                    Style::default().add_modifier(Modifier::REVERSED)
+                } else if pane.unread > 0 {
+                    Style::default().add_modifier(Modifier::BOLD)
+                } else {
+                    Style::default()
+                };
+                ListItem::new(label).style(style)
+            })
+            .collect();
+        frame.render_widget(
+            List::new(channels).block(Block::bordered().title("Channels")),
+            sidebar,
+        );
+
+        self.draw_scrollback(frame, scrollback);
+        frame.render_widget(Paragraph::new(self.status.as_str()), status);
+
+        let prompt = format!("[{}] ", self.session.nick());
+        frame.render_widget(
+            Paragraph::new(format!("{}{}", prompt, self.input)).block(Block::bordered()),
+            input,
+        );
+        let cursor = (prompt.chars().count() + self.input.chars().count()) as u16;
+        frame.set_cursor_position((
+            (input.x + 1 + cursor).min(input.right().saturating_sub(2)),
+            input.y + 1,
+        ));
+    }
+
+    fn draw_scrollback(&self, frame: &mut Frame, area: Rect) {
+        let pane = match self.panes.get(self.current) {
+            Some(pane) => pane,
+            None => {
+                let block = Block::bordered().title("Join a channel with /join CHANNEL");
+                frame.render_widget(block, area);
+                return;
+            }
+        };
+        let title = if pane.scroll > 0 {
+            format!("{} (scrolled back {})", pane.channel, pane.scroll)
+        } else {
+            pane.channel.clone()
+        };
+
+        let height = area.height.saturating_sub(2) as usize;
+        let end = pane.messages.len() - pane.scroll;
+        let start = end.saturating_sub(height);
+        let lines: Vec<Line> = pane
+            .messages
+            .range(start..end)
+            .map(|m| {
+                if m.direct {
+                    let line = format!("{} (DM) {}: {}", m.time, m.display_name, m.message);
+                    Line::styled(line, Style::default().add_modifier(Modifier::BOLD))
+                } else {
+                    Line::from(format!("{} {}: {}", m.time, m.display_name, m.message))
+                }
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            area,
+        );
+    }
+}
+
+/// Takes over the terminal until the user quits, sending whatever they type
+/// and showing everything the server sends in the meantime.
+pub fn run(mut client: Client, session: Session, reliable: bool) -> Result<(), Error> {
+    let mut app = App::new(session);
+    let mut terminal = ratatui::init();
+    let result = (|| {
+        while !app.should_quit() {
+            terminal.draw(|frame| app.draw(frame))?;
+            if event::poll(POLL_INTERVAL)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        for datagram in app.key(key) {
+                            if let Err(error) = repl::send(&mut client, &datagram, reliable) {
+                                app.set_status(format!("Failed to send: {}", error));
+                            }
+                        }
+                    }
+                }
+            }
+            // Take everything that's waiting, without holding up the next key press
+            while let Some(reply) = client.listen(Some(Duration::from_millis(1))) {
+                match reply {
+                    Ok(datagram) => app.receive(datagram, Local::now()),
+                    Err(error) => app.error(error),
+                }
+            }
+        }
+        Ok(())
+    })();
+    ratatui::restore();
+
+    repl::leave_all(&mut client, &app.session, reliable);
+    result
+}
+
+#[cfg(test)]
+mod tui_tests {
+    use super::*;
+    use chrono::TimeZone;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    fn app(channels: &[&str]) -> App {
+        let mut session = Session::new("me");
+        for channel in channels {
+            session
+                .handle(Command::Join(String::from(*channel)))
+                .unwrap();
+        }
+        App::new(session)
+    }
+
+    fn type_line(app: &mut App, line: &str) -> Vec<Datagram> {
+        for c in line.chars() {
+            app.key(KeyEvent::from(KeyCode::Char(c)));
+        }
+        app.key(KeyEvent::from(KeyCode::Enter))
+    }
+
+    fn noon() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2019, 6, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_unread_counts() {
+        let mut app = app(&["a", "b"]);
+        assert_eq!(app.panes[app.current].channel, "b");
+
+        app.receive(Datagram::publish("a", "you", "hi"), noon());
+        app.receive(Datagram::publish("a", "you", "again"), noon());
+        app.receive(Datagram::publish("b", "you", "hello"), noon());
+        app.receive(Datagram::publish("c", "you", "nobody's here"), noon());
+        assert_eq!(app.panes[0].unread, 2);
+        assert_eq!(app.panes[1].unread, 0);
+
+        app.key(KeyEvent::from(KeyCode::Tab));
+        assert_eq!(app.panes[app.current].channel, "a");
+        assert_eq!(app.panes[0].unread, 0);
+        assert_eq!(app.panes[0].messages.len(), 2);
+        assert_eq!(app.panes[0].messages[0].time, "12:00:00");
+    }
+
+    #[test]
+    fn test_direct_messages() {
+        let mut nowhere = app(&[]);
+        nowhere.receive(Datagram::direct("me", "you", "psst"), noon());
+        assert_eq!(nowhere.status, "(DM) you: psst");
+
+        let mut app = app(&["a", "b"]);
+        app.receive(Datagram::direct("me", "you", "psst"), noon());
+        assert_eq!(app.panes[1].messages.len(), 1);
+        assert!(app.panes[1].messages[0].direct);
+        assert_eq!(app.panes[0].messages.len(), 0);
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut app = app(&[]);
+        assert_eq!(type_line(&mut app, "hi"), vec![]);
+        assert!(!app.status.is_empty());
+
+        assert_eq!(
+            type_line(&mut app, "/join rust_club"),
+            vec![Datagram::subscribe("rust_club")]
+        );
+        assert_eq!(
+            type_line(&mut app, "hi"),
+            vec![Datagram::publish("rust_club", "me", "hi")]
+        );
+        assert_eq!(app.input, "");
+        assert_eq!(
+            type_line(&mut app, "/leave"),
+            vec![Datagram::unsubscribe("rust_club")]
+        );
+        assert!(app.panes.is_empty());
+
+        assert_eq!(type_line(&mut app, "/quit"), vec![]);
+        assert!(app.should_quit());
+    }
+
+    #[test]
+    fn test_scrollback() {
+        let mut app = app(&["a"]);
+        for i in 0..SCROLLBACK + 5 {
+            app.receive(Datagram::publish("a", "you", i.to_string()), noon());
+        }
+        assert_eq!(app.panes[0].messages.len(), SCROLLBACK);
+        assert_eq!(app.panes[0].messages[0].message, "5");
+
+        app.key(KeyEvent::from(KeyCode::PageUp));
+        assert_eq!(app.panes[0].scroll, 10);
+        // New messages don't move what we're reading
+        app.receive(Datagram::publish("a", "you", "new"), noon());
+        assert_eq!(app.panes[0].scroll, 11);
+        app.key(KeyEvent::from(KeyCode::PageDown));
+        app.key(KeyEvent::from(KeyCode::PageDown));
+        assert_eq!(app.panes[0].scroll, 0);
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut app = app(&["a", "rust_club"]);
+        app.receive(Datagram::publish("rust_club", "you", "hello"), noon());
+        app.receive(Datagram::publish("a", "you", "psst"), noon());
+        app.receive(Datagram::direct("me", "them", "secret"), noon());
+        app.input = String::from("typing");
+
+        let mut terminal = Terminal::new(TestBackend::new(80, 10)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        let buffer = terminal.backend().buffer();
+        let screen: String = (0..buffer.area.height)
+            .map(|y| {
+                (0..buffer.area.width)
+                    .map(|x| buffer[(x, y)].symbol())
+                    .collect::<String>()
+                    + "\n"
+            })
+            .collect();
+        assert!(screen.contains("a (1)"));
+        assert!(screen.contains("12:00:00 you: hello"));
+        assert!(!screen.contains("psst"));
+        assert!(screen.contains("12:00:00 (DM) them: secret"));
+        assert!(screen.contains("[me] typing"));
+    }
+}
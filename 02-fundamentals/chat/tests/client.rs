@@ -0,0 +1,14 @@
+mod common;
+
+use common::CHAT;
+use std::process::Command;
+
+#[test]
+fn rejects_invalid_messages() {
+    let output = Command::new(CHAT)
+        .args(["client", "--server", "127.0.0.1:31337", "-m", "rust_club"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(output.stderr, b"Missing field: display name\n");
+}
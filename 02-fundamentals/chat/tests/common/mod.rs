@@ -0,0 +1,23 @@
+//! Helpers shared by the integration tests, which each use some of them
+#![allow(dead_code)]
+
+use std::net::UdpSocket;
+use std::process::Child;
+
+pub const CHAT: &str = env!("CARGO_BIN_EXE_chat");
+
+/// Kills the process when the test ends, even if it fails
+pub struct Process(pub Child);
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A UDP port that was free a moment ago
+pub fn free_port() -> u16 {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap().port()
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use common::{free_port, Process, CHAT};
+use std::fs;
+use std::net::UdpSocket;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::Duration;
+
+fn test_directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("chat-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+fn check_config(config: &str) -> Output {
+    let directory = test_directory("check");
+    let config_file = directory.join("server.toml");
+    fs::write(&config_file, config).unwrap();
+    Command::new(CHAT)
+        .args(["server", "--check-config", "--config"])
+        .arg(&config_file)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn checks_config_files() {
+    let output = check_config("port = 4000\nwrite_timeout = 0.5\n");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"Configuration is valid\n");
+
+    let output = check_config("prot = 4000\n");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown field `prot`"));
+
+    let output = check_config("acl_file = \"/nowhere/acl\"\n");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("/nowhere/acl"));
+}
+
+#[test]
+fn reloads_config_on_sighup() {
+    let directory = test_directory("reload");
+    let config_file = directory.join("server.toml");
+    let acl_file = directory.join("acl");
+    let port = free_port();
+    fs::write(
+        &config_file,
+        format!(
+            "bind = \"127.0.0.1\"\nport = {}\nacl_file = {:?}\n",
+            port, acl_file
+        ),
+    )
+    .unwrap();
+    fs::write(&acl_file, "secret read 10.9.9.9\n").unwrap();
+
+    let server = Process(
+        Command::new(CHAT)
+            .args(["server", "--config"])
+            .arg(&config_file)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_millis(200));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(("127.0.0.1", port)).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut buf = [0; 1024];
+    let mut who = || {
+        client.send(b"W|secret").unwrap();
+        let n = client.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..n]).into_owned()
+    };
+    assert_eq!(who(), "E|forbidden|Not allowed to read: secret");
+
+    fs::write(&acl_file, "secret read *\n").unwrap();
+    let reloaded = Command::new("kill")
+        .args(["-HUP", &server.0.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(reloaded.success());
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(who(), "G|secret");
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use common::{free_port, Process, CHAT};
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn address(port: u16) -> String {
+    format!("127.0.0.1:{}", port)
+}
+
+fn server(port: u16, peers: &[u16]) -> Process {
+    let mut command = Command::new(CHAT);
+    command.args(["server", "--bind", "127.0.0.1", "--port", &port.to_string()]);
+    for peer in peers {
+        command.args(["--peer", &address(*peer)]);
+    }
+    let child = command
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    Process(child)
+}
+
+#[test]
+fn forwards_publishes_along_a_chain_of_servers() {
+    let (a, b, c) = (free_port(), free_port(), free_port());
+    let _servers = [server(a, &[b]), server(b, &[a, c]), server(c, &[b])];
+    thread::sleep(Duration::from_millis(200));
+
+    let mut subscriber = Process(
+        Command::new(CHAT)
+            .args(["client", "-s", &address(c), "-c", "federated"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    let stdout = subscriber.0.stdout.take().unwrap();
+    let (line_sender, lines) = channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            if line_sender.send(line.unwrap()).is_err() {
+                return;
+            }
+        }
+    });
+
+    // A only hears about the subscription on C once it has passed through B
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        assert!(
+            Instant::now() < deadline,
+            "Nothing published on A reached C"
+        );
+        let status = Command::new(CHAT)
+            .args([
+                "client",
+                "-s",
+                &address(a),
+                "-m",
+                "federated|alice|hello from A",
+            ])
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        if let Ok(line) = lines.recv_timeout(Duration::from_millis(200)) {
+            assert_eq!(line, "P|federated|alice|hello from A");
+            break;
+        }
+    }
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use common::{free_port, Process, CHAT};
+use std::net::UdpSocket;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn says_goodbye_when_terminated() {
+    let subscriber = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = free_port();
+    let mut server = Process(
+        Command::new(CHAT)
+            .args(["server", "--bind", "127.0.0.1", "--port", &port.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_millis(200));
+
+    subscriber.connect(("127.0.0.1", port)).unwrap();
+    subscriber.send(b"S|farewell").unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let killed = Command::new("kill")
+        .args(["-TERM", &server.0.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+
+    subscriber
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut buf = [0; 1024];
+    let n = subscriber.recv(&mut buf);
+    let status = server.0.wait().unwrap();
+    assert_eq!(&buf[..n.unwrap()], b"Q|Server is shutting down");
+    assert!(status.success());
+}
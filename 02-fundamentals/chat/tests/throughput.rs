@@ -0,0 +1,133 @@
+//! How fast one server fans publishes out to thousands of subscribers on
+//! loopback. It takes a while, so it only runs when asked for:
+//!
+//! ```sh
+//! cargo test --release --test throughput -- --ignored --nocapture
+//! ```
+
+mod common;
+
+use common::{free_port, Process, CHAT};
+use std::net::UdpSocket;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SUBSCRIBERS: usize = 2000;
+const PUBLISHES: usize = 200;
+const READER_THREADS: usize = 2;
+
+// Reads everything that arrives on some of the subscribers' sockets until
+// nothing has arrived for a while, returning when the last of it did
+fn count_received(sockets: Vec<UdpSocket>, received: Arc<AtomicU64>) -> Instant {
+    let mut buf = [0; 1024];
+    let mut last_received = Instant::now();
+    while last_received.elapsed() < Duration::from_secs(2) {
+        let mut idle = true;
+        for socket in &sockets {
+            while let Ok(length) = socket.recv(&mut buf) {
+                // Heartbeats and stray test messages don't count
+                if buf[..length].starts_with(b"P|bench.run|") {
+                    received.fetch_add(1, Ordering::Relaxed);
+                }
+                idle = false;
+            }
+        }
+        if !idle {
+            last_received = Instant::now();
+        }
+        // Leave the server the CPU, since the sockets buffer plenty meanwhile
+        thread::sleep(Duration::from_millis(10));
+    }
+    last_received
+}
+
+#[test]
+#[ignore]
+fn fans_out_to_thousands_of_subscribers() {
+    let port = free_port();
+    let server = format!("127.0.0.1:{}", port);
+    let _server = Process(
+        Command::new(CHAT)
+            .args(["server", "--bind", "127.0.0.1", "--port", &port.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_millis(200));
+
+    let publisher = UdpSocket::bind("127.0.0.1:0").unwrap();
+    publisher.connect(&server).unwrap();
+    let sockets: Vec<UdpSocket> = (0..SUBSCRIBERS)
+        .map(|_| {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.connect(&server).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            socket
+        })
+        .collect();
+
+    // So many subscribes at once overflow the server's buffer, so keep
+    // subscribing until everyone hears a test message. Subscribing to a
+    // pattern keeps presence notifications out of the count.
+    let mut buf = [0; 1024];
+    let mut waiting: Vec<&UdpSocket> = sockets.iter().collect();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !waiting.is_empty() {
+        assert!(Instant::now() < deadline, "Not everyone could subscribe");
+        for socket in &waiting {
+            socket.send(b"S|bench.*").unwrap();
+        }
+        thread::sleep(Duration::from_millis(200));
+        publisher.send(b"P|bench.warmup|bench|ready").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        waiting.retain(|socket| {
+            let mut subscribed = false;
+            while socket.recv(&mut buf).is_ok() {
+                subscribed = true;
+            }
+            !subscribed
+        });
+    }
+
+    let received = Arc::new(AtomicU64::new(0));
+    let mut sockets = sockets.into_iter();
+    let readers: Vec<_> = (0..READER_THREADS)
+        .map(|_| {
+            let sockets = sockets
+                .by_ref()
+                .take(SUBSCRIBERS / READER_THREADS)
+                .collect();
+            let received = received.clone();
+            thread::spawn(move || count_received(sockets, received))
+        })
+        .collect();
+
+    let start = Instant::now();
+    for n in 0..PUBLISHES {
+        let publish = format!("P|bench.run|bench|message {}", n);
+        publisher.send(publish.as_bytes()).unwrap();
+    }
+    let finish = readers
+        .into_iter()
+        .map(|reader| reader.join().unwrap())
+        .max()
+        .unwrap();
+    let elapsed = finish.saturating_duration_since(start);
+
+    // Anything the sockets couldn't buffer in time was dropped
+    let expected = SUBSCRIBERS * PUBLISHES;
+    let received = received.load(Ordering::Relaxed);
+    println!(
+        "{} of {} datagrams delivered to {} subscribers in {:.2?} ({:.0} datagrams per second)",
+        received,
+        expected,
+        SUBSCRIBERS,
+        elapsed,
+        received as f64 / elapsed.as_secs_f64()
+    );
+    assert!(received > 0, "Nothing reached any subscribers");
+}